@@ -197,7 +197,11 @@ fn inner(path: PathBuf, op: Operation, args: Rest) -> Result<()>
             println!("Reading header from: {}", path.display());
             let head = read_head(&mut fs::File::open(path)?)?;
             println!("{} file, version: {}",
-                match head.ftype { FileType::Snapshot(_) => "Snapshot", FileType::CommitLog(_) => "Commit log" },
+                match head.ftype {
+                    FileType::Snapshot(_) => "Snapshot",
+                    FileType::CommitLog(_) => "Commit log",
+                    FileType::Bundle(_) => "Bundle",
+                },
                 head.ftype.ver());
             println!("Repository name: {}", head.name);
             
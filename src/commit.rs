@@ -6,14 +6,14 @@
 
 use std::collections::{HashMap, hash_map};
 use std::clone::Clone;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::u32;
 use std::cmp::max;
 use std::ops::BitOr;
 
 use chrono::{DateTime, NaiveDateTime, UTC};
 
-use state::{PartState, MutPartState, StateWrite};
+use state::{PartState, MutPartState, StateRead};
 use elt::{Element, EltId};
 use sum::Sum;
 use error::{Result, ElementOp, OtherError};
@@ -266,40 +266,75 @@ pub struct Commit<E: Element> {
 }
 
 /// Per-element changes
+/// 
+/// Insertions and replacements include the element's sum (see
+/// `Element::sum`), so that applying the change does not require serialising
+/// the element.
 #[derive(PartialEq, Eq, Debug)]
 pub enum EltChange<E: Element> {
     /// Element was deleted
     Deletion,
-    /// Element was added (full data)
-    Insertion(Rc<E>),
-    /// Element was replaced (full data)
-    Replacement(Rc<E>),
+    /// Element was added (full data and element sum)
+    Insertion(Arc<E>, Sum),
+    /// Element was replaced (full data and element sum)
+    Replacement(Arc<E>, Sum),
+}
+impl<E: Element> Clone for EltChange<E> {
+    fn clone(&self) -> EltChange<E> {
+        match *self {
+            EltChange::Deletion => EltChange::Deletion,
+            EltChange::Insertion(ref elt, ref sum) => EltChange::Insertion(elt.clone(), sum.clone()),
+            EltChange::Replacement(ref elt, ref sum) => EltChange::Replacement(elt.clone(), sum.clone()),
+        }
+    }
 }
 impl<E: Element> EltChange<E> {
-    /// Create an `Insertion`
-    pub fn insertion(elt: Rc<E>) -> EltChange<E> {
-        EltChange::Insertion(elt)
+    /// Create an `Insertion`. `sum` must equal `elt.sum(id)` where `id` is
+    /// the element's identifier.
+    pub fn insertion(elt: Arc<E>, sum: Sum) -> EltChange<E> {
+        EltChange::Insertion(elt, sum)
     }
-    /// Create a `Replacement`
-    pub fn replacement(elt: Rc<E>) -> EltChange<E> {
-        EltChange::Replacement(elt)
+    /// Create a `Replacement`. `sum` must equal `elt.sum(id)` where `id` is
+    /// the element's identifier.
+    pub fn replacement(elt: Arc<E>, sum: Sum) -> EltChange<E> {
+        EltChange::Replacement(elt, sum)
     }
     /// Create a `Deletion`
     pub fn deletion() -> EltChange<E> {
         EltChange::Deletion
     }
     /// Get `Some(elt)` if an element is contained, `None` otherwise
-    pub fn element(&self) -> Option<&Rc<E>> {
+    pub fn element(&self) -> Option<&Arc<E>> {
+        use commit::EltChange::*;
+        match *self {
+            Deletion => None,
+            Insertion(ref elt, _) | Replacement(ref elt, _) => Some(elt),
+        }
+    }
+    /// Get `Some(sum)` (the element sum) if an element is contained, `None`
+    /// otherwise
+    pub fn elt_sum(&self) -> Option<&Sum> {
         use commit::EltChange::*;
         match *self {
             Deletion => None,
-            Insertion(ref elt) | Replacement(ref elt) => Some(elt),
+            Insertion(_, ref sum) | Replacement(_, ref sum) => Some(sum),
         }
     }
 }
 
 // —————  Commit operations  —————
 
+impl<E: Element> Clone for Commit<E> {
+    fn clone(&self) -> Commit<E> {
+        Commit {
+            statesum: self.statesum.clone(),
+            parents: self.parents.clone(),
+            changes: self.changes.clone(),
+            meta: self.meta.clone(),
+        }
+    }
+}
+
 impl<E: Element> Commit<E> {
     /// Create a commit from parts. It is suggested not to use this unless you
     /// are sure all sums are correct.
@@ -317,33 +352,34 @@ impl<E: Element> Commit<E> {
     /// Create a commit from an old state and a new state. Return the commit if
     /// there are any differences or None if the states are identical.
     /// 
-    /// This is one of two ways to create a commit; the other would be to track
-    /// changes to a state (possibly the latter is the more sensible approach
-    /// for most applications).
+    /// This compares the element sums of both states. Where a `MutPartState`
+    /// is available, `MutPartState::changes` is much cheaper (this is what
+    /// `Partition::push_state` uses).
+    /// 
+    /// Elements of the new state which differ are loaded if not in memory;
+    /// this fails with `ElementOp::LoadFailed` if one cannot be loaded.
     pub fn from_diff(old_state: &PartState<E>, new_state: &PartState<E>)
-            -> Option<Commit<E>>
+            -> Result<Option<Commit<E>>, ElementOp>
     {
-        // #0019: is using `collect()` for a HashMap efficient? Better to add a "clone_map" function to new_state?
-        let mut elt_map: HashMap<_,_> = new_state.elts_iter().collect();
         let mut changes = HashMap::new();
-        for (id, old_elt) in old_state.elts_iter() {
-            if let Some(new_elt) = elt_map.remove(&id) {
-                // #0019: should we compare sums? Would this be faster?
-                if new_elt == old_elt {
-                    /* no change */
-                } else {
-                    changes.insert(id, EltChange::replacement(new_elt.clone()));
-                }
-            } else {
+        for (id, old_sum) in old_state.elt_sums_iter() {
+            match new_state.get_sum(id) {
+                Ok(new_sum) => if new_sum != old_sum {
+                    let elt = new_state.get_rc(id)?.clone();
+                    changes.insert(id, EltChange::replacement(elt, new_sum.clone()));
+                },
                 // not in new state: has been deleted
-                changes.insert(id, EltChange::deletion());
+                Err(_) => { changes.insert(id, EltChange::deletion()); },
             }
         }
-        for (id, new_elt) in elt_map {
-            changes.insert(id, EltChange::insertion(new_elt.clone()));
+        for (id, new_sum) in new_state.elt_sums_iter() {
+            if !old_state.is_avail(id) {
+                let elt = new_state.get_rc(id)?.clone();
+                changes.insert(id, EltChange::insertion(elt, new_sum.clone()));
+            }
         }
         
-        if changes.is_empty() {
+        Ok(if changes.is_empty() {
             None
         } else {
             Some(Commit {
@@ -352,7 +388,7 @@ impl<E: Element> Commit<E> {
                 changes: changes,
                 meta: new_state.meta().clone(),
             })
-        }
+        })
     }
     
     /// Apply this commit to a `MutPartState`. This does not verify the final
@@ -365,13 +401,13 @@ impl<E: Element> Commit<E> {
         for (id, change) in &self.changes {
             match *change {
                 EltChange::Deletion => {
-                    mut_state.remove(*id)?;
+                    mut_state.discard(*id)?;
                 },
-                EltChange::Insertion(ref elt) => {
-                    mut_state.insert_rc(*id, elt.clone())?;
+                EltChange::Insertion(ref elt, ref sum) => {
+                    mut_state.insert_rc_sum(*id, elt.clone(), sum.clone())?;
                 }
-                EltChange::Replacement(ref elt) => {
-                    mut_state.replace_rc(*id, elt.clone())?;
+                EltChange::Replacement(ref elt, ref sum) => {
+                    mut_state.set_rc_sum(*id, elt.clone(), sum.clone())?;
                 }
             }
         }
@@ -4,14 +4,17 @@
 
 //! Pippin: control traits
 
+use std::io::Read;
 use std::usize;
 use std::marker::PhantomData;
 
 use commit::MakeCommitMeta;
 use elt::Element;
-use error::Result;
+use error::{Result, Error};
 use io::RepoIO;
 use rw::header::{UserData, FileHeader};
+use rw::snapshot::{read_snapshot, read_snapshot_parallel};
+use state::PartState;
 
 
 /// Allows the user to control various repository operations. Library-provided implementations
@@ -40,6 +43,14 @@ pub trait Control: MakeCommitMeta {
     /// This layer of indirection allows use of the `DefaultSnapshot`.
     fn snapshot_policy(&mut self) -> &mut SnapshotPolicy;
     
+    /// Get the point at which writing to a commit log stops and a new log
+    /// is started.
+    /// 
+    /// The default implementation returns `LogRollover::default()`.
+    fn log_rollover(&self) -> LogRollover {
+        LogRollover::default()
+    }
+    
     /// Cast self to a `&MakeCommitMeta`
     // #0018: shouldn't be needed when Rust finally supports upcasting
     fn as_mcm_ref(&self) -> &MakeCommitMeta;
@@ -72,6 +83,88 @@ pub trait Control: MakeCommitMeta {
     fn read_header(&mut self, _header: &FileHeader) -> Result<()> {
         Ok(())
     }
+    
+    /// This function is called when a commit log is found to end with a
+    /// truncated or corrupt commit (for example after power loss while
+    /// appending), and decides how loading proceeds.
+    /// 
+    /// The default implementation returns `LogRecovery::Fail`.
+    fn damaged_log(&mut self, _damage: &LogDamage) -> LogRecovery {
+        LogRecovery::Fail
+    }
+    
+    /// If true, elements of snapshots are read only when first accessed,
+    /// where the snapshot has an element index and the I/O provider supports
+    /// random access (see `RepoIO::ss_source`). Opening a partition then
+    /// reads only headers, element indices and commit logs.
+    /// 
+    /// Elements which fail to load are reported by `StateRead::get` as
+    /// `ElementOp::LoadFailed`. The default implementation returns false.
+    fn lazy_elements(&self) -> bool {
+        false
+    }
+    
+    /// Read a snapshot's state from a stream (following the file header).
+    /// This is used except when the snapshot is read from a memory map (see
+    /// `RepoIO::map_ss`) or elements are loaded on demand.
+    /// 
+    /// The default implementation calls `rw::snapshot::read_snapshot`. Where
+    /// the element type is `Send`, this may be implemented via
+    /// `rw::snapshot::read_snapshot_parallel` to verify and decode elements on
+    /// several threads (see `DefaultControl::set_read_threads`).
+    fn read_snapshot(&self, reader: &mut Read, format_ver: u32) ->
+            Result<PartState<Self::Element>>
+    {
+        read_snapshot(reader, format_ver)
+    }
+    
+    /// Historical states are normally not held in memory; instead each is
+    /// stored as the commit creating it and rebuilt when required (see
+    /// `Partition::state`). Tips, states read from snapshots and one in every
+    /// `n` states along each chain of commits are held, where `n` is the
+    /// number returned; thus no more than `n - 1` commits need be applied to
+    /// rebuild a state. Returning 1 holds all states in memory; 0 is treated
+    /// as 1.
+    /// 
+    /// The default implementation returns 32.
+    fn state_checkpoint_interval(&self) -> usize {
+        32
+    }
+    
+    /// The number of historical states rebuilt from commits which are cached
+    /// (least-recently-used states are dropped first).
+    /// 
+    /// The default implementation returns 8.
+    fn state_cache_len(&self) -> usize {
+        8
+    }
+}
+
+/// Describes where reading of a damaged commit log stopped.
+#[derive(Debug)]
+pub struct LogDamage {
+    /// Snapshot number of the log
+    pub ss: usize,
+    /// Log number
+    pub cl: usize,
+    /// Length in bytes of the undamaged part of the log (including the
+    /// header); all commits before this offset were read successfully
+    pub offset: usize,
+    /// The error encountered when reading the damaged part
+    pub error: Error,
+}
+
+/// How to proceed when a damaged commit log is found (see
+/// `Control::damaged_log`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRecovery {
+    /// Fail to load, reporting the error
+    Fail,
+    /// Keep commits before the damage and leave the log unchanged
+    Ignore,
+    /// Keep commits before the damage and truncate the log to remove the
+    /// damaged tail (see `RepoIO::truncate_ss_cl`)
+    Truncate,
 }
 
 /// An interface allowing configuration of snapshot policy.
@@ -99,6 +192,26 @@ pub trait SnapshotPolicy {
     fn want_snapshot(&self) -> bool;
 }
 
+/// Limits on the size of a single commit log.
+/// 
+/// A partition appends new commits to the log it last wrote to until either
+/// limit would be exceeded, then starts a new log. Set `max_commits` to 1 to
+/// write each commit to its own log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogRollover {
+    /// Maximum number of commits in one log
+    pub max_commits: usize,
+    /// Maximum length of one log in bytes (a log always holds at least one
+    /// commit, so may exceed this if a single commit is larger)
+    pub max_bytes: usize,
+}
+impl Default for LogRollover {
+    /// Defaults: 1000 commits or 16 MiB.
+    fn default() -> LogRollover {
+        LogRollover { max_commits: 1000, max_bytes: 1 << 24 }
+    }
+}
+
 /// A convenient implementation of `Control`.
 /// 
 /// Uses `DefaultSnapshot` snapshot policy.
@@ -107,11 +220,51 @@ pub struct DefaultControl<E: Element, IO: RepoIO + 'static> {
     _elt_type: PhantomData<E>,
     io: IO,
     ss_policy: DefaultSnapshot,
+    log_rollover: LogRollover,
+    log_recovery: LogRecovery,
+    lazy_elements: bool,
+    checkpoint_interval: usize,
+    // parallel reader and number of threads, if enabled
+    read_parallel: Option<(fn(&mut Read, u32, usize) -> Result<PartState<E>>, usize)>,
 }
 impl<E: Element, IO: RepoIO> DefaultControl<E, IO> {
     /// Create, given I/O provider
     pub fn new(io: IO) -> Self {
-        DefaultControl { _elt_type: Default::default(), io: io, ss_policy: Default::default() }
+        DefaultControl {
+            _elt_type: Default::default(),
+            io: io,
+            ss_policy: Default::default(),
+            log_rollover: Default::default(),
+            log_recovery: LogRecovery::Fail,
+            lazy_elements: false,
+            checkpoint_interval: 32,
+            read_parallel: None,
+        }
+    }
+    
+    /// Set the commit log rollover point (see `Control::log_rollover`)
+    pub fn set_log_rollover(&mut self, rollover: LogRollover) {
+        self.log_rollover = rollover;
+    }
+    
+    /// Set how damaged commit logs are handled (see `Control::damaged_log`).
+    /// Initially `LogRecovery::Fail`.
+    pub fn set_log_recovery(&mut self, recovery: LogRecovery) {
+        self.log_recovery = recovery;
+    }
+    
+    /// Set whether snapshot elements are loaded on demand (see
+    /// `Control::lazy_elements`). Initially false.
+    pub fn set_lazy_elements(&mut self, lazy: bool) {
+        self.lazy_elements = lazy;
+    }
+    
+    /// Set how often historical states are held in memory (see
+    /// `Control::state_checkpoint_interval`). Initially 32. Panics if `n` is
+    /// zero.
+    pub fn set_state_checkpoint_interval(&mut self, n: usize) {
+        assert!(n > 0);
+        self.checkpoint_interval = n;
     }
     
     /// Get direct access to the held `IO`
@@ -121,6 +274,19 @@ impl<E: Element, IO: RepoIO> DefaultControl<E, IO> {
     /// Unwrap the held `IO`
     pub fn unwrap_io(self) -> IO { self.io }
 }
+impl<E: Element+Send, IO: RepoIO> DefaultControl<E, IO> {
+    /// Set the number of threads used to verify and decode snapshot elements
+    /// (see `Control::read_snapshot` and
+    /// `rw::snapshot::read_snapshot_parallel`). Initially 1: snapshots are
+    /// read on the calling thread only.
+    pub fn set_read_threads(&mut self, threads: usize) {
+        self.read_parallel = if threads > 1 {
+            Some((read_snapshot_parallel::<E>, threads))
+        } else {
+            None
+        };
+    }
+}
 impl<E: Element, IO: RepoIO> MakeCommitMeta for DefaultControl<E, IO> {}
 impl<E: Element, IO: RepoIO> Control for DefaultControl<E, IO> {
     type Element = E;
@@ -133,6 +299,24 @@ impl<E: Element, IO: RepoIO> Control for DefaultControl<E, IO> {
     fn snapshot_policy(&mut self) -> &mut SnapshotPolicy {
         &mut self.ss_policy
     }
+    fn log_rollover(&self) -> LogRollover {
+        self.log_rollover
+    }
+    fn damaged_log(&mut self, _damage: &LogDamage) -> LogRecovery {
+        self.log_recovery
+    }
+    fn lazy_elements(&self) -> bool {
+        self.lazy_elements
+    }
+    fn read_snapshot(&self, reader: &mut Read, format_ver: u32) -> Result<PartState<E>> {
+        match self.read_parallel {
+            Some((read, threads)) => read(reader, format_ver, threads),
+            None => read_snapshot(reader, format_ver),
+        }
+    }
+    fn state_checkpoint_interval(&self) -> usize {
+        self.checkpoint_interval
+    }
     fn as_mcm_ref(&self) -> &MakeCommitMeta { self }
     fn as_mcm_ref_mut(&mut self) -> &mut MakeCommitMeta { self }
 }
@@ -156,10 +156,19 @@ pub trait Element where Self: Sized+PartialEq+Eq+Debug+'static {
         Self::from_vec(vec)
     }
     
+    /// Create an instance from borrowed data + a sum (as for `from_vec_sum`).
+    /// This is used when reading from memory-mapped files, where there is no
+    /// owned buffer to hand over. The default implementation copies `data`
+    /// and calls `from_vec_sum`; implement to avoid the copy.
+    fn from_slice_sum(data: &[u8], sum: Sum) -> Result<Self>{
+        Self::from_vec_sum(data.to_vec(), sum)
+    }
+    
     /// This can either return a copy of an internally cached element sum or
-    /// calculate one on the fly. It is used when inserting, removing or
-    /// replacing an element in a state, and when merging states where the
-    /// element differs.
+    /// calculate one on the fly. States store the sum of each element they
+    /// hold (as do commits for elements they include), so this is only
+    /// called when a new element is inserted or replaces another in a state,
+    /// or is created while merging states.
     /// 
     /// The element sum is calculated via `Sum::elt_id(id, data)`.
     /// 
@@ -183,4 +192,7 @@ impl Element for String {
     fn from_vec(vec: Vec<u8>) -> Result<Self>{
         Ok(String::from_utf8(vec)?)
     }
+    fn from_slice_sum(data: &[u8], _sum: Sum) -> Result<Self>{
+        Self::read_buf(data)
+    }
 }
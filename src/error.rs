@@ -8,6 +8,8 @@ use std::{io, fmt, result};
 use std::path::PathBuf;
 use std::cmp::{min, max};
 
+use elt::EltId;
+use sum::Sum;
 use util::HexFormatter;
 
 /// Our custom result type
@@ -170,6 +172,9 @@ pub enum ElementOp {
     /// Identifier already in use. An insertion failed since the given
     /// identifier is already in use.
     IdClash,
+    /// An element not yet loaded into memory (see `Control::lazy_elements`)
+    /// could not be read or failed verification. Details are logged.
+    LoadFailed,
 }
 impl ErrorTrait for ElementOp {
     fn description(&self) -> &'static str {
@@ -177,6 +182,7 @@ impl ErrorTrait for ElementOp {
             ElementOp::EltNotFound => "element not found",
             ElementOp::IdGenFailure => "id generation failed to find a free identifier",
             ElementOp::IdClash => "identifier already in use",
+            ElementOp::LoadFailed => "failed to load element data",
         }
     }
 }
@@ -313,6 +319,9 @@ pub enum MergeError {
     NoState,
     /// No common ancestor found
     NoCommonAncestor,
+    /// No common ancestor found, and history ends at the partition's shallow
+    /// boundary (see `Partition::shallow_boundary`)
+    HistoryTruncated,
     /// Solver did not find a solution
     NotSolved,
     /// Patching failed
@@ -323,6 +332,7 @@ impl ErrorTrait for MergeError {
         match *self {
             MergeError::NoState => "merge: could not find state",
             MergeError::NoCommonAncestor => "merge: could not find a common ancestor",
+            MergeError::HistoryTruncated => "merge: history truncated at boundary",
             MergeError::NotSolved => "merge: solver failed",
             MergeError::PatchOp(ref p) => p.description(),
         }
@@ -340,6 +350,135 @@ impl From<PatchOp> for MergeError {
 }
 
 
+// —————  TxnError  —————
+/// Error type returned by `Partition::commit_txn()`.
+#[derive(PartialEq, Eq, Debug)]
+pub enum TxnError {
+    /// There is no single tip to commit to
+    Tip(TipError),
+    /// The state the transaction started from is no longer known
+    NoBase,
+    /// Elements read or written by the transaction were changed since it
+    /// started. The identifiers of these elements are listed (sorted).
+    Conflict(Vec<EltId>),
+    /// Patching failed
+    PatchOp(PatchOp),
+}
+impl ErrorTrait for TxnError {
+    fn description(&self) -> &str {
+        match *self {
+            TxnError::Tip(ref e) => e.description(),
+            TxnError::NoBase => "transaction: base state not found",
+            TxnError::Conflict(_) => "transaction: conflicting changes",
+            TxnError::PatchOp(ref p) => p.description(),
+        }
+    }
+}
+impl fmt::Display for TxnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            TxnError::Conflict(ref ids) => {
+                write!(f, "transaction: conflicting changes to elements")?;
+                for id in ids {
+                    write!(f, " {}", id)?;
+                }
+                Ok(())
+            },
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+impl From<TipError> for TxnError {
+    fn from(e: TipError) -> TxnError {
+        TxnError::Tip(e)
+    }
+}
+impl From<PatchOp> for TxnError {
+    fn from(e: PatchOp) -> TxnError {
+        TxnError::PatchOp(e)
+    }
+}
+impl From<ElementOp> for TxnError {
+    fn from(e: ElementOp) -> TxnError {
+        TxnError::PatchOp(e.into())
+    }
+}
+
+
+// —————  SyncError  —————
+/// Error type of synchronisation between replicas (see `sync` module).
+#[derive(PartialEq, Eq, Debug)]
+pub enum SyncError {
+    /// The peer sent something unexpected or uses another protocol version
+    Protocol(&'static str),
+    /// The peer failed, reporting this message
+    Remote(String),
+    /// The peer holds a different repository (the repo name differs)
+    WrongRepo,
+    /// The peer requires history not available locally (see
+    /// `Partition::load_all`)
+    NoHistory,
+    /// The tip changed while serving a request which depends on it (the
+    /// request may be retried)
+    TipChanged,
+}
+impl SyncError {
+    /// New `Protocol` error, wrapped with `Err`
+    pub fn protocol<T>(msg: &'static str) -> Result<T> {
+        Err(Box::new(SyncError::Protocol(msg)))
+    }
+}
+impl ErrorTrait for SyncError {
+    fn description(&self) -> &str {
+        match *self {
+            SyncError::Protocol(msg) => msg,
+            SyncError::Remote(ref msg) => msg,
+            SyncError::WrongRepo => "sync: repository name does not match",
+            SyncError::NoHistory => "sync: required history is not available",
+            SyncError::TipChanged => "sync: tip changed while serving request",
+        }
+    }
+}
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            SyncError::Protocol(msg) => write!(f, "sync protocol: {}", msg),
+            SyncError::Remote(ref msg) => write!(f, "sync: peer failed: {}", msg),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+// —————  BundleError  —————
+/// Error type of bundle export and import (see `Partition::export_bundle`).
+#[derive(PartialEq, Eq, Debug)]
+pub enum BundleError {
+    /// The commit creating a state to be bundled is not available (see
+    /// `Partition::load_all`)
+    NoHistory(Sum),
+    /// The bundle requires states not known locally
+    MissingStates(Vec<Sum>),
+}
+impl ErrorTrait for BundleError {
+    fn description(&self) -> &str {
+        match *self {
+            BundleError::NoHistory(_) => "bundle: history of a state is not available",
+            BundleError::MissingStates(_) => "bundle: required states are not known",
+        }
+    }
+}
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            BundleError::NoHistory(ref sum) =>
+                write!(f, "bundle: history of state {} is not available", sum),
+            BundleError::MissingStates(ref sums) =>
+                write!(f, "bundle: {} required states are not known (e.g. {})",
+                        sums.len(), sums[0]),
+        }
+    }
+}
+
 // —————  ReadOnly  —————
 /// Thing is not modifiable.
 #[derive(PartialEq, Eq, Debug)]
@@ -364,6 +503,43 @@ impl fmt::Display for ReadOnly {
 }
 
 
+// —————  LockError  —————
+/// A lock could not be acquired since it is held by another process.
+#[derive(PartialEq, Eq, Debug)]
+pub struct LockError {
+    path: PathBuf,
+    pid: Option<u32>,
+}
+impl LockError {
+    /// Create, given the path of the lock file and the process identifier
+    /// recorded in it (if readable)
+    pub fn new<P: Into<PathBuf>>(path: P, pid: Option<u32>) -> LockError {
+        LockError { path: path.into(), pid: pid }
+    }
+    /// New instance, wrapped with `Err`
+    pub fn err<T, P: Into<PathBuf>>(path: P, pid: Option<u32>) -> Result<T> {
+        Err(Box::new(LockError::new(path, pid)))
+    }
+    /// Get the process identifier of the lock holder, if known
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+}
+impl ErrorTrait for LockError {
+    fn description(&self) -> &str {
+        "repository is locked by another process"
+    }
+}
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match self.pid {
+            Some(pid) => write!(f, "locked by process {}: {}", pid, self.path.display()),
+            None => write!(f, "locked: {}", self.path.display()),
+        }
+    }
+}
+
+
 // —————  UserError  —————
 /// An error the user may return
 #[derive(PartialEq, Eq, Debug)]
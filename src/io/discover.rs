@@ -4,13 +4,17 @@
 
 //! Pippin: file discovery
 
-use std::path::Path;
-use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::fs::{read_dir, remove_file};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
 
 use regex::Regex;
+use walkdir::WalkDir;
 
-use io::file::{RepoFileIO, PartPaths};
-use error::{Result, PathError};
+use io::file::{RepoFileIO, PartPaths, TEMP_EXT};
+use error::{Result, Error, PathError};
 
 
 /// Will attempt to discover files belonging to a single partition from a path.
@@ -22,25 +26,28 @@ use error::{Result, PathError};
 /// all files in the same directory and with the same prefix (the part before
 /// the snapshot number, `ssN`).
 /// 
+/// If a directory holding files of several partitions is given, the partition
+/// with the first basename (in sorted order) is chosen; use `discover_repo`
+/// to find all partitions. Fails if two files map to the same snapshot or
+/// log number.
+/// 
+/// Temporary files left by an interrupted write (see `RepoFileIO`) are
+/// ignored; `clean_temp_files` can remove them.
+/// 
 /// #0040: consider supporting blobs or partial file names (i.e. patterns of
 /// some kind). Is there any use-case besides lazy entry in command-line tools?
 pub fn part_from_path<P: AsRef<Path>>(path: P) -> Result<RepoFileIO> {
     let path = path.as_ref();
-    let ss_pat = Regex::new("^((?:.*)-)?ss(0|[1-9][0-9]*)\\.pip$").expect("valid regex");
-    let cl_pat = Regex::new("^((?:.*)-)?ss(0|[1-9][0-9]*)-cl(0|[1-9][0-9]*)\\.piplog$").expect("valid regex");
-    
-    let mut basename: Option<String> = None;
     
-    let dir = if path.is_dir() {
+    let (dir, basename) = if path.is_dir() {
         info!("Scanning for partition files in: {}", path.display());
-        path
+        (path, None)
     } else if let Some(fname) = path.file_name() {
         let fname = fname.to_str().ok_or_else(|| PathError::new("not valid UTF-8", path))?;
         if let Some(bname) = discover_basename(fname) {
             let dir = path.parent().ok_or_else(|| PathError::new("path has no parent", path))?;
             info!("Scanning for partition files matching: {}/{}*", dir.display(), bname);
-            basename = Some(bname);
-            dir
+            (dir, Some(bname))
         } else {
             return PathError::err("discover::part_from_path: not a Pippin file", path);
         }
@@ -48,69 +55,299 @@ pub fn part_from_path<P: AsRef<Path>>(path: P) -> Result<RepoFileIO> {
         return PathError::err("discover::part_from_path: neither a file nor a directory", path)
     };
     
-    let mut part_paths = PartPaths::new();
+    let mut diagnostics = Vec::new();
+    let mut parts = scan_dir(dir, basename.as_ref().map(|s| s.as_str()), &mut diagnostics)?;
+    for diagnostic in diagnostics {
+        if let Diagnostic::Duplicate { ref first, .. } = diagnostic {
+            return PathError::err("multiple files map to the same basename and number", first.clone());
+        }
+    }
+    
+    let bname = match basename {
+        Some(bname) => bname,
+        None => match parts.keys().next() {
+            Some(bname) => {
+                if parts.len() > 1 {
+                    warn!("Multiple partitions found in {}; using basename '{}'", dir.display(), bname);
+                }
+                bname.clone()
+            },
+            None => return PathError::err("discover::part_from_path: no Pippin files found in", path),
+        },
+    };
+    match parts.remove(&bname) {
+        Some(part_paths) => Ok(RepoFileIO::for_paths(dir.join(bname), part_paths)),
+        None => PathError::err("discover::part_from_path: no Pippin files found in", path),
+    }
+}
+
+
+/// A partition found by `discover_repo`.
+#[derive(Debug)]
+pub struct DiscoveredPart {
+    /// The partition's `BASENAME` (without trailing `-`; may be empty)
+    pub basename: String,
+    /// The partition number, if `BASENAME` ends with `pnN`
+    pub part_num: Option<u64>,
+    /// Access to the partition's files
+    pub io: RepoFileIO,
+}
+
+/// Partitions found in one directory by `discover_repo`, sorted by basename.
+#[derive(Debug)]
+pub struct DirParts {
+    /// The directory
+    pub dir: PathBuf,
+    /// Partitions found
+    pub parts: Vec<DiscoveredPart>,
+}
+
+/// Problems found by `discover_repo`. None of these stop discovery.
+#[derive(Debug)]
+pub enum Diagnostic {
+    /// Two files map to the same partition file (e.g. `ss1.pip` and
+    /// `-ss1.pip`); only the first is used.
+    Duplicate {
+        /// The file used
+        first: PathBuf,
+        /// The file ignored
+        second: PathBuf,
+    },
+    /// Two partitions in different places have the same partition number.
+    DuplicatePartNum {
+        /// The partition number
+        num: u64,
+        /// Prefix of the first partition found
+        first: PathBuf,
+        /// Prefix of the other partition
+        second: PathBuf,
+    },
+    /// A commit log whose snapshot file is missing. The partition is still
+    /// returned (see `Partition::load_range` on missing snapshots).
+    OrphanLog(PathBuf),
+    /// A partition with commit logs but no snapshot at all.
+    NoSnapshot(PathBuf),
+    /// A `.pip` or `.piplog` file whose name doesn't match the expected
+    /// pattern, or isn't valid UTF-8.
+    BadName(PathBuf),
+    /// A temporary file left by an interrupted write (see `clean_temp_files`)
+    TempFile(PathBuf),
+    /// A directory or file could not be read.
+    Unreadable(PathBuf, Error),
+}
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Diagnostic::Duplicate { ref first, ref second } =>
+                write!(f, "{} duplicates {} (ignored)", second.display(), first.display()),
+            Diagnostic::DuplicatePartNum { num, ref first, ref second } =>
+                write!(f, "partition number {} used by {} and {}", num, first.display(), second.display()),
+            Diagnostic::OrphanLog(ref p) =>
+                write!(f, "commit log without snapshot: {}", p.display()),
+            Diagnostic::NoSnapshot(ref p) =>
+                write!(f, "partition without any snapshot: {}", p.display()),
+            Diagnostic::BadName(ref p) =>
+                write!(f, "file name does not match expected pattern: {}", p.display()),
+            Diagnostic::TempFile(ref p) =>
+                write!(f, "temporary file: {}", p.display()),
+            Diagnostic::Unreadable(ref p, ref e) =>
+                write!(f, "unable to read {}: {}", p.display(), e),
+        }
+    }
+}
+
+/// The result of `discover_repo`.
+#[derive(Debug, Default)]
+pub struct RepoDiscovery {
+    /// Partitions found, grouped by directory (sorted by path)
+    pub dirs: Vec<DirParts>,
+    /// Problems found
+    pub diagnostics: Vec<Diagnostic>,
+}
+impl RepoDiscovery {
+    /// Iterate over all partitions found
+    pub fn parts<'a>(&'a self) -> Box<Iterator<Item = &'a DiscoveredPart> + 'a> {
+        Box::new(self.dirs.iter().flat_map(|d| d.parts.iter()))
+    }
+    /// Count the partitions found
+    pub fn num_parts(&self) -> usize {
+        self.dirs.iter().map(|d| d.parts.len()).fold(0, |a, b| a + b)
+    }
+    /// Consume self, returning all partitions found
+    pub fn into_parts(self) -> Vec<DiscoveredPart> {
+        self.dirs.into_iter().flat_map(|d| d.parts.into_iter()).collect()
+    }
+}
+
+/// Discover all partitions of a repository (see `doc/repo-files.md`), starting
+/// from the directory `path`. If `recursive`, sub-directories are also
+/// searched (symbolic links are not followed).
+/// 
+/// Each distinct basename in each directory is taken to be one partition.
+/// Problems such as duplicate or orphaned files are reported as diagnostics
+/// (and logged) instead of causing failure; only failure to read `path`
+/// itself is an error.
+pub fn discover_repo<P: AsRef<Path>>(path: P, recursive: bool) -> Result<RepoDiscovery> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return PathError::err("discover::discover_repo: not a directory", path);
+    }
+    info!("Discovering partitions in: {}{}", path.display(), if recursive { " (recursive)" } else { "" });
     
-    {   // new scope for filter_skip closure
-    let mut filter_skip = |bname: &str| -> Result<bool> {
-        if let Some(ref req_bname) = basename {
-            // basename known: filter by it
-            if bname != req_bname {
-                return Ok(true);    // skip
+    let mut discovery = RepoDiscovery::default();
+    let mut dirs = vec![path.to_path_buf()];
+    if recursive {
+        for entry in WalkDir::new(path).min_depth(1) {
+            match entry {
+                Ok(entry) => if entry.file_type().is_dir() {
+                    dirs.push(entry.path().to_path_buf());
+                },
+                Err(e) => {
+                    let p = e.path().unwrap_or(path).to_path_buf();
+                    discovery.diagnostics.push(Diagnostic::Unreadable(p, Box::new(io::Error::from(e))));
+                },
             }
         }
-        // done filtering; update basename if necessary
-        if basename == None {
-            basename = Some(bname.to_string()); // assume
+        dirs.sort();
+    }
+    
+    let mut part_nums: HashMap<u64, PathBuf> = HashMap::new();
+    for dir in dirs {
+        let parts = match scan_dir(&dir, None, &mut discovery.diagnostics) {
+            Ok(parts) => parts,
+            Err(e) => {
+                if dir == path {
+                    return Err(e);
+                }
+                discovery.diagnostics.push(Diagnostic::Unreadable(dir, e));
+                continue;
+            },
+        };
+        if parts.is_empty() { continue; }
+        
+        let mut dir_parts = DirParts { dir: dir.clone(), parts: Vec::with_capacity(parts.len()) };
+        for (basename, part_paths) in parts {
+            let prefix = dir.join(&basename);
+            if part_paths.num_ss_files() == 0 {
+                discovery.diagnostics.push(Diagnostic::NoSnapshot(prefix.clone()));
+            }
+            let part_num = part_number(&basename);
+            if let Some(num) = part_num {
+                if let Some(first) = part_nums.get(&num) {
+                    discovery.diagnostics.push(Diagnostic::DuplicatePartNum {
+                        num: num, first: first.clone(), second: prefix.clone()
+                    });
+                }
+                part_nums.entry(num).or_insert_with(|| prefix.clone());
+            }
+            dir_parts.parts.push(DiscoveredPart {
+                basename: basename,
+                part_num: part_num,
+                io: RepoFileIO::for_paths(prefix, part_paths),
+            });
         }
-        Ok(false)   /* do not skip */
-    };
+        discovery.dirs.push(dir_parts);
+    }
     
+    for d in &discovery.diagnostics {
+        warn!("Discovery: {}", d);
+    }
+    Ok(discovery)
+}
+
+/// Get the partition number from a basename ending `pnN`, if any.
+pub fn part_number(basename: &str) -> Option<u64> {
+    let pat = Regex::new("(?:^|[^a-zA-Z0-9])pn(0|[1-9][0-9]*)$").expect("valid regex");
+    pat.captures(basename).and_then(|caps| caps.at(1).expect("cap").parse().ok())
+}
+
+// Scan a single directory (non-recursively) for partition files, optionally
+// only those with a given basename. Returns paths by basename (without the
+// trailing `-`).
+fn scan_dir(dir: &Path, basename: Option<&str>, diagnostics: &mut Vec<Diagnostic>) ->
+        Result<BTreeMap<String, PartPaths>>
+{
+    let ss_pat = Regex::new("^(?:(.*)-)?ss(0|[1-9][0-9]*)\\.pip$").expect("valid regex");
+    let cl_pat = Regex::new("^(?:(.*)-)?ss(0|[1-9][0-9]*)-cl(0|[1-9][0-9]*)\\.piplog$").expect("valid regex");
+    
+    let mut parts: BTreeMap<String, PartPaths> = BTreeMap::new();
     for entry in read_dir(dir)? {
         // —— Get file name ——
         let entry = entry?;
-        let fpath = &entry.path();
+        let fpath = entry.path();
         let os_name = entry.file_name();    // must be named for lifetime
         let fname = match os_name.to_str() {
             Some(s) if s.ends_with(".pip") || s.ends_with(".piplog") => s,
-            _ => { continue; },
+            Some(s) if is_temp_name(s) => {
+                info!("Ignoring temporary file: {}", fpath.display());
+                diagnostics.push(Diagnostic::TempFile(fpath));
+                continue;
+            },
+            Some(_) => { continue; },
+            None => {
+                let lossy = os_name.to_string_lossy();
+                if lossy.ends_with(".pip") || lossy.ends_with(".piplog") {
+                    diagnostics.push(Diagnostic::BadName(fpath));
+                }
+                continue;
+            },
         };
         
         // —— Match, filter and add ——
-        if let Some(caps) = ss_pat.captures(fname) {
-            let bname = caps.at(1).expect("cap");
-            if filter_skip(bname)? { continue; }
-            
-            let ss: usize = caps.at(2).expect("cap").parse()?;
-            trace!("Adding snapshot {}: {}", ss, fpath.display());
-            let has_prev = part_paths.insert_ss(ss, entry.path());
-            // #0011: better error handling
-            assert!(!has_prev, "multiple files map to same basename/number");
+        // (numbers too large to parse count as not matching)
+        let parsed = if let Some(caps) = ss_pat.captures(fname) {
+            caps.at(2).expect("cap").parse().ok().map(|ss| (caps.at(1).unwrap_or(""), ss, None))
         } else if let Some(caps) = cl_pat.captures(fname) {
-            let bname = caps.at(1).expect("cap");
-            if filter_skip(bname)? { continue; }
-            
-            let ss: usize = caps.at(2).expect("cap").parse()?;
-            let cl: usize = caps.at(3).expect("cap").parse()?;
-            trace!("Adding snapshot {} log {}: {}", ss, cl, fpath.display());
-            let has_prev = part_paths.insert_cl(ss, cl, entry.path());
-            // #0011: better error handling
-            assert!(!has_prev, "multiple files map to same basename/number");
+            match (caps.at(2).expect("cap").parse(), caps.at(3).expect("cap").parse()) {
+                (Ok(ss), Ok(cl)) => Some((caps.at(1).unwrap_or(""), ss, Some(cl))),
+                _ => None,
+            }
         } else {
-            warn!(".pip or .piplog file does not match expected pattern: {}", fname);
+            None
+        };
+        let (bname, ss, cl) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                warn!(".pip or .piplog file does not match expected pattern: {}", fname);
+                diagnostics.push(Diagnostic::BadName(fpath));
+                continue;
+            },
+        };
+        if basename.map_or(false, |b| b != bname) {
             continue;
         }
+        
+        let part_paths = parts.entry(bname.to_string()).or_insert_with(PartPaths::new);
+        let prev = match cl {
+            None => part_paths.get_ss(ss),
+            Some(cl) => part_paths.get_cl(ss, cl),
+        }.map(|p| p.to_path_buf());
+        if let Some(first) = prev {
+            diagnostics.push(Diagnostic::Duplicate { first: first, second: fpath });
+            continue;
+        }
+        match cl {
+            None => {
+                trace!("Adding snapshot {}: {}", ss, fpath.display());
+                part_paths.insert_ss(ss, fpath);
+            },
+            Some(cl) => {
+                trace!("Adding snapshot {} log {}: {}", ss, cl, fpath.display());
+                part_paths.insert_cl(ss, cl, fpath);
+            },
+        }
     }
-    }   // destroy filter_skip: end borrow on basename
     
-    if let Some(mut bname) = basename {
-        if bname.ends_with('-') {
-            // RepoFileIO does not expect '-' separator in prefix
-            bname.pop();
+    // Logs without a snapshot:
+    for part_paths in parts.values() {
+        for (ss, cl, path) in part_paths.iter_cl() {
+            if part_paths.get_ss(ss).is_none() {
+                trace!("Log {}-{} has no snapshot: {}", ss, cl, path.display());
+                diagnostics.push(Diagnostic::OrphanLog(path.to_path_buf()));
+            }
         }
-        Ok(RepoFileIO::for_paths(dir.join(bname), part_paths))
-    } else {
-        Err(Box::new(PathError::new("discover::part_from_path: no Pippin files found in", path)))
     }
+    Ok(parts)
 }
 
 
@@ -123,3 +360,80 @@ pub fn discover_basename(fname: &str) -> Option<String> {
     pat.captures(fname)
             .map(|caps| caps.at(1).expect("cap").to_string())
 }
+
+
+/// Remove temporary files left in a directory by interrupted writes (see
+/// `RepoFileIO`). Returns the number of files removed.
+/// 
+/// Do not call this while another process may be writing to the directory,
+/// since the files it is currently writing would be removed.
+pub fn clean_temp_files<P: AsRef<Path>>(dir: P) -> Result<usize> {
+    let mut n = 0;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_str().map_or(false, is_temp_name) {
+            info!("Removing temporary file: {}", entry.path().display());
+            remove_file(entry.path())?;
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+fn is_temp_name(fname: &str) -> bool {
+    fname.ends_with(TEMP_EXT) && {
+        let base = &fname[..fname.len() - TEMP_EXT.len()];
+        base.ends_with(".pip") || base.ends_with(".piplog")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use super::*;
+
+    #[test]
+    fn discover() {
+        let dir = env::temp_dir().join(format!("pippin-discover-{}", ::std::process::id()));
+        let files = ["inbox/pn1-ss1.pip", "inbox/pn1-ss2.pip", "inbox/pn1-ss2-cl1.piplog",
+            "archives/2015-pn6-ss1.pip", "archives/2015-pn6-ss3-cl1.piplog",
+            "archives/2016-pn1-ss1.pip", "archives/ss1.pip", "archives/-ss1.pip",
+            "archives/2016-pn1-ss2.pip.tmp", "archives/notes-ss01.pip",
+            "orphan/x-ss0-cl0.piplog", "orphan/x-ss99999999999999999999999.pip"];
+        for f in &files {
+            let path = dir.join(f);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+        
+        assert_eq!(discover_repo(&dir, false).unwrap().num_parts(), 0);
+        let discovery = discover_repo(&dir, true).unwrap();
+        let names: Vec<_> = discovery.parts().map(|p| (p.basename.as_str(), p.part_num)).collect();
+        assert_eq!(names, vec![("", None), ("2015-pn6", Some(6)), ("2016-pn1", Some(1)),
+            ("pn1", Some(1)), ("x", None)]);
+        assert_eq!(discovery.dirs[1].dir, dir.join("inbox"));
+        assert_eq!(discovery.dirs[1].parts[0].io.paths().num_cl_files(), 1);
+        
+        let mut n = (0, 0, 0, 0, 0, 0);
+        for d in &discovery.diagnostics {
+            match *d {
+                Diagnostic::Duplicate { .. } => n.0 += 1,
+                Diagnostic::DuplicatePartNum { num, .. } => { assert_eq!(num, 1); n.1 += 1 },
+                Diagnostic::OrphanLog(_) => n.2 += 1,
+                Diagnostic::NoSnapshot(_) => n.3 += 1,
+                Diagnostic::BadName(_) => n.4 += 1,
+                Diagnostic::TempFile(_) => n.5 += 1,
+                Diagnostic::Unreadable(..) => panic!("unexpected: {}", d),
+            }
+        }
+        assert_eq!(n, (1, 1, 2, 1, 2, 1));
+        
+        let io = part_from_path(dir.join("inbox/pn1-ss2.pip")).unwrap();
+        assert_eq!(io.paths().num_ss_files(), 2);
+        assert!(part_from_path(dir.join("archives")).is_err());
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
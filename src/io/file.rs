@@ -5,14 +5,21 @@
 //! Pippin: data access for repositories.
 
 use std::path::{Path, PathBuf};
-use std::io::{Read, Write};
-use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::fs::{self, File, OpenOptions};
 use std::ops::Add;
+use std::sync::{Arc, Mutex};
+use std::process;
+use std::thread::sleep;
+use std::time::Duration;
 
 use vec_map::{VecMap, Entry};
+use fs2::{FileExt, lock_contended_error};
 
-use io::RepoIO;
-use error::{Result, ReadOnly};
+use io::{RepoIO, EltSource};
+#[cfg(all(unix, feature = "mmap"))]
+use io::mmap::MappedFile;
+use error::{Result, ReadOnly, LockError};
 
 
 // —————  Partition  —————
@@ -58,6 +65,11 @@ impl PartPaths {
             .and_then(|&(_, ref logs)| logs.get(cl))    // Option<PathBuf>
             .map(|p| p.as_path())
     }
+    /// Iterate over all log files as `(ss, cl, path)` tuples.
+    pub fn iter_cl<'a>(&'a self) -> Box<Iterator<Item = (usize, usize, &'a Path)> + 'a> {
+        Box::new(self.paths.iter().flat_map(|(ss, &(_, ref logs))|
+            logs.iter().map(move |(cl, p)| (ss, cl, p.as_path()))))
+    }
     
     /// Add a path to the list of known files. This does not do any checking.
     /// 
@@ -91,12 +103,38 @@ impl PartPaths {
 
 /// Remembers a set of file names associated with a partition, opens read
 /// and write streams on these and creates new partition files.
+/// 
+/// New snapshot and log files are first written under a temporary name (the
+/// final name with `.tmp` appended) and only moved into place, after being
+/// synchronised to disk, when the stream is flushed. A stream dropped without
+/// being flushed removes its temporary file, thus a crash while writing
+/// cannot leave a truncated snapshot or log under the final name. Files are
+/// known to this `RepoFileIO` once moved into place; moving fails rather than
+/// replace a file created meanwhile by another process. A number whose
+/// temporary file exists (being written by another process, or left over
+/// from a crash; see `discover::clean_temp_files`) is treated as taken.
+/// 
+/// Optionally, an advisory lock may be taken (see `lock`) to stop other
+/// processes using `RepoFileIO` from writing to the same partition.
 #[derive(Debug, Clone)]
 pub struct RepoFileIO {
     readonly: bool,
     // Appended with snapshot/log number and extension to get a file path
     prefix: PathBuf,
     paths: PartPaths,
+    // Held lock, if any; released when the last clone is dropped
+    lock: Option<Arc<LockFile>>,
+}
+
+/// How `RepoFileIO::lock` behaves when another process holds the lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Wait until the lock is released
+    Wait,
+    /// Fail with a `LockError`
+    Try,
+    /// Do not lock but continue in read-only mode (see `set_readonly`)
+    ReadOnlyFallback,
 }
 
 impl RepoFileIO {
@@ -122,6 +160,7 @@ impl RepoFileIO {
             readonly: false,
             prefix: prefix,
             paths: paths,
+            lock: None,
         }
     }
     
@@ -137,10 +176,83 @@ impl RepoFileIO {
         self.readonly = readonly;
     }
     
+    /// Take an advisory lock on the partition. The lock is an operating
+    /// system lock (as by `flock`) on a file named like the partition's files
+    /// with extension `.lock`, which holds the process identifier of the
+    /// holder. It is released by `unlock` or when this `RepoFileIO` and all
+    /// its clones are dropped; the file itself is left in place.
+    /// 
+    /// Since the operating system releases the lock when its holder exits,
+    /// locks are never left behind by processes which no longer exist.
+    /// 
+    /// Locking twice within one process (via separate `RepoFileIO`s) is
+    /// contention like any other, except that `LockMode::Wait` then fails with
+    /// a `LockError` since it would wait forever.
+    /// 
+    /// Returns true if the lock is held on return (including when it was
+    /// already held) or false if `LockMode::ReadOnlyFallback` was used and
+    /// self was made read-only.
+    pub fn lock(&mut self, mode: LockMode) -> Result<bool> {
+        if self.lock.is_some() {
+            return Ok(true);
+        }
+        let mut path = self.prefix.as_os_str().to_os_string();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        loop {
+            match LockFile::acquire(&path)? {
+                Ok(lock) => {
+                    self.lock = Some(Arc::new(lock));
+                    return Ok(true);
+                },
+                Err(pid) => match mode {
+                    LockMode::Wait if pid == Some(process::id()) => {
+                        return LockError::err(path, pid);
+                    },
+                    LockMode::Wait => {
+                        trace!("Waiting for lock: {}", path.display());
+                        sleep(Duration::from_millis(100));
+                    },
+                    LockMode::Try => return LockError::err(path, pid),
+                    LockMode::ReadOnlyFallback => {
+                        info!("Locked by another process; continuing read-only: {}", path.display());
+                        self.readonly = true;
+                        return Ok(false);
+                    },
+                },
+            }
+        }
+    }
+    
+    /// Release a lock taken by `lock` (the lock file remains until all
+    /// clones of this `RepoFileIO` holding it have released it).
+    pub fn unlock(&mut self) {
+        self.lock = None;
+    }
+    
+    /// True if this holds a lock (see `lock`)
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_some()
+    }
+    
     /// Get a reference to the prefix
     pub fn prefix(&self) -> &Path {
         &self.prefix
     }
+    
+    /// Split the prefix into a directory and the start of file names (which
+    /// may be empty). The directory is `.` if the prefix has none.
+    pub fn split_prefix(&self) -> (&Path, String) {
+        if self.prefix.as_os_str().to_string_lossy().ends_with('/') {
+            return (&self.prefix, String::new());
+        }
+        let dir = match self.prefix.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let name = self.prefix.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+        (dir, name)
+    }
     /// Get a reference to the internal store of paths
     pub fn paths(&self) -> &PartPaths {
         &self.paths
@@ -195,17 +307,13 @@ impl RepoIO for RepoFileIO {
         let mut p = self.prefix.as_os_str().to_os_string();
         p.push(format!("-ss{}.pip", ss_num));
         let p = PathBuf::from(p);
-        if self.paths.paths.get(ss_num).map_or(false, |&(ref p, _)| p.is_some()) || p.exists() {
+        if self.paths.get_ss(ss_num).is_some() || p.exists() {
             // File already exists in internal map or on filesystem
             return Ok(None);
         }
         trace!("Creating snapshot file: {}", p.display());
-        let stream = File::create(&p)?;
-        match self.paths.paths.entry(ss_num) {
-            Entry::Occupied(mut entry) => { entry.get_mut().0 = Some(p); },
-            Entry::Vacant(entry) => { entry.insert((Some(p), VecMap::new())); },
-        };
-        Ok(Some(Box::new(stream)))
+        let opt_stream = TempFile::create(p, &mut self.paths, ss_num, None)?;
+        Ok(opt_stream.map(|stream| Box::new(stream) as Box<Write+'a>))
     }
     
     fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
@@ -224,17 +332,430 @@ impl RepoIO for RepoFileIO {
         if self.readonly {
             return ReadOnly::err();
         }
-        let mut logs = &mut self.paths.paths.entry(ss_num).or_insert_with(|| (None, VecMap::new())).1;
         let mut p = self.prefix.as_os_str().to_os_string();
         p.push(format!("-ss{}-cl{}.piplog", ss_num, cl_num));
         let p = PathBuf::from(p);
-        if logs.contains_key(cl_num) || p.exists() {
+        if self.paths.get_cl(ss_num, cl_num).is_some() || p.exists() {
             // File already exists in internal map or on filesystem
             return Ok(None);
         }
         trace!("Creating log file: {}", p.display());
-        let stream = OpenOptions::new().create(true).write(true).append(true).open(&p)?;
-        logs.insert(cl_num, p);
-        Ok(Some(Box::new(stream)))
+        let opt_stream = TempFile::create(p, &mut self.paths, ss_num, Some(cl_num))?;
+        Ok(opt_stream.map(|stream| Box::new(stream) as Box<Write+'a>))
+    }
+    
+    fn truncate_ss_cl(&mut self, ss_num: usize, cl_num: usize, len: usize) -> Result<bool> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        Ok(match self.paths.get_cl(ss_num, cl_num) {
+            Some(p) => {
+                warn!("Truncating log file to {} bytes: {}", len, p.display());
+                let file = OpenOptions::new().write(true).open(p)?;
+                file.set_len(len as u64)?;
+                file.sync_all()?;
+                true
+            },
+            None => false,
+        })
+    }
+    
+    fn ss_source(&self, ss_num: usize) -> Result<Option<Arc<EltSource>>> {
+        Ok(self.paths.get_ss(ss_num).map(|p| {
+            Arc::new(FileSource { path: p.to_path_buf(), file: Mutex::new(None) }) as Arc<EltSource>
+        }))
+    }
+    
+    #[cfg(all(unix, feature = "mmap"))]
+    fn map_ss(&self, ss_num: usize) -> Result<Option<Box<AsRef<[u8]>>>> {
+        Ok(match self.paths.get_ss(ss_num) {
+            Some(p) => Some(Box::new(MappedFile::open(p)?)),
+            None => None,
+        })
+    }
+    
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        Ok(match self.paths.paths.get_mut(ss_num) {
+            Some(&mut (ref mut opt_path, _)) if opt_path.is_some() => {
+                {
+                    let p = opt_path.as_ref().unwrap();
+                    info!("Removing snapshot file: {}", p.display());
+                    fs::remove_file(p)?;
+                }
+                *opt_path = None;
+                true
+            },
+            _ => false,
+        })
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        Ok(match self.paths.paths.get_mut(ss_num) {
+            Some(&mut (_, ref mut logs)) if logs.contains_key(cl_num) => {
+                info!("Removing log file: {}", logs[cl_num].display());
+                fs::remove_file(&logs[cl_num])?;
+                logs.remove(cl_num);
+                true
+            },
+            _ => false,
+        })
+    }
+    
+    fn rescan(&mut self) -> Result<usize> {
+        // Forget files removed since (e.g. by pruning or compaction):
+        for (ss, &mut (ref mut opt_path, ref mut logs)) in self.paths.paths.iter_mut() {
+            if opt_path.as_ref().map_or(false, |p| !p.exists()) {
+                trace!("Snapshot {} no longer found: {}", ss, opt_path.as_ref().unwrap().display());
+                *opt_path = None;
+            }
+            let removed: Vec<usize> = logs.iter()
+                    .filter(|&(_, p)| !p.exists())
+                    .map(|(cl, _)| cl)
+                    .collect();
+            for cl in removed {
+                trace!("Snapshot {} log {} no longer found: {}", ss, cl, logs[cl].display());
+                logs.remove(cl);
+            }
+        }
+        
+        let (dir, prefix) = self.split_prefix();
+        let prefix = prefix + "-ss";
+        let mut n = 0;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let os_name = entry.file_name();
+            let rest = match os_name.to_str() {
+                Some(name) if name.starts_with(&prefix) => &name[prefix.len()..],
+                _ => continue,
+            };
+            // Parse "N.pip" or "N-clM.piplog":
+            let (ss, cl) = if rest.ends_with(".pip") {
+                (rest[..rest.len() - 4].parse().ok(), None)
+            } else if rest.ends_with(".piplog") {
+                let mut parts = rest[..rest.len() - 7].splitn(2, "-cl");
+                match (parts.next(), parts.next()) {
+                    (Some(ss), Some(cl)) => (ss.parse().ok(), cl.parse().ok()),
+                    _ => continue,
+                }
+            } else {
+                continue;
+            };
+            match (ss, cl) {
+                (Some(ss), None) => {
+                    if self.paths.get_ss(ss).is_none() {
+                        trace!("Found new snapshot {}: {}", ss, entry.path().display());
+                        self.paths.insert_ss(ss, entry.path());
+                        n += 1;
+                    }
+                },
+                (Some(ss), Some(cl)) => {
+                    if self.paths.get_cl(ss, cl).is_none() {
+                        trace!("Found new snapshot {} log {}: {}", ss, cl, entry.path().display());
+                        self.paths.insert_cl(ss, cl, entry.path());
+                        n += 1;
+                    }
+                },
+                _ => {
+                    warn!("File name does not match expected pattern: {}", entry.path().display());
+                },
+            }
+        }
+        Ok(n)
+    }
+}
+
+
+// Random access to a snapshot file, opened on first use
+#[derive(Debug)]
+struct FileSource {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+impl EltSource for FileSource {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        let mut file = self.file.lock().expect("file lock");
+        if file.is_none() {
+            trace!("Opening snapshot file for random access: {}", self.path.display());
+            *file = Some(File::open(&self.path)?);
+        }
+        let file = file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(buf)?;
+        Ok(())
+    }
+}
+
+
+// A held lock on a lock file, released on drop
+#[derive(Debug)]
+struct LockFile {
+    path: PathBuf,
+    file: File,
+}
+impl LockFile {
+    // Try to lock the lock file, creating it if necessary. On failure,
+    // returns the identifier of the process holding the lock, if known.
+    fn acquire(path: &Path) -> Result<::std::result::Result<LockFile, Option<u32>>> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+        if let Err(e) = file.try_lock_exclusive() {
+            if e.kind() != lock_contended_error().kind() {
+                return Err(Box::new(e));
+            }
+            let mut contents = String::new();
+            let holder = file.read_to_string(&mut contents).ok()
+                    .and_then(|_| contents.trim().parse::<u32>().ok());
+            return Ok(Err(holder));
+        }
+        // Record our identifier, replacing that of any previous holder:
+        file.set_len(0)?;
+        file.write_all(format!("{}\n", process::id()).as_bytes())?;
+        trace!("Acquired lock: {}", path.display());
+        Ok(Ok(LockFile { path: path.to_path_buf(), file: file }))
+    }
+}
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        trace!("Releasing lock: {}", self.path.display());
+        // Clear our identifier; the lock is released when the file is closed
+        if let Err(e) = self.file.set_len(0) {
+            warn!("Failed to clear lock file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Extension appended to the names of files being written
+pub const TEMP_EXT: &'static str = ".tmp";
+
+// A write stream on a file under a temporary name, moved into place on flush
+// and then added to `paths` as snapshot `ss` or log `(ss, cl)`.
+// 
+// The temporary file must not exist already (otherwise `create` returns
+// `None`), and is moved into place without replacing an existing file (see
+// `move_no_replace`), so that neither replaces a file written by another
+// process. Writes after the first flush go to the file under its final name
+// (the handle is still valid), thus a new log can continue to be appended to.
+struct TempFile<'a> {
+    file: File,
+    temp: PathBuf,
+    path: PathBuf,
+    paths: &'a mut PartPaths,
+    ss: usize,
+    cl: Option<usize>,
+    placed: bool,
+}
+impl<'a> TempFile<'a> {
+    fn create(path: PathBuf, paths: &'a mut PartPaths, ss: usize, cl: Option<usize>) ->
+            Result<Option<TempFile<'a>>>
+    {
+        let mut temp = path.clone().into_os_string();
+        temp.push(TEMP_EXT);
+        let temp = PathBuf::from(temp);
+        let file = match OpenOptions::new().create_new(true).write(true).open(&temp) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                warn!("Temporary file already exists: {}", temp.display());
+                return Ok(None);
+            },
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(Some(TempFile { file: file, temp: temp, path: path, paths: paths, ss: ss, cl: cl,
+                placed: false }))
+    }
+}
+impl<'a> Write for TempFile<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()?;
+        if !self.placed {
+            trace!("Moving into place: {}", self.path.display());
+            let linked = move_no_replace(&self.temp, &self.path)?;
+            self.placed = true;
+            match self.cl {
+                None => self.paths.insert_ss(self.ss, self.path.clone()),
+                Some(cl) => self.paths.insert_cl(self.ss, cl, self.path.clone()),
+            };
+            if linked {
+                fs::remove_file(&self.temp)?;
+            }
+            sync_parent(&self.path)?;
+        }
+        Ok(())
+    }
+}
+impl<'a> Drop for TempFile<'a> {
+    fn drop(&mut self) {
+        if !self.placed {
+            warn!("Not flushed; removing incomplete file: {}", self.temp.display());
+            if let Err(e) = fs::remove_file(&self.temp) {
+                warn!("Failed to remove {}: {}", self.temp.display(), e);
+            }
+        }
+    }
+}
+
+// Move a file to `path`, failing if a file already exists there.
+// 
+// The file is hard-linked to `path` where possible, since this fails
+// atomically if `path` exists; the caller then removes `from` (returns true).
+// Some file systems (e.g. FAT or SMB shares) do not support hard links, in
+// which case the file is renamed after checking that `path` does not exist
+// (returns false); another process could then create `path` in between.
+fn move_no_replace(from: &Path, path: &Path) -> io::Result<bool> {
+    match fs::hard_link(from, path) {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            if e.kind() == io::ErrorKind::AlreadyExists || path.exists() {
+                return Err(e);
+            }
+            debug!("Failed to link {} ({}); renaming instead", path.display(), e);
+            fs::rename(from, path)?;
+            Ok(false)
+        },
+    }
+}
+
+// Make a rename durable by synchronising the directory containing it.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if dir != Path::new("") => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use io::RepoIO;
+    use io::discover::{part_from_path, clean_temp_files};
+    use super::*;
+    
+    #[test]
+    fn write_via_temp_file() {
+        let dir = env::temp_dir().join(format!("pippin-temp-file-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut io = RepoFileIO::new(dir.join("part"));
+        
+        // Dropped without flushing: nothing is left behind
+        io.new_ss(0).unwrap().expect("new ss").write_all(b"partial").unwrap();
+        assert!(!dir.join("part-ss0.pip").exists());
+        assert!(!dir.join("part-ss0.pip.tmp").exists());
+        assert!(!io.has_ss(0));
+        assert_eq!(io.ss_len(), 0);
+        
+        {
+            let mut w = io.new_ss(0).unwrap().expect("new ss");
+            w.write_all(b"complete").unwrap();
+            assert!(dir.join("part-ss0.pip.tmp").exists());
+            w.flush().unwrap();
+        }
+        assert_eq!(fs::read(dir.join("part-ss0.pip")).unwrap(), b"complete");
+        assert!(io.has_ss(0));
+        
+        // A file created meanwhile by someone else is not replaced:
+        {
+            let mut w = io.new_ss_cl(0, 0).unwrap().expect("new log");
+            w.write_all(b"ours").unwrap();
+            fs::write(dir.join("part-ss0-cl0.piplog"), b"theirs").unwrap();
+            assert!(w.flush().is_err());
+        }
+        assert_eq!(fs::read(dir.join("part-ss0-cl0.piplog")).unwrap(), b"theirs");
+        assert!(!dir.join("part-ss0-cl0.piplog.tmp").exists());
+        assert_eq!(io.ss_cl_len(0), 0);
+        fs::remove_file(dir.join("part-ss0-cl0.piplog")).unwrap();
+        
+        // A file removed by someone else is forgotten when rescanning:
+        io.new_ss_cl(0, 1).unwrap().expect("new log").flush().unwrap();
+        assert_eq!(io.ss_cl_len(0), 2);
+        fs::remove_file(dir.join("part-ss0-cl1.piplog")).unwrap();
+        assert_eq!(io.rescan().unwrap(), 0);
+        assert_eq!(io.ss_cl_len(0), 0);
+        
+        // Discovery ignores left-over temporary files
+        fs::write(dir.join("part-ss1.pip.tmp"), b"left over").unwrap();
+        assert!(io.new_ss(1).unwrap().is_none());
+        let found = part_from_path(&dir).unwrap();
+        assert_eq!(found.ss_len(), 1);
+        assert_eq!(clean_temp_files(&dir).unwrap(), 1);
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn locking() {
+        let dir = env::temp_dir().join(format!("pippin-locking-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut io1 = RepoFileIO::new(dir.join("part"));
+        assert!(io1.lock(LockMode::Try).unwrap());
+        assert!(dir.join("part.lock").exists());
+        
+        // Our own process holds the lock, which is contention like any other:
+        let mut io2 = RepoFileIO::new(dir.join("part"));
+        assert!(io2.lock(LockMode::Try).is_err());
+        assert!(!io2.lock(LockMode::ReadOnlyFallback).unwrap());
+        assert!(io2.readonly());
+        
+        // Waiting on our own process would never end:
+        let mut io3 = RepoFileIO::new(dir.join("part"));
+        let e = io3.lock(LockMode::Wait).unwrap_err();
+        assert_eq!(e.downcast_ref::<LockError>().and_then(|e| e.pid()), Some(::std::process::id()));
+        
+        io1.unlock();
+        assert!(io3.lock(LockMode::Try).unwrap());
+        io3.unlock();
+        
+        // A lock file left by a process which no longer exists is not locked:
+        fs::write(dir.join("part.lock"), b"2147483647\n").unwrap();
+        assert!(io1.lock(LockMode::Try).unwrap());
+        assert_eq!(fs::read_to_string(dir.join("part.lock")).unwrap(),
+                format!("{}\n", ::std::process::id()));
+        drop(io1);
+        assert!(io2.clone().lock(LockMode::Try).unwrap());
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[cfg(all(unix, feature = "mmap"))]
+    #[test]
+    fn mapped_snapshot() {
+        use control::DefaultControl;
+        use part::Partition;
+        use state::{StateRead, StateWrite};
+        
+        let dir = env::temp_dir().join(format!("pippin-mmap-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let io = RepoFileIO::new(dir.join("part"));
+        let mut part = Partition::create(DefaultControl::<String, _>::new(io), "mmap")
+                .expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        let id = state.insert_new("mapped".to_string()).expect("insert");
+        part.push_state(state).expect("push");
+        part.write_snapshot().expect("snapshot");
+        let tip = part.tip_key().expect("tip").clone();
+        
+        let io = part_from_path(&dir).unwrap();
+        let map = io.map_ss(1).unwrap().expect("map");
+        assert_eq!((*map).as_ref(), &fs::read(dir.join("part-ss1.pip")).unwrap()[..]);
+        
+        let part2 = Partition::open(DefaultControl::<String, _>::new(io), true).expect("open");
+        assert_eq!(*part2.tip_key().expect("tip"), tip);
+        assert_eq!(part2.tip().expect("tip").get(id), Ok(&"mapped".to_string()));
+        
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
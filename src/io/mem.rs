@@ -0,0 +1,280 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: in-memory repository storage.
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::cmp::min;
+
+use vec_map::VecMap;
+
+use io::{RepoIO, EltSource};
+use error::{Result, make_io_err};
+
+
+// Map of snapshot-number to pair (snapshot, map of log number to log)
+type Store = VecMap<(Option<Vec<u8>>, VecMap<Vec<u8>>)>;
+
+// Identifies one buffer within a `Store`
+#[derive(Clone, Copy, Debug)]
+enum Key {
+    Ss(usize),
+    Cl(usize, usize),
+}
+
+fn get_buf(store: &Store, key: Key) -> Option<&Vec<u8>> {
+    match key {
+        Key::Ss(ss) => store.get(ss).and_then(|&(ref data, _)| data.as_ref()),
+        Key::Cl(ss, cl) => store.get(ss).and_then(|&(_, ref logs)| logs.get(cl)),
+    }
+}
+fn get_buf_mut(store: &mut Store, key: Key) -> Option<&mut Vec<u8>> {
+    match key {
+        Key::Ss(ss) => store.get_mut(ss).and_then(|&mut (ref mut data, _)| data.as_mut()),
+        Key::Cl(ss, cl) => store.get_mut(ss).and_then(|&mut (_, ref mut logs)| logs.get_mut(cl)),
+    }
+}
+
+/// Keeps all snapshots and commit logs in memory as byte buffers.
+///
+/// Unlike `DummyRepoIO` nothing written is forgotten, so a `Partition` can be
+/// written, dropped and re-opened from the same `MemRepoIO`. Data is lost when
+/// the last clone is dropped.
+///
+/// Clones share the same underlying storage, thus one in-memory repository can
+/// be opened by several `Partition`s (each via its own clone). Readers see
+/// data appended after they were opened.
+#[derive(Debug, Clone, Default)]
+pub struct MemRepoIO {
+    store: Arc<Mutex<Store>>,
+}
+impl MemRepoIO {
+    /// Create a new, empty instance
+    pub fn new() -> MemRepoIO {
+        MemRepoIO { store: Arc::new(Mutex::new(VecMap::new())) }
+    }
+
+    /// Count the snapshots present.
+    pub fn num_ss(&self) -> usize {
+        self.store.lock().unwrap().values().filter(|v| v.0.is_some()).count()
+    }
+    /// Count the commit logs present.
+    pub fn num_cl(&self) -> usize {
+        self.store.lock().unwrap().values().map(|v| v.1.len()).fold(0, |a, b| a + b)
+    }
+    /// Get a copy of the data of a snapshot, if found.
+    pub fn ss_data(&self, ss_num: usize) -> Option<Vec<u8>> {
+        get_buf(&self.store.lock().unwrap(), Key::Ss(ss_num)).cloned()
+    }
+    /// Get a copy of the data of a commit log, if found.
+    pub fn cl_data(&self, ss_num: usize, cl_num: usize) -> Option<Vec<u8>> {
+        get_buf(&self.store.lock().unwrap(), Key::Cl(ss_num, cl_num)).cloned()
+    }
+
+    fn reader(&self, key: Key) -> Option<Box<Read+'static>> {
+        if get_buf(&self.store.lock().unwrap(), key).is_some() {
+            Some(Box::new(MemReader { store: self.store.clone(), key: key, pos: 0 }))
+        } else {
+            None
+        }
+    }
+    fn writer(&self, key: Key) -> Box<Write+'static> {
+        Box::new(MemWriter { store: self.store.clone(), key: key })
+    }
+}
+
+impl RepoIO for MemRepoIO {
+    fn ss_len(&self) -> usize {
+        self.store.lock().unwrap().keys().next_back().map(|x| x+1).unwrap_or(0)
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        self.store.lock().unwrap().get(ss_num)
+            .and_then(|&(_, ref logs)| logs.keys().next_back())
+            .map(|x| x+1).unwrap_or(0)
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        get_buf(&self.store.lock().unwrap(), Key::Ss(ss_num)).is_some()
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.reader(Key::Ss(ss_num)))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.reader(Key::Cl(ss_num, cl_num)))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        {
+            let mut store = self.store.lock().unwrap();
+            let pair = store.entry(ss_num).or_insert_with(|| (None, VecMap::new()));
+            if pair.0.is_some() {
+                return Ok(None);
+            }
+            pair.0 = Some(Vec::new());
+        }
+        Ok(Some(self.writer(Key::Ss(ss_num))))
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        let key = Key::Cl(ss_num, cl_num);
+        if get_buf(&self.store.lock().unwrap(), key).is_none() {
+            return Ok(None);
+        }
+        Ok(Some(self.writer(key)))
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) ->
+            Result<Option<Box<Write+'a>>>
+    {
+        {
+            let mut store = self.store.lock().unwrap();
+            let logs = &mut store.entry(ss_num).or_insert_with(|| (None, VecMap::new())).1;
+            if logs.contains_key(cl_num) {
+                return Ok(None);
+            }
+            logs.insert(cl_num, Vec::new());
+        }
+        Ok(Some(self.writer(Key::Cl(ss_num, cl_num))))
+    }
+    fn truncate_ss_cl(&mut self, ss_num: usize, cl_num: usize, len: usize) -> Result<bool> {
+        Ok(match get_buf_mut(&mut self.store.lock().unwrap(), Key::Cl(ss_num, cl_num)) {
+            Some(data) => {
+                data.truncate(len);
+                true
+            },
+            None => false,
+        })
+    }
+    fn ss_source(&self, ss_num: usize) -> Result<Option<Arc<EltSource>>> {
+        if get_buf(&self.store.lock().unwrap(), Key::Ss(ss_num)).is_some() {
+            Ok(Some(Arc::new(MemSource { store: self.store.clone(), ss: ss_num })))
+        } else {
+            Ok(None)
+        }
+    }
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        Ok(match self.store.lock().unwrap().get_mut(ss_num) {
+            Some(&mut (ref mut data, _)) => data.take().is_some(),
+            None => false,
+        })
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        Ok(match self.store.lock().unwrap().get_mut(ss_num) {
+            Some(&mut (_, ref mut logs)) => logs.remove(cl_num).is_some(),
+            None => false,
+        })
+    }
+}
+
+// Reads from a buffer in the store, locking the store only for the duration
+// of each `read` call.
+struct MemReader {
+    store: Arc<Mutex<Store>>,
+    key: Key,
+    pos: usize,
+}
+impl Read for MemReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let store = self.store.lock().unwrap();
+        let data = match get_buf(&store, self.key) {
+            Some(data) => data,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "buffer removed")),
+        };
+        let start = min(self.pos, data.len());
+        let len = min(buf.len(), data.len() - start);
+        buf[0..len].copy_from_slice(&data[start..start+len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+// Appends to a buffer in the store. Each `write` is a single append.
+struct MemWriter {
+    store: Arc<Mutex<Store>>,
+    key: Key,
+}
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut store = self.store.lock().unwrap();
+        match get_buf_mut(&mut store, self.key) {
+            Some(data) => {
+                data.extend_from_slice(buf);
+                Ok(buf.len())
+            },
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "buffer removed")),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+// Random access to a snapshot in the store
+#[derive(Debug)]
+struct MemSource {
+    store: Arc<Mutex<Store>>,
+    ss: usize,
+}
+impl EltSource for MemSource {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        let store = self.store.lock().unwrap();
+        let data = match get_buf(&store, Key::Ss(self.ss)) {
+            Some(data) => data,
+            None => return make_io_err(io::ErrorKind::NotFound, "snapshot removed"),
+        };
+        let pos = pos as usize;
+        if pos + buf.len() > data.len() {
+            return make_io_err(io::ErrorKind::UnexpectedEof, "read beyond end of snapshot");
+        }
+        buf.copy_from_slice(&data[pos..pos + buf.len()]);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use control::DefaultControl;
+    use part::Partition;
+    use state::{StateRead, StateWrite};
+
+    #[test]
+    fn semantics() {
+        let mut io = MemRepoIO::new();
+        assert_eq!(io.ss_len(), 0);
+        io.new_ss(0).unwrap().expect("new ss").write_all(b"snapshot").unwrap();
+        assert!(io.new_ss(0).unwrap().is_none());
+        assert!(io.append_ss_cl(0, 0).unwrap().is_none());
+        io.new_ss_cl(0, 0).unwrap().expect("new cl").write_all(b"log ").unwrap();
+        assert!(io.new_ss_cl(0, 0).unwrap().is_none());
+        io.append_ss_cl(0, 0).unwrap().expect("append cl").write_all(b"more").unwrap();
+        io.new_ss_cl(2, 1).unwrap().expect("new cl").write_all(b"x").unwrap();
+
+        assert_eq!(io.ss_len(), 3);
+        assert_eq!(io.ss_cl_len(0), 1);
+        assert_eq!(io.ss_cl_len(2), 2);
+        assert!(io.has_ss(0) && !io.has_ss(2));
+        assert_eq!((io.num_ss(), io.num_cl()), (1, 2));
+
+        let mut buf = Vec::new();
+        io.read_ss_cl(0, 0).unwrap().expect("read cl").read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"log more");
+        assert!(io.read_ss(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn write_and_reopen() {
+        let io = MemRepoIO::new();
+        let control = DefaultControl::<String, _>::new(io.clone());
+        let mut part = Partition::create(control, "mem repo").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        let id = state.insert_new("one".to_string()).expect("insert");
+        part.push_state(state).expect("push");
+        part.write_fast().expect("write");
+        let tip = part.tip_key().expect("tip").clone();
+
+        let part2 = Partition::open(DefaultControl::<String, _>::new(io), true)
+                .expect("reopen");
+        assert_eq!(*part2.tip_key().expect("tip"), tip);
+        assert_eq!(part2.tip().expect("tip").get(id), Ok(&"one".to_string()));
+    }
+}
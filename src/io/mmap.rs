@@ -0,0 +1,55 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: read-only memory maps of snapshot files (Unix, feature `mmap`).
+
+use std::fs::File;
+use std::ops::Deref;
+use std::path::Path;
+
+use memmap::Mmap;
+
+use error::Result;
+
+
+/// A file mapped read-only into memory.
+///
+/// The file must not be modified while mapped (Pippin never modifies snapshot
+/// files; removing the file is safe).
+#[derive(Debug)]
+pub struct MappedFile {
+    // `None` for empty files, which cannot be mapped
+    map: Option<Mmap>,
+}
+impl MappedFile {
+    /// Map the whole of a file
+    pub fn open(path: &Path) -> Result<MappedFile> {
+        let file = File::open(path)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(MappedFile { map: None });
+        }
+        // Safety: the map is only valid while the file's contents do not
+        // change. Snapshot files are written in full before being placed
+        // (see `RepoFileIO::new_ss`) and never modified afterwards; on Unix,
+        // removing the file (e.g. by `Partition::prune`) leaves the mapped
+        // data intact.
+        let map = unsafe { Mmap::map(&file)? };
+        trace!("Mapped {} bytes of {}", map.len(), path.display());
+        Ok(MappedFile { map: Some(map) })
+    }
+}
+impl Deref for MappedFile {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self.map {
+            Some(ref map) => map,
+            None => &[],
+        }
+    }
+}
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
@@ -6,11 +6,17 @@
 
 use std::io::{Read, Write};
 use std::fmt::Debug;
+use std::sync::Arc;
 
-use error::Result;
+use error::{Result, OtherError};
 
 pub mod discover;
 pub mod file;
+pub mod mem;
+#[cfg(all(target_os = "linux", feature = "inotify"))]
+pub mod watch;
+#[cfg(all(unix, feature = "mmap"))]
+pub mod mmap;
 
 
 /// An interface providing read and/or write access to a suitable location.
@@ -34,7 +40,7 @@ pub trait RepoIO: Debug {
     /// `Partition::create`.
     /// 
     /// This number must not change except to increase when write_snapshot()
-    /// is called.
+    /// or `rescan()` is called.
     fn ss_len(&self) -> usize;
     
     /// One greater than the number of the last log file available for some snapshot
@@ -67,6 +73,10 @@ pub trait RepoIO: Debug {
     /// 
     /// Returns None if a snapshot with number ss_num already exists.
     /// 
+    /// The caller flushes the stream once the snapshot is complete;
+    /// implementations may defer making the data visible until then and may
+    /// discard it if the stream is dropped without being flushed.
+    /// 
     /// Returns a heap-allocated write stream, either to some external resource
     /// (such as a file) or to an internal data-structure.
     /// 
@@ -94,18 +104,100 @@ pub trait RepoIO: Debug {
     /// Returns None if a commit log with number `cl_num` for snapshot `ss_num`
     /// already exists.
     /// 
+    /// As with `new_ss`, the log need not be made visible before the stream
+    /// is first flushed. Writes after a flush must still be appended.
+    /// 
     /// Returns a heap-allocated write stream, either to some external resource
     /// (such as a file) or to an internal data-structure.
     /// 
     /// This can fail due to IO operations failing.
     // #0012: verify atomicity of writes
     fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>>;
+    
+    /// Truncate an existing commit log to `len` bytes. This is used to remove
+    /// a damaged tail from a log (see `Control::damaged_log`).
+    /// 
+    /// Returns false if no commit log with this `ss_num` and `cl_num` exists.
+    /// 
+    /// The default implementation fails, reporting that the operation is not
+    /// supported.
+    fn truncate_ss_cl(&mut self, _ss_num: usize, _cl_num: usize, _len: usize) -> Result<bool> {
+        OtherError::err("truncating commit logs is not supported by this RepoIO")
+    }
+    
+    /// Delete a snapshot file (but not its commit logs). Used by
+    /// `Partition::prune`.
+    /// 
+    /// Returns false if no snapshot with this number exists.
+    /// 
+    /// The default implementation fails, reporting that the operation is not
+    /// supported.
+    fn remove_ss(&mut self, _ss_num: usize) -> Result<bool> {
+        OtherError::err("removing snapshots is not supported by this RepoIO")
+    }
+    
+    /// Delete a commit log. Used by `Partition::prune`.
+    /// 
+    /// Returns false if no commit log with this `ss_num` and `cl_num` exists.
+    /// 
+    /// The default implementation fails, reporting that the operation is not
+    /// supported.
+    fn remove_ss_cl(&mut self, _ss_num: usize, _cl_num: usize) -> Result<bool> {
+        OtherError::err("removing commit logs is not supported by this RepoIO")
+    }
+    
+    /// Get random access to the data of a snapshot, if supported. This allows
+    /// elements to be read from a snapshot only when used (see
+    /// `Control::lazy_elements`).
+    /// 
+    /// Positions passed to the `EltSource` are relative to the start of the
+    /// snapshot file (i.e. the start of what `read_ss` reads).
+    /// 
+    /// Returns `None` if the snapshot is not found or random access is not
+    /// supported (the default implementation).
+    fn ss_source(&self, _ss_num: usize) -> Result<Option<Arc<EltSource>>> {
+        Ok(None)
+    }
+    
+    /// Get the whole contents of a snapshot file as a single buffer, usually
+    /// by mapping the file into memory. This allows snapshots to be read
+    /// without copying element data into temporary buffers.
+    /// 
+    /// Returns `None` if the snapshot is not found or this is not supported
+    /// (the default implementation), in which case `read_ss` is used.
+    fn map_ss(&self, _ss_num: usize) -> Result<Option<Box<AsRef<[u8]>>>> {
+        Ok(None)
+    }
+    
+    /// Look for snapshots and commit logs created since this `RepoIO` was
+    /// created, e.g. by another process (see `Partition::refresh`), and
+    /// forget files which have since been removed.
+    /// 
+    /// Returns the number of new files found. The default implementation
+    /// does nothing and returns zero (suitable when new files are always
+    /// visible anyway).
+    fn rescan(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+}
+
+/// Random access to the data of a snapshot file. See `RepoIO::ss_source`.
+/// 
+/// The data must not change while in use (snapshot files are never modified,
+/// but may be deleted by `Partition::prune`, after which reading fails;
+/// `prune` first loads all elements of states held by the partition).
+/// 
+/// Sources are shared by states, which may be used from several threads.
+pub trait EltSource: Debug + Send + Sync {
+    /// Fill `buf` with data read starting at byte `pos` of the file.
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<()>;
 }
 
 /// Doesn't provide any IO.
 /// 
 /// Can be used for testing but big fat warning: this does not provide any
 /// method to save your data. Write operations succeed but forget the data.
+/// See `MemRepoIO` for an in-memory alternative which retains data.
 #[derive(Debug, Default)]
 pub struct DummyRepoIO {
     // The internal buffer allows us to accept write operations. Data gets
@@ -145,6 +237,15 @@ impl RepoIO for DummyRepoIO {
         self.buf.clear();
         Ok(Some(Box::new(&mut self.buf)))
     }
+    fn truncate_ss_cl(&mut self, _ss_num: usize, _cl_num: usize, _len: usize) -> Result<bool> {
+        Ok(false)
+    }
+    fn remove_ss(&mut self, _ss_num: usize) -> Result<bool> {
+        Ok(false)
+    }
+    fn remove_ss_cl(&mut self, _ss_num: usize, _cl_num: usize) -> Result<bool> {
+        Ok(false)
+    }
 }
 
 impl RepoIO for Box<RepoIO> {
@@ -169,4 +270,22 @@ impl RepoIO for Box<RepoIO> {
     {
         (**self).new_ss_cl(ss_num, cl_num)
     }
+    fn truncate_ss_cl(&mut self, ss_num: usize, cl_num: usize, len: usize) -> Result<bool> {
+        (**self).truncate_ss_cl(ss_num, cl_num, len)
+    }
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        (**self).remove_ss(ss_num)
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        (**self).remove_ss_cl(ss_num, cl_num)
+    }
+    fn ss_source(&self, ss_num: usize) -> Result<Option<Arc<EltSource>>> {
+        (**self).ss_source(ss_num)
+    }
+    fn map_ss(&self, ss_num: usize) -> Result<Option<Box<AsRef<[u8]>>>> {
+        (**self).map_ss(ss_num)
+    }
+    fn rescan(&mut self) -> Result<usize> {
+        (**self).rescan()
+    }
 }
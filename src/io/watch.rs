@@ -0,0 +1,152 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: watching partition files for changes (Linux only, via inotify)
+
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use inotify::{Inotify, WatchMask, EventMask};
+
+use io::file::{RepoFileIO, TEMP_EXT};
+use error::Result;
+
+
+/// Watches the directory holding a partition's files for new snapshots and
+/// logs and for commits appended to logs, for example by another process.
+/// 
+/// This does not read anything itself; call `Partition::refresh` when a
+/// change is reported:
+/// 
+/// ```no_run
+/// use pippin::pip::{Partition, DefaultControl, part_from_path, PartWatcher};
+/// 
+/// let io = part_from_path("./my-partition").unwrap();
+/// let mut watcher = PartWatcher::new(&io).unwrap();
+/// let mut part = Partition::open(DefaultControl::<String, _>::new(io), true).unwrap();
+/// loop {
+///     watcher.wait().unwrap();
+///     let summary = part.refresh().unwrap();
+///     println!("Found {} new states", summary.states);
+/// }
+/// ```
+/// 
+/// Events are merged: after several changes, one call to `wait` or `check`
+/// reports them all.
+pub struct PartWatcher {
+    inotify: Inotify,
+    dir: PathBuf,
+    // Start of the names of partition files
+    prefix: String,
+    buf: Vec<u8>,
+}
+impl PartWatcher {
+    /// Start watching the files of the partition accessed by `io`.
+    pub fn new(io: &RepoFileIO) -> Result<PartWatcher> {
+        let (dir, name) = io.split_prefix();
+        let mut inotify = Inotify::init()?;
+        inotify.add_watch(dir, WatchMask::MODIFY | WatchMask::MOVED_TO | WatchMask::CREATE)?;
+        debug!("Watching for changes in: {}", dir.display());
+        Ok(PartWatcher {
+            inotify: inotify,
+            dir: dir.to_path_buf(),
+            prefix: name + "-ss",
+            buf: vec![0; 4096],
+        })
+    }
+    
+    /// Get the directory watched
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+    
+    /// Block until a relevant change occurs.
+    pub fn wait(&mut self) -> Result<()> {
+        loop {
+            let events = self.inotify.read_events_blocking(&mut self.buf)?;
+            if has_change(&self.prefix, events) {
+                return Ok(());
+            }
+        }
+    }
+    
+    /// Check whether relevant changes occurred since the last call to `wait`
+    /// or `check`, without blocking.
+    pub fn check(&mut self) -> Result<bool> {
+        let mut changed = false;
+        loop {
+            match self.inotify.read_events(&mut self.buf) {
+                Ok(events) => {
+                    let mut events = events.peekable();
+                    if events.peek().is_none() {
+                        return Ok(changed);
+                    }
+                    changed = has_change(&self.prefix, events) || changed;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(changed),
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+    }
+}
+
+// True if any event concerns a (non-temporary) partition file
+fn has_change<'a, I>(prefix: &str, events: I) -> bool
+    where I: Iterator<Item = ::inotify::Event<&'a ::std::ffi::OsStr>>
+{
+    let mut changed = false;
+    for event in events {
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            changed = true;
+            continue;
+        }
+        if let Some(name) = event.name.and_then(|name| name.to_str()) {
+            if name.starts_with(prefix) && !name.ends_with(TEMP_EXT) &&
+                (name.ends_with(".pip") || name.ends_with(".piplog"))
+            {
+                trace!("Partition file changed: {}", name);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use super::*;
+    use control::DefaultControl;
+    use io::discover::part_from_path;
+    use part::Partition;
+    use state::StateWrite;
+
+    #[test]
+    fn watch_and_refresh() {
+        let dir = env::temp_dir().join(format!("pippin-watch-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let io = RepoFileIO::new(dir.join("part"));
+        let mut part = Partition::create(DefaultControl::<String, _>::new(io),
+                "watch").expect("create");
+        let io = part_from_path(&dir).expect("discover");
+        let mut watcher = PartWatcher::new(&io).expect("watch");
+        let mut reader = Partition::open(DefaultControl::<String, _>::new(io), true)
+                .expect("open");
+        assert!(!watcher.check().unwrap());
+        
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert_new("one".to_string()).expect("insert");
+        part.push_state(state).expect("push");
+        part.write_fast().expect("write");
+        
+        assert!(watcher.check().unwrap());
+        let summary = reader.refresh().expect("refresh");
+        assert_eq!(summary.states, 1);
+        assert_eq!(reader.tip_key().expect("tip"), part.tip_key().expect("tip"));
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
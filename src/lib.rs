@@ -15,18 +15,30 @@
 //! (b) objects are normally read-only with explicit copy-on-write, and (c)
 //! objects can be serialised to and deserialised from a byte stream.
 //! 
-//! TODO: scalability. The current code requires reading all data on start-up;
-//! the original approach (partitioning) was abandoned for a host of different
-//! reasons; an alternative approach (reading data on-demand) is planned.
+//! Scalability: by default all data is read on start-up. Snapshots include an
+//! element index, allowing elements to be read on demand instead (see
+//! `Control::lazy_elements`); opening a partition then requires reading only
+//! file headers, element indices and commit logs. Historical states are
+//! kept as commits, with only tips and periodic checkpoints held in memory;
+//! other states are rebuilt when needed (see `Partition::state`).
 //! 
 //! Historical data may be deleted easily, since full snapshots are written
-//! periodically. The limitation here is that distributed synchronisation
+//! periodically (see `Partition::prune`). The limitation here is that distributed synchronisation
 //! requires common history; currently it is up to the user to ensure that
-//! sufficient common history is maintained on machines doing merges.
+//! sufficient common history is maintained on machines doing merges. Replicas
+//! may be synchronised over any byte stream (e.g. TCP) via the `sync` module,
+//! which can also reconcile element content without common history. Clones may
+//! carry limited history; the point where this stops is recorded (see
+//! `Partition::shallow_boundary`) so that merges needing more history report
+//! this (`MergeError::HistoryTruncated`).
 //! 
 //! The library has good support for checking for corruption of data, though
 //! currently limited facilities for dealing with corrupt data.
 //! 
+//! Threads: elements are held via `Arc`, thus states, commits and partitions
+//! are `Send + Sync` where the element type and I/O provider are. A shared
+//! `PartState` may be read from many threads at once.
+//! 
 //! Potentially, it could be extended to support the following, however so far
 //! there has been no need for these features:
 //! 
@@ -66,6 +78,8 @@
 //! 
 //! *   `PartState` — a consistent view of data
 //! *   `Partition` — represents states, controls loading and saving of data
+//! *   `Transaction` — modifications to a state, checked against concurrent
+//!     changes when committed (see `Partition::begin`)
 
 // This should probably be enabled by default for libraries.
 #![warn(missing_docs)]
@@ -81,6 +95,11 @@ extern crate regex;
 extern crate vec_map;
 extern crate rand;
 extern crate walkdir;
+extern crate fs2;
+#[cfg(all(target_os = "linux", feature = "inotify"))]
+extern crate inotify;
+#[cfg(all(unix, feature = "mmap"))]
+extern crate memmap;
 #[macro_use]
 extern crate log;
 
@@ -92,9 +111,13 @@ pub mod io;
 pub mod merge;
 pub mod part;
 pub mod pip;
+mod pmap;
 pub mod rw;
+pub mod shared;
 pub mod state;
+pub mod sync;
 pub mod sum;
+pub mod txn;
 pub mod util;
 
 
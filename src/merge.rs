@@ -34,7 +34,7 @@
 
 use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use commit::{Commit, CommitMeta, EltChange, MakeCommitMeta};
 use state::{PartState, StateRead};
@@ -50,27 +50,28 @@ pub struct TwoWayMerge<'a, E: Element+'a> {
     a: &'a PartState<E>,
     // Second tip
     b: &'a PartState<E>,
-    // Common ancestor
-    c: &'a PartState<E>,
+    // Common ancestor (a copy, since it may not be held by the partition)
+    c: PartState<E>,
     // List of conflicts
     v: Vec<(EltId, EltMerge<E>)>,
 }
 impl<'a, E: Element> TwoWayMerge<'a, E> {
     /// Create an instance. `c` should be a common ancestor state of `a` and `b`.
+    /// `c` is copied (this is cheap; see `PartState::clone_exact`).
     /// 
     /// Operation is `O(A + B + X)` where `A` and `B` are the numbers of
     /// elements in states `a` and `b` respectively and `X` are the number of
     /// conflicts.
     pub fn new<'b>(a: &'b PartState<E>, b: &'b PartState<E>,
-        c: &'b PartState<E>) -> TwoWayMerge<'b, E>
+        c: &PartState<E>) -> TwoWayMerge<'b, E>
     {
         let mut v: Vec<(EltId, EltMerge<E>)> = Vec::new();
-        // #0019: is using `collect()` for a HashMap efficient? Better to add a "clone_map" function to b?
-        let mut map_b: HashMap<_,_> = b.elts_iter().collect();
-        for (id, elt1) in a.elts_iter() {
-            if let Some(elt2) = map_b.remove(&id) {
+        // Elements are compared by sum, thus need not be loaded:
+        let mut map_b: HashMap<_,_> = b.elt_sums_iter().collect();
+        for (id, sum1) in a.elt_sums_iter() {
+            if let Some(sum2) = map_b.remove(&id) {
                 // Have elt in states 1 and 2
-                if elt1 != elt2 {
+                if sum1 != sum2 {
                     v.push((id, EltMerge::Fail));
                 }
             } else {
@@ -82,7 +83,7 @@ impl<'a, E: Element> TwoWayMerge<'a, E> {
             // Have elt in state 2 but not 1
             v.push((id, EltMerge::Fail));
         }
-        TwoWayMerge { a: a, b: b, c: c, v: v }
+        TwoWayMerge { a: a, b: b, c: c.clone_exact(), v: v }
     }
     
     /// Run a solver over all still-ambiguous cases. This need not resolve all
@@ -158,78 +159,82 @@ impl<'a, E: Element> TwoWayMerge<'a, E> {
         let mut sum2: Sum = self.b.statesum() ^ &self.b.metasum();
         
         for (id, result) in self.v {
-            let a = self.a.get_rc(id);
-            let b = self.b.get_rc(id);
+            // Elements with their sums (which states store, thus these are
+            // not recalculated):
+            let (state_a, state_b) = (self.a, self.b);
+            let a = state_a.get_rc(id).and_then(|elt| Ok((elt, state_a.get_sum(id)?)));
+            let b = state_b.get_rc(id).and_then(|elt| Ok((elt, state_b.get_sum(id)?)));
             match result {
                 EltMerge::A => {
-                    if let Ok(elt1) = a {
-                        if let Ok(elt2) = b {
-                            c2.insert(id, EltChange::replacement(elt1.clone()));
-                            sum2.permute(&elt2.sum(id));
-                            sum2.permute(&elt1.sum(id));
+                    if let Ok((elt1, s1)) = a {
+                        if let Ok((_, s2)) = b {
+                            c2.insert(id, EltChange::replacement(elt1.clone(), s1.clone()));
+                            sum2.permute(s2);
+                            sum2.permute(s1);
                         } else {
-                            c2.insert(id, EltChange::insertion(elt1.clone()));
-                            sum2.permute(&elt1.sum(id));
+                            c2.insert(id, EltChange::insertion(elt1.clone(), s1.clone()));
+                            sum2.permute(s1);
                         }
                     } else {
-                        if let Ok(elt2) = b {
+                        if let Ok((_, s2)) = b {
                             c2.insert(id, EltChange::deletion());
-                            sum2.permute(&elt2.sum(id));
+                            sum2.permute(s2);
                         }
                     }
                 },
                 EltMerge::B => {
-                    if let Ok(elt1) = a {
-                        if let Ok(elt2) = b {
-                            c1.insert(id, EltChange::replacement(elt2.clone()));
-                            sum1.permute(&elt1.sum(id));
-                            sum1.permute(&elt2.sum(id));
+                    if let Ok((_, s1)) = a {
+                        if let Ok((elt2, s2)) = b {
+                            c1.insert(id, EltChange::replacement(elt2.clone(), s2.clone()));
+                            sum1.permute(s1);
+                            sum1.permute(s2);
                         } else {
                             c1.insert(id, EltChange::deletion());
-                            sum1.permute(&elt1.sum(id));
+                            sum1.permute(s1);
                         }
                     } else {
-                        if let Ok(elt2) = b {
-                            c1.insert(id, EltChange::insertion(elt2.clone()));
-                            sum1.permute(&elt2.sum(id));
+                        if let Ok((elt2, s2)) = b {
+                            c1.insert(id, EltChange::insertion(elt2.clone(), s2.clone()));
+                            sum1.permute(s2);
                         }
                     }
                 },
                 EltMerge::Value(elt) => {
-                    if let Ok(elt1) = a {
+                    let sum = elt.sum(id);
+                    if let Ok((elt1, s1)) = a {
                         if *elt1 != elt {
-                            sum1.permute(&elt1.sum(id));
-                            sum1.permute(&elt.sum(id));
-                            c1.insert(id, EltChange::replacement(elt.clone()));
+                            sum1.permute(s1);
+                            sum1.permute(&sum);
+                            c1.insert(id, EltChange::replacement(elt.clone(), sum.clone()));
                         }
                     } else {
-                        sum1.permute(&elt.sum(id));
-                        c1.insert(id, EltChange::insertion(elt.clone()));
+                        sum1.permute(&sum);
+                        c1.insert(id, EltChange::insertion(elt.clone(), sum.clone()));
                     }
-                    if let Ok(elt2) = b {
+                    if let Ok((elt2, s2)) = b {
                         if *elt2 != elt {
-                            sum2.permute(&elt2.sum(id));
-                            sum2.permute(&elt.sum(id));
-                            c2.insert(id, EltChange::replacement(elt));
+                            sum2.permute(s2);
+                            sum2.permute(&sum);
+                            c2.insert(id, EltChange::replacement(elt, sum));
                         }
                     } else {
-                        sum2.permute(&elt.sum(id));
-                        c2.insert(id, EltChange::insertion(elt));
+                        sum2.permute(&sum);
+                        c2.insert(id, EltChange::insertion(elt, sum));
                     }
                 },
                 EltMerge::Delete => {
-                    if let Ok(elt1) = a {
+                    if let Ok((_, s1)) = a {
                         c1.insert(id, EltChange::deletion());
-                        sum1.permute(&elt1.sum(id));
+                        sum1.permute(s1);
                     }
-                    if let Ok(elt2) = b {
+                    if let Ok((_, s2)) = b {
                         c2.insert(id, EltChange::deletion());
-                        sum2.permute(&elt2.sum(id));
+                        sum2.permute(s2);
                     }
                 },
                 EltMerge::Rename => {
-                    if let Ok(elt1) = a {
-                        if let Ok(elt2) = b {
+                    if let Ok((elt1, s1)) = a {
+                        if let Ok((elt2, s2)) = b {
                             let new_id = match self.a.gen_id_binary(self.b) {
                                 Ok(id) => id,
                                 Err(_) => { /*#0017: warn about failure*/
@@ -237,18 +242,19 @@ impl<'a, E: Element> TwoWayMerge<'a, E> {
                                 }
                             };
                             
-                            c1.insert(new_id, EltChange::insertion(elt2.clone()));
-                            sum1.permute(&elt2.sum(id));
-                            c2.insert(new_id, EltChange::insertion(elt1.clone()));
-                            sum2.permute(&elt1.sum(id));
+                            // Sums differ with the identifier:
+                            c1.insert(new_id, EltChange::insertion(elt2.clone(), elt2.sum(new_id)));
+                            sum1.permute(s2);
+                            c2.insert(new_id, EltChange::insertion(elt1.clone(), elt1.sum(new_id)));
+                            sum2.permute(s1);
                         } else {
-                            c2.insert(id, EltChange::insertion(elt1.clone()));
-                            sum2.permute(&elt1.sum(id));
+                            c2.insert(id, EltChange::insertion(elt1.clone(), s1.clone()));
+                            sum2.permute(s1);
                         }
                     } else {
-                        if let Ok(elt2) = b {
-                            c1.insert(id, EltChange::insertion(elt2.clone()));
-                            sum1.permute(&elt2.sum(id));
+                        if let Ok((elt2, s2)) = b {
+                            c1.insert(id, EltChange::insertion(elt2.clone(), s2.clone()));
+                            sum1.permute(s2);
                         }
                     }
                 },
@@ -393,7 +399,7 @@ pub enum EltMerge<E: Element> {
     /// Use the value from the second state
     B,
     /// Use a custom value (specified in full)
-    Value(Rc<E>),
+    Value(Arc<E>),
     /// Remove the element
     Delete,
     /// Rename one element and include both; where only one element is present
@@ -408,8 +414,8 @@ pub trait TwoWaySolver<E: Element> {
     /// This function should take possibly-present elements from states A, B
     /// and common ancestor state C, which all have the same identifier, and
     /// return an `EltMerge` object.
-    fn solve<'a>(&self, a: Option<&'a Rc<E>>, b: Option<&'a Rc<E>>,
-        c: Option<&'a Rc<E>>) -> EltMerge<E>;
+    fn solve<'a>(&self, a: Option<&'a Arc<E>>, b: Option<&'a Arc<E>>,
+        c: Option<&'a Arc<E>>) -> EltMerge<E>;
 }
 
 /// Implementation of `TwoWaySolver` which always selects state A.
@@ -423,8 +429,8 @@ impl<E: Element> TwoWaySolveUseA<E> {
     }
 }
 impl<E: Element> TwoWaySolver<E> for TwoWaySolveUseA<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        _: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        _: Option<&Arc<E>>) -> EltMerge<E>
     {
         EltMerge::A
     }
@@ -440,8 +446,8 @@ impl<E: Element> TwoWaySolveUseB<E> {
     }
 }
 impl<E: Element> TwoWaySolver<E> for TwoWaySolveUseB<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        _: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        _: Option<&Arc<E>>) -> EltMerge<E>
     {
         EltMerge::B
     }
@@ -457,8 +463,8 @@ impl<E: Element> TwoWaySolveUseC<E> {
     }
 }
 impl<E: Element> TwoWaySolver<E> for TwoWaySolveUseC<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        c: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        c: Option<&Arc<E>>) -> EltMerge<E>
     {
         match c {
             Some(elt) => EltMerge::Value((*elt).clone()),
@@ -477,8 +483,8 @@ impl<E: Element> TwoWaySolveFail<E> {
     }
 }
 impl<E: Element> TwoWaySolver<E> for TwoWaySolveFail<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        _: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        _: Option<&Arc<E>>) -> EltMerge<E>
     {
         EltMerge::Fail
     }
@@ -503,8 +509,8 @@ impl<'a, E: Element, S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a>
 impl<'a, E: Element, S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a> TwoWaySolver<E>
     for TwoWaySolverChain<'a, E, S, T>
 {
-    fn solve(&self, a: Option<&Rc<E>>, b: Option<&Rc<E>>,
-        c: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, a: Option<&Arc<E>>, b: Option<&Arc<E>>,
+        c: Option<&Arc<E>>) -> EltMerge<E>
     {
         let result = self.s.solve(a, b, c);
         if result != EltMerge::Fail {
@@ -534,8 +540,8 @@ impl<E: Element> AncestorSolver2W<E> {
     }
 }
 impl<E: Element> TwoWaySolver<E> for AncestorSolver2W<E> {
-    fn solve<'a>(&self, a: Option<&'a Rc<E>>, b: Option<&'a Rc<E>>,
-        c: Option<&'a Rc<E>>) -> EltMerge<E>
+    fn solve<'a>(&self, a: Option<&'a Arc<E>>, b: Option<&'a Arc<E>>,
+        c: Option<&'a Arc<E>>) -> EltMerge<E>
     {
         // Assumption: a != b
         if a == c {
@@ -561,8 +567,8 @@ impl<E: Element> RenamingSolver2W<E> {
     }
 }
 impl<E: Element> TwoWaySolver<E> for RenamingSolver2W<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        c: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        c: Option<&Arc<E>>) -> EltMerge<E>
     {
         if c == None {
             EltMerge::Rename
@@ -4,26 +4,34 @@
 
 //! Pippin: partition
 
-use std::io::ErrorKind;
-use std::collections::{HashSet, VecDeque};
+use std::io::{Read, Write, ErrorKind};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::collections::hash_set as hs;
 use std::result;
 use std::ops::Deref;
 use std::usize;
-use std::cmp::min;
+use std::cmp::{min, max};
+use std::sync::{Arc, Mutex};
+use std::vec;
 
-use hashindexed::{HashIndexed, Iter};
+use hashindexed::HashIndexed;
 
 use commit::Commit;
-use control::Control;
+use control::{Control, LogRollover, LogDamage, LogRecovery};
 use elt::Element;
-use error::{Result, TipError, PatchOp, MatchError, MergeError, OtherError, make_io_err};
+use error::{Result, TipError, PatchOp, ElementOp, MatchError, MergeError, TxnError, BundleError,
+        OtherError, make_io_err};
+use io::{EltSource, RepoIO};
 use merge::{TwoWayMerge, TwoWaySolver};
 use rw::header::{FileType, FileHeader, validate_repo_name, read_head, write_head};
-use rw::snapshot::{read_snapshot, write_snapshot};
-use rw::commitlog::{read_log, start_log, write_commit};
-use state::{PartState, MutPartState, PartStateSumComparator};
-use sum::Sum;
+use rw::snapshot::{read_snapshot_slice, read_snapshot_lazy, read_snapshot_summary,
+        has_index, write_snapshot};
+use rw::commitlog::{LogPos, read_log, read_log_recover, skip_log, start_log, write_commit};
+use rw::bundle::{read_requires, write_bundle};
+use state::{PartState, MutPartState, PartStateSumComparator, StateRead};
+use sum::{Sum, SUM_BYTES};
+use txn::Transaction;
+use util::CountingReader;
 
 
 /// A *partition* is a sub-set of the entire set such that (a) each element is
@@ -51,14 +59,108 @@ pub struct Partition<C: Control> {
     ss0: usize,
     // Number of latest snapshot file loaded + 1; 0 if nothing loaded and never less than ss0
     ss1: usize,
-    // Known committed states indexed by statesum 
-    states: HashIndexed<PartState<C::Element>, Sum, PartStateSumComparator>,
-    // All states not in `states` which are known to be superceded
+    // Committed states held in memory, indexed by statesum: all tips, states
+    // read from snapshots and checkpoints (see `Control::state_checkpoint_interval`)
+    states: HashIndexed<Arc<PartState<C::Element>>, Sum, PartStateSumComparator>,
+    // Each known state created from a commit, with this commit and the
+    // number of commits since the last state without a commit. States not
+    // in `states` are rebuilt from these on demand.
+    history: HashMap<Sum, (Commit<C::Element>, usize)>,
+    // States recently rebuilt from `history`, most recently used first
+    cache: Mutex<VecDeque<Arc<PartState<C::Element>>>>,
+    // All states not known which are known to be superceded
     ancestors: HashSet<Sum>,
+    // Shallow boundary: states whose parents were intentionally not copied,
+    // as recorded in file headers
+    boundary: HashSet<Sum>,
     // All states without a known successor
     tips: HashSet<Sum>,
     // Commits created but not yet saved to disk. First in at front; use as queue.
     unsaved: VecDeque<Commit<C::Element>>,
+    // The log most recently written to, if it may be appended to
+    log: Option<CurrentLog>,
+    // Length of each commit log (ss, cl) read or written, including header,
+    // and the checksum of its last commit (if any). Used to read only new
+    // commits when refreshing, unless the log has since been replaced.
+    log_lens: HashMap<(usize, usize), (usize, Option<Sum>)>,
+}
+
+// A commit log created by this partition, tracked to allow appending
+#[derive(Clone, Debug)]
+struct CurrentLog {
+    ss: usize,
+    cl: usize,
+    commits: usize,
+    bytes: usize,
+    // checksum of the last commit written
+    last: Option<Sum>,
+}
+impl CurrentLog {
+    // True if a commit of length `len` may be appended
+    fn fits(&self, rollover: &LogRollover, len: usize) -> bool {
+        self.commits < rollover.max_commits && self.bytes + len <= rollover.max_bytes
+    }
+}
+
+/// Summary of changes found by `Partition::refresh`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Refresh {
+    /// Number of new snapshots loaded
+    pub snapshots: usize,
+    /// Number of commit logs (new or extended) from which commits were read
+    pub logs: usize,
+    /// Number of commits read (including any already known)
+    pub commits: usize,
+    /// Number of new states
+    pub states: usize,
+    /// True if the set of tips changed
+    pub tips_changed: bool,
+}
+impl Refresh {
+    /// True if nothing new was found
+    pub fn is_empty(&self) -> bool {
+        self.snapshots == 0 && self.states == 0
+    }
+}
+
+/// Selects history kept by `Partition::prune`. In all cases, history needed
+/// to rebuild the partition's tips is kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Keep {
+    /// Keep this many of the latest snapshots (at least one), with their logs
+    Snapshots(usize),
+    /// Keep states from this time (a timestamp as in `CommitMeta::timestamp`)
+    /// onwards: the latest snapshot written at or before this time and all
+    /// later files are kept
+    Since(i64),
+    /// Keep these states (identified by statesum) available
+    States(HashSet<Sum>),
+}
+
+/// Summary of what `Partition::prune` removed.
+#[derive(Clone, Debug, Default)]
+pub struct Pruned {
+    /// Number of the oldest snapshot kept
+    pub ss0: usize,
+    /// Snapshots removed
+    pub snapshots: Vec<usize>,
+    /// Commit logs removed, as `(ss, cl)` pairs
+    pub logs: Vec<(usize, usize)>,
+    /// States which could be rebuilt before but not after pruning
+    pub lost: Vec<Sum>,
+}
+
+/// Summary of a compaction by `Partition::compact_logs`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compacted {
+    /// Number of the new log (usually zero; see `Partition::compact_logs`)
+    pub cl: usize,
+    /// Number of logs replaced
+    pub logs: usize,
+    /// Number of commits in the new log
+    pub commits: usize,
+    /// Number of duplicate commits omitted
+    pub duplicates: usize,
 }
 
 // Methods creating a partition, loading its data or checking status
@@ -88,21 +190,27 @@ impl<C: Control> Partition<C> {
             ss0: ss,
             ss1: ss + 1,
             states: HashIndexed::new(),
+            history: HashMap::new(),
+            cache: Mutex::new(VecDeque::new()),
             ancestors: HashSet::new(),
+            boundary: HashSet::new(),
             tips: HashSet::new(),
             unsaved: VecDeque::new(),
+            log: None,
+            log_lens: HashMap::new(),
         };
         let header = part.make_header(FileType::Snapshot(0))?;
         
          if let Some(mut writer) = part.control.io_mut().new_ss(ss)? {
             write_head(&header, &mut writer)?;
             write_snapshot(&state, &mut writer)?;
+            writer.flush()?;
         } else {
             return make_io_err(ErrorKind::AlreadyExists, "snapshot already exists");
         }
         
         part.tips.insert(state.statesum().clone());
-        part.states.insert(state);
+        part.states.insert(Arc::new(state));
         
         Ok(part)
     }
@@ -133,31 +241,28 @@ impl<C: Control> Partition<C> {
         let ss_len = control.io().ss_len();
         for ss in (0..ss_len).rev() {
             debug!("Partition: reading snapshot {}", ss);
-            let result = if let Some(mut ssf) = control.io().read_ss(ss)? {
-                let head = read_head(&mut *ssf)?;
+            let result = if let Some((head, state)) = read_ss_file(&control, ss, read_data)? {
                 trace!("Partition: name: {}", head.name);
-                
-                let state = if read_data {
-                    Some(read_snapshot(&mut *ssf, head.ftype.ver())?)
-                } else {
-                    None
-                };
-                
-                Some((head.name, state))
+                Some((head, state))
             } else {
                 warn!("Partition: missing snapshot {}", ss);
                 None
             };
-            if let Some((name, opt_state)) = result {
+            if let Some((head, opt_state)) = result {
                 let mut part = Partition {
                     control,
-                    name,
+                    name: head.name,
                     ss0: 0,
                     ss1: 0,
                     states: HashIndexed::new(),
+                    history: HashMap::new(),
+                    cache: Mutex::new(VecDeque::new()),
                     ancestors: HashSet::new(),
+                    boundary: head.shallow.into_iter().collect(),
                     tips: HashSet::new(),
                     unsaved: VecDeque::new(),
+                    log: None,
+                    log_lens: HashMap::new(),
                 };
                 
                 if let Some(state) = opt_state {
@@ -165,11 +270,11 @@ impl<C: Control> Partition<C> {
                     for parent in state.parents() {
                         part.ancestors.insert(parent.clone());
                     }
-                    part.states.insert(state);
+                    part.states.insert(Arc::new(state));
                     part.control.snapshot_policy().reset();
                     part.ss0 = ss;
                     for ss2 in ss..ss_len {
-                        part.read_commits_for_ss(ss2)?;
+                        part.read_commits_for_ss(ss2, true)?;
                     }
                     part.ss1 = ss_len;
                 }
@@ -205,7 +310,8 @@ impl<C: Control> Partition<C> {
     /// does not overlap with this range, all snapshots in between will be
     /// loaded.
     /// 
-    /// TODO: allow loading new & extended log files when snapshot is already loaded.
+    /// To load new and extended log files for snapshots already loaded, use
+    /// `refresh`.
     pub fn load_range(&mut self, ss0: usize, ss1: usize) -> Result<()> {
         // We have to consider several cases: nothing previously loaded, that
         // we're loading data older than what was previously loaded, or newer,
@@ -231,10 +337,21 @@ impl<C: Control> Partition<C> {
         while ss0 > 0 && !self.control.io().has_ss(ss0) { ss0 -= 1; }
         
         if ss0 == 0 && !self.control.io().has_ss(ss0) {
-            // No initial snapshot; assume a blank state
-            let state = PartState::new(self.control.as_mcm_ref_mut());
-            self.tips.insert(state.statesum().clone());
-            self.states.insert(state);
+            // If all files before some snapshot are missing, history was
+            // pruned (see `prune`); otherwise there is no initial snapshot.
+            let io = self.control.io();
+            match (0..ss_len).find(|&ss| io.has_ss(ss)) {
+                Some(ss) if (0..ss).all(|ss2| io.ss_cl_len(ss2) == 0) => {
+                    ss0 = ss;
+                    ss1 = max(ss1, ss + 1);
+                },
+                _ => {
+                    // No initial snapshot; assume a blank state
+                    let state = PartState::new(self.control.as_mcm_ref_mut());
+                    self.tips.insert(state.statesum().clone());
+                    self.states.insert(Arc::new(state));
+                },
+            }
         }
         
         let mut require_ss = false;
@@ -243,30 +360,7 @@ impl<C: Control> Partition<C> {
             if self.ss0 <= ss && ss < self.ss1 { continue; }
             let at_tip = ss >= self.ss1;
             
-            debug!("Partition {}: reading snapshot {}", self.name, ss);
-            let opt_result = if let Some(mut r) = self.control.io().read_ss(ss)? {
-                let head = read_head(&mut r)?;
-                let state = read_snapshot(&mut r, head.ftype.ver())?;
-                Some((head, state))
-            } else {
-                warn!("Partition {}: missing snapshot {}", self.name, ss);
-                None
-            };
-            
-            if let Some((header, state)) = opt_result {
-                self.verify_header(header)?;
-                
-                if !self.ancestors.contains(state.statesum()) {
-                    self.tips.insert(state.statesum().clone());
-                }
-                for parent in state.parents() {
-                    if !self.states.contains(parent) {
-                        self.ancestors.insert(parent.clone());
-                    }
-                }
-                // TODO: check that classification in state equals that of this partition? (Already done in this case.)
-                self.states.insert(state);
-                
+            if self.load_snapshot(ss)? {
                 require_ss = false;
                 if at_tip {
                     self.control.snapshot_policy().reset();
@@ -276,7 +370,7 @@ impl<C: Control> Partition<C> {
                 require_ss = at_tip;
             }
             
-            self.read_commits_for_ss(ss)?;
+            self.read_commits_for_ss(ss, true)?;
             if at_tip {
                 self.ss1 = ss + 1;
             }
@@ -296,26 +390,343 @@ impl<C: Control> Partition<C> {
         Ok(())
     }
     
-    // Read commit logs for a snapshot
-    fn read_commits_for_ss(&mut self, ss: usize) -> Result<()> {
+    /// Check for snapshots and commits written since data was last loaded
+    /// (e.g. by another process) and load them, updating tips. New files are
+    /// found via `RepoIO::rescan`.
+    /// 
+    /// Only the newest snapshot loaded and any newer snapshots are checked;
+    /// of commit logs already read, only the part not yet read is read. A
+    /// damaged or partially written commit at the end of a log is not
+    /// treated as an error (it may be in the process of being written), but
+    /// is read again on the next refresh.
+    /// 
+    /// Does nothing if no data has been loaded (see `load_latest`).
+    pub fn refresh(&mut self) -> Result<Refresh> {
+        let mut summary = Refresh::default();
+        if self.ss1 == self.ss0 {
+            return Ok(summary);
+        }
+        let states_len = self.states_len();
+        let tips = self.tips.clone();
+        
+        self.control.io_mut().rescan()?;
+        let ss_len = self.control.io().ss_len();
+        for ss in (self.ss1 - 1)..ss_len {
+            if ss >= self.ss1 && self.load_snapshot(ss)? {
+                summary.snapshots += 1;
+                self.control.snapshot_policy().reset();
+            }
+            let (logs, commits) = self.read_commits_for_ss(ss, false)?;
+            summary.logs += logs;
+            summary.commits += commits;
+            self.ss1 = ss + 1;
+        }
+        
+        summary.states = self.states_len() - states_len;
+        summary.tips_changed = self.tips != tips;
+        if summary.states > 0 {
+            debug!("Partition {}: refresh found {} new states", self.name, summary.states);
+        }
+        Ok(summary)
+    }
+    
+    /// Import history from another replica of this partition, whose files
+    /// are accessed via `io` (e.g. a copy of the partition synchronised
+    /// through a shared folder).
+    /// 
+    /// All snapshots and commit logs of the replica are read; the repo name
+    /// of each must match that of this partition. Each commit not already
+    /// known is added (as by `add_commit`) and queued to be written to this
+    /// partition's own commit logs; these are then written (see
+    /// `write_fast`). A snapshot whose state is not known but whose parent
+    /// is known is imported as a commit.
+    /// 
+    /// Commits whose parent cannot be found locally or in the replica's
+    /// files are skipped with a warning; if local history is incomplete it
+    /// may help to call `load_all` first. Damage at the end of one of the
+    /// replica's logs is likewise skipped (the replica's files are never
+    /// modified); other read errors and corruption within a log fail.
+    /// 
+    /// Returns the tips which are new (usually with more than one tip; call
+    /// `merge` to resolve). Requires that the partition is loaded (see
+    /// `is_loaded`).
+    pub fn pull_from(&mut self, io: &RepoIO) -> Result<Vec<Sum>> {
+        if !self.is_loaded() {
+            return Err(Box::new(TipError::NotReady));
+        }
+        let tips = self.tips.clone();
+        
+        // Commits in file order; snapshots converted to commits:
+        let mut queue = vec![];
+        for ss in 0..io.ss_len() {
+            if let Some(mut r) = io.read_ss(ss)? {
+                let header = read_head(&mut r)?;
+                let ver = header.ftype.ver();
+                self.verify_header(header)?;
+                let state = self.control.read_snapshot(&mut r, ver)?;
+                if !self.is_known(state.statesum()) && !state.parents().is_empty() {
+                    if let Some(parent) = self.state(&state.parents()[0]) {
+                        queue.push(snapshot_commit(&parent, &state)?);
+                    }
+                }
+            }
+            for cl in 0..io.ss_cl_len(ss) {
+                if let Some(mut r) = io.read_ss_cl(ss, cl)? {
+                    let header = read_head(&mut r)?;
+                    let ver = header.ftype.ver();
+                    self.verify_header(header)?;
+                    let (_, error) = read_log_recover(&mut r, &mut queue, ver, LogPos::default())?;
+                    if let Some(e) = error {
+                        warn!("Partition {}: skipping damaged end of pulled commit log {}-{}: {}",
+                                self.name, ss, cl, e);
+                    }
+                }
+            }
+        }
+        
+        let imported = self.import_commits(queue)?;
+        debug!("Partition {}: pulled {} commits", self.name, imported);
+        self.write_fast()?;
+        
+        let mut new_tips: Vec<Sum> = self.tips.difference(&tips).cloned().collect();
+        new_tips.sort();
+        Ok(new_tips)
+    }
+    
+    /// Write a bundle: a file holding all commits leading to the states
+    /// `tips` from states in (or descended from) `known`, for transfer to
+    /// another replica of this partition (see `import_bundle`).
+    /// 
+    /// `known` should list states known to the recipient (e.g. its tips); it
+    /// may include states not known locally. The bundle lists which states
+    /// the recipient must know in order to apply its commits: parents of
+    /// bundled commits which are in `known` or their ancestors, and any
+    /// initial states (without parents) reached.
+    /// 
+    /// Fails with `BundleError::NoHistory` if a state to be bundled is not
+    /// known or its commit is not available (if history is not fully loaded,
+    /// `load_all` may help). Returns the number of commits written.
+    pub fn export_bundle(&mut self, tips: &[Sum], known: &[Sum], writer: &mut Write) ->
+            Result<usize>
+    {
+        // States the recipient knows, i.e. `known` and its ancestors:
+        let mut excluded = HashSet::new();
+        let mut next: Vec<&Sum> = known.iter().collect();
+        while let Some(key) = next.pop() {
+            if !excluded.insert(key.clone()) { continue; }
+            if let Some(parents) = self.parents_of(key) {
+                next.extend(parents);
+            }
+        }
+        
+        // Walk back from tips to excluded states, ordering parents before
+        // children (depth-first, post-order):
+        let mut commits = vec![];
+        let mut requires = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut stack: Vec<(Sum, bool)> = tips.iter().rev()
+                .map(|tip| (tip.clone(), false)).collect();
+        while let Some((key, expanded)) = stack.pop() {
+            if expanded {
+                let commit = self.commit(&key)?.ok_or_else(|| BundleError::NoHistory(key.clone()))?;
+                commits.push(commit);
+                continue;
+            }
+            if excluded.contains(&key) {
+                requires.insert(key);
+                continue;
+            }
+            if !visited.insert(key.clone()) { continue; }
+            let parents = match self.parents_of(&key) {
+                Some(parents) => parents.to_vec(),
+                None => return Err(Box::new(BundleError::NoHistory(key))),
+            };
+            if parents.is_empty() {
+                // An initial state: this cannot be made from a commit
+                requires.insert(key);
+                continue;
+            }
+            stack.push((key, true));
+            for parent in parents.into_iter().rev() {
+                if !self.is_known(&parent) {
+                    requires.insert(parent);
+                } else {
+                    stack.push((parent, false));
+                }
+            }
+        }
+        let mut requires: Vec<Sum> = requires.into_iter().collect();
+        requires.sort();
+        
+        let header = self.make_header(FileType::Bundle(0))?;
+        write_head(&header, writer)?;
+        write_bundle(&requires, &commits, writer)?;
+        debug!("Partition {}: exported bundle with {} commits ({} states required)",
+                self.name, commits.len(), requires.len());
+        Ok(commits.len())
+    }
+    
+    /// Import a bundle written by `export_bundle`.
+    /// 
+    /// The bundle's repo name must match that of this partition. Before
+    /// anything is added, the states required by the bundle are checked;
+    /// if any are not known this fails with `BundleError::MissingStates`
+    /// (if history is not fully loaded, `load_all` may help). Each commit not
+    /// already known is then added (as by `import_commits`) and these are
+    /// written to this partition's commit logs (see `write_fast`). The
+    /// header is passed to `Control::read_header` and its shallow boundary
+    /// added only once the bundle has been read and checked.
+    /// 
+    /// Returns the tips which are new (usually with more than one tip; call
+    /// `merge` to resolve). Requires that the partition is loaded (see
+    /// `is_loaded`).
+    pub fn import_bundle(&mut self, reader: &mut Read) -> Result<Vec<Sum>> {
+        if !self.is_loaded() {
+            return Err(Box::new(TipError::NotReady));
+        }
+        let tips = self.tips.clone();
+        
+        let header = read_head(reader)?;
+        let ver = match header.ftype {
+            FileType::Bundle(ver) => ver,
+            _ => return OtherError::err("not a bundle file"),
+        };
+        if self.name != header.name {
+            return OtherError::err("repository name does not match when importing bundle (wrong repo?)");
+        }
+        let missing: Vec<Sum> = read_requires(reader, ver)?.into_iter()
+                .filter(|key| !self.is_known(key)).collect();
+        if !missing.is_empty() {
+            return Err(Box::new(BundleError::MissingStates(missing)));
+        }
         let mut queue = vec![];
+        read_log(reader, &mut queue, ver)?;
+        
+        // Header data is only used once the bundle is accepted:
+        self.control.read_header(&header)?;
+        let imported = self.import_commits(queue)?;
+        self.boundary.extend(header.shallow);
+        debug!("Partition {}: imported {} commits from bundle", self.name, imported);
+        self.write_fast()?;
+        
+        let mut new_tips: Vec<Sum> = self.tips.difference(&tips).cloned().collect();
+        new_tips.sort();
+        Ok(new_tips)
+    }
+    
+    // Load a snapshot. Returns false if not found. A snapshot of a state
+    // already known (e.g. from a commit log) is not added again.
+    fn load_snapshot(&mut self, ss: usize) -> Result<bool> {
+        debug!("Partition {}: reading snapshot {}", self.name, ss);
+        let opt_result = if let Some((head, state)) = read_ss_file(&self.control, ss, true)? {
+            Some((head, state.expect("state read")))
+        } else {
+            warn!("Partition {}: missing snapshot {}", self.name, ss);
+            None
+        };
+        
+        if let Some((header, state)) = opt_result {
+            self.verify_header(header)?;
+            if self.is_known(state.statesum()) {
+                trace!("Partition {}: state of snapshot {} already known", self.name, ss);
+                return Ok(true);
+            }
+            
+            for parent in state.parents() {
+                self.tips.remove(parent);
+                if !self.is_known(parent) {
+                    self.ancestors.insert(parent.clone());
+                }
+            }
+            // A state with known successors is in 'ancestors' and is no tip:
+            if !self.ancestors.contains(state.statesum()) {
+                self.tips.insert(state.statesum().clone());
+            }
+            // TODO: check that classification in state equals that of this partition? (Already done in this case.)
+            self.states.insert(Arc::new(state));
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+    
+    // Read commit logs for a snapshot, skipping the part of each log already
+    // read (unless the log has since been replaced, e.g. by `compact_logs` in
+    // another process). If `recover` is false, damage is assumed to be a
+    // partially written commit and ignored for now.
+    // 
+    // Returns the number of logs with new commits and the number of commits read.
+    fn read_commits_for_ss(&mut self, ss: usize, recover: bool) -> Result<(usize, usize)> {
+        let mut queue = vec![];
+        let mut logs = 0;
         for cl in 0..self.control.io().ss_cl_len(ss) {
-            debug!("Partition {}: reading commit log {}-{}", self.name, ss, cl);
-            let opt_header = if let Some(mut r) = self.control.io().read_ss_cl(ss, cl)? {
+            let mut known = self.log_lens.get(&(ss, cl)).cloned();
+            let opt_result = loop {
+                let mut r = match self.control.io().read_ss_cl(ss, cl)? {
+                    Some(r) => CountingReader::new(r),
+                    None => break None,
+                };
                 let header = read_head(&mut r)?;
-                read_log(&mut r, &mut queue, header.ftype.ver())?;
-                Some(header)
-            } else {
-                warn!("Partition {}: missing commit log {}-{}", self.name, ss, cl);
-                None
+                let head_len = r.count();
+                let mut from = LogPos::default();
+                // A log without commits is simply read again
+                if let Some((len, Some(last))) = known.take() {
+                    let pos = LogPos { len: len.saturating_sub(head_len), last: Some(last) };
+                    if !skip_log(&mut r, &pos)? {
+                        debug!("Partition {}: commit log {}-{} changed since last read",
+                                self.name, ss, cl);
+                        continue;
+                    }
+                    trace!("Partition {}: reading commit log {}-{} after {} bytes",
+                            self.name, ss, cl, pos.len);
+                    from = pos;
+                } else {
+                    debug!("Partition {}: reading commit log {}-{}", self.name, ss, cl);
+                }
+                let n = queue.len();
+                let (pos, error) = read_log_recover(&mut r, &mut queue, header.ftype.ver(), from)?;
+                if queue.len() > n { logs += 1; }
+                let damage = error.map(|error| LogDamage {
+                    ss: ss, cl: cl, offset: head_len + pos.len, error: error
+                });
+                break Some((header, head_len + pos.len, pos.last, damage));
             };
-            if let Some(header) = opt_header {
+            if let Some((header, len, last, opt_damage)) = opt_result {
                 self.verify_header(header)?;
+                if let Some(damage) = opt_damage {
+                    if recover {
+                        self.recover_log(damage)?;
+                    } else {
+                        debug!("Partition {}: incomplete commit in log {}-{} after {} bytes: {}",
+                                self.name, ss, cl, damage.offset, damage.error);
+                    }
+                }
+                self.log_lens.insert((ss, cl), (len, last));
+            } else {
+                warn!("Partition {}: missing commit log {}-{}", self.name, ss, cl);
+                self.log_lens.remove(&(ss, cl));
             }
         }
+        let commits = queue.len();
         for commit in queue {
             self.add_commit(commit)?;
         }
+        Ok((logs, commits))
+    }
+    
+    // Handle a damaged commit log as directed by the control
+    fn recover_log(&mut self, damage: LogDamage) -> Result<()> {
+        warn!("Partition {}: commit log {}-{} damaged after {} bytes: {}",
+                self.name, damage.ss, damage.cl, damage.offset, damage.error);
+        match self.control.damaged_log(&damage) {
+            LogRecovery::Fail => return Err(damage.error),
+            LogRecovery::Ignore => {},
+            LogRecovery::Truncate => {
+                if !self.control.io_mut().truncate_ss_cl(damage.ss, damage.cl, damage.offset)? {
+                    return OtherError::err("damaged commit log not found when truncating");
+                }
+            },
+        }
         Ok(())
     }
     
@@ -353,6 +764,7 @@ impl<C: Control> Partition<C> {
         }
         
         self.control.read_header(&header)?;
+        self.boundary.extend(header.shallow);
         
         Ok(())
     }
@@ -363,6 +775,7 @@ impl<C: Control> Partition<C> {
             ftype: file_type,
             name: self.name.clone(),
             user: vec![],
+            shallow: self.shallow_boundary(),
         };
         let user_fields = self.control.make_user_data(&header)?;
         header.user = user_fields;
@@ -378,8 +791,11 @@ impl<C: Control> Partition<C> {
         trace!("Unloading partition {} data", self.name);
         if force || self.unsaved.is_empty() {
             self.states.clear();
+            self.history.clear();
+            self.cache.lock().expect("state cache lock").clear();
             self.ancestors.clear();
             self.tips.clear();
+            self.log_lens.clear();
             true
         } else {
             false
@@ -434,49 +850,145 @@ impl<C: Control> Partition<C> {
         &self.tips
     }
     
-    /// Get the number of states.
+    /// Get the number of known states (including those not held in memory).
     /// 
     /// Tips are a subset of states, so `tips_len() <= states_len()`.
     pub fn states_len(&self) -> usize {
-        self.states.len()
+        self.states.len() + self.history.keys().filter(|k| !self.states.contains(k)).count()
     }
     
     /// Iterate over all states which have been loaded (see `load_...` functions).
     /// 
+    /// States not held in memory are rebuilt as they are reached (see
+    /// `state`); iterating over a long history is therefore not cheap.
+    /// 
     /// Items are unordered (actually, they follow the order of an internal
     /// hash map, which is randomised and usually different each time the
     /// program is loaded).
-    pub fn states_iter(&self) -> StateIter<C::Element> {
-        StateIter { iter: self.states.iter(), tips: &self.tips }
+    pub fn states_iter(&self) -> StateIter<C> {
+        let keys: Vec<Sum> = self.states.iter().map(|state| state.statesum())
+                .chain(self.history.keys().filter(|k| !self.states.contains(k)))
+                .cloned().collect();
+        StateIter { part: self, keys: keys.into_iter() }
     }
     
-    /// Get a read-only reference to a state by its statesum, if found.
+    /// Get a state by its statesum, if found.
     /// 
-    /// If you want to keep a copy, clone it.
-    pub fn state(&self, key: &Sum) -> Option<&PartState<C::Element>> {
-        self.states.get(key)
+    /// Tips, states read from snapshots and some other states are held in
+    /// memory; other states are rebuilt by applying commits to one of these
+    /// (see `Control::state_checkpoint_interval`). Recently rebuilt states are
+    /// cached. Returns `None` if the state is not known or could not be
+    /// rebuilt (the latter is logged).
+    pub fn state(&self, key: &Sum) -> Option<Arc<PartState<C::Element>>> {
+        if let Some(state) = self.states.get(key) {
+            return Some(state.clone());
+        }
+        let mut cache = self.cache.lock().expect("state cache lock");
+        if let Some(pos) = cache.iter().position(|state| state.statesum() == key) {
+            let state = cache.remove(pos).unwrap();
+            cache.push_front(state.clone());
+            return Some(state);
+        }
+        
+        // Find the nearest held or cached state along first parents:
+        let mut commits = vec![];
+        let mut k = key;
+        let mut state = loop {
+            if let Some(state) = self.states.get(k) {
+                break state.clone();
+            }
+            if let Some(state) = cache.iter().find(|state| state.statesum() == k) {
+                break state.clone();
+            }
+            let commit = &self.history.get(k)?.0;
+            commits.push(commit);
+            k = commit.first_parent();
+        };
+        trace!("Partition {}: rebuilding state {} from {} commits",
+                self.name, key, commits.len());
+        for commit in commits.into_iter().rev() {
+            state = match PartState::from_state_commit(&state, commit) {
+                Ok(state) => Arc::new(state),
+                Err(e) => {
+                    warn!("Partition {}: failed to rebuild state {}: {}",
+                            self.name, commit.statesum(), e);
+                    return None;
+                }
+            };
+        }
+        cache.push_front(state.clone());
+        cache.truncate(self.control.state_cache_len());
+        Some(state)
     }
     
     /// Try to find a state given a string representation of the key (as a byte array).
     /// 
     /// Like git, we accept partial keys (so long as they uniquely resolve a key).
-    pub fn state_from_string(&self, string: String) -> Result<&PartState<C::Element>, MatchError> {
+    pub fn state_from_string(&self, string: String) -> Result<Arc<PartState<C::Element>>, MatchError> {
         let string = string.to_uppercase().replace(" ", "");
         let mut matching: Option<&Sum> = None;
-        for state in self.states.iter() {
-            if state.statesum().matches_string(string.as_bytes()) {
+        let keys = self.states.iter().map(|state| state.statesum())
+                .chain(self.history.keys().filter(|k| !self.states.contains(k)));
+        for key in keys {
+            if key.matches_string(string.as_bytes()) {
                 if let Some(prev) = matching {
                     return Err(MatchError::MultiMatch(
-                        prev.as_string(false), state.statesum().as_string(false)));
+                        prev.as_string(false), key.as_string(false)));
                 } else {
-                    matching = Some(state.statesum());
+                    matching = Some(key);
                 }
             }
         }
-        if let Some(m) = matching {
-            Ok(self.states.get(m).unwrap())
-        } else {
-            Err(MatchError::NoMatch)
+        matching.and_then(|m| self.state(m)).ok_or(MatchError::NoMatch)
+    }
+    
+    /// True if a state is known, whether or not it is held in memory (see
+    /// `state`).
+    pub fn is_known(&self, key: &Sum) -> bool {
+        self.states.contains(key) || self.history.contains_key(key)
+    }
+    
+    /// Get the parents of a known state, without rebuilding it.
+    pub fn parents_of(&self, key: &Sum) -> Option<&[Sum]> {
+        match self.states.get(key) {
+            Some(state) => Some(state.parents()),
+            None => self.history.get(key).map(|&(ref commit, _)| commit.parents()),
+        }
+    }
+    
+    /// Get the shallow boundary: states whose parents were intentionally not
+    /// copied (see `sync::clone`) and are still missing, sorted. This is
+    /// empty unless history was truncated; it is recorded in the headers of
+    /// files written.
+    /// 
+    /// Merges needing history from beyond this boundary fail with
+    /// `MergeError::HistoryTruncated`.
+    pub fn shallow_boundary(&self) -> Vec<Sum> {
+        let mut states: Vec<Sum> = self.boundary.iter()
+                .filter(|key| self.truncated_at(key))
+                .cloned().collect();
+        states.sort();
+        states
+    }
+    
+    /// Get a commit creating a known state from its first parent.
+    /// 
+    /// For states created from a commit, this is that commit. States read
+    /// from snapshots have none; a commit is made by comparing the state with
+    /// its first parent, if this is known. Returns `None` if the state is not
+    /// known, has no parents or its first parent is not known, and fails with
+    /// `ElementOp::LoadFailed` if elements to compare cannot be loaded.
+    pub fn commit(&self, key: &Sum) -> Result<Option<Commit<C::Element>>, ElementOp> {
+        if let Some(&(ref commit, _)) = self.history.get(key) {
+            return Ok(Some(commit.clone()));
+        }
+        let state = match self.states.get(key) {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        match state.parents().first().and_then(|parent| self.state(parent)) {
+            Some(parent) => Ok(Some(snapshot_commit(&parent, state)?)),
+            None => Ok(None),
         }
     }
     
@@ -527,7 +1039,8 @@ impl<C: Control> Partition<C> {
             trace!("Partition {}: attempting merge of tips {} and {}", self.name, &tip1, &tip2);
             let c = match self.merge_two(&tip1, &tip2) {
                 Ok(merge) => merge.solve_inline(solver).make_commit(self.control.as_mcm_ref()),
-                Err(MergeError::NoCommonAncestor) if auto_load && self.ss0 > 0 => {
+                Err(MergeError::NoCommonAncestor) |
+                Err(MergeError::HistoryTruncated) if auto_load && self.ss0 > 0 => {
                     // Iteratively load previous history and retry until success or error.
                     start_ss = self.ss0 - 1;
                     continue;
@@ -562,6 +1075,9 @@ impl<C: Control> Partition<C> {
     /// Note that this function can fail with `MergeError::NoCommonAncestor` if not enough history
     /// is available. In this case you might try calling `part.load_all()?;` or
     /// `let ss0 = part.oldest_ss_loaded(); part.load_range(ss0 - 1, ss0);`, then retrying.
+    /// It fails with `MergeError::HistoryTruncated` instead where the search
+    /// reached the partition's shallow boundary (see `shallow_boundary`);
+    /// loading more history can then only help if not everything is loaded.
     pub fn merge_two(&self, tip1: &Sum, tip2: &Sum) -> Result<TwoWayMerge<C::Element>, MergeError> {
         let common = match self.latest_common_ancestor(tip1, tip2) {
             Ok(sum) => sum,
@@ -569,8 +1085,8 @@ impl<C: Control> Partition<C> {
         };
         let s1 = self.states.get(tip1).ok_or(MergeError::NoState)?;
         let s2 = self.states.get(tip2).ok_or(MergeError::NoState)?;
-        let s3 = self.states.get(&common).ok_or(MergeError::NoState)?;
-        Ok(TwoWayMerge::new(s1, s2, s3))
+        let s3 = self.state(&common).ok_or(MergeError::NoState)?;
+        Ok(TwoWayMerge::new(s1, s2, &s3))
     }
     
     // #0003: allow getting a reference to other states listing snapshots,
@@ -591,16 +1107,17 @@ impl<C: Control> Partition<C> {
     /// already known state.
     pub fn push_commit(&mut self, commit: Commit<C::Element>) -> Result<bool, PatchOp> {
         let state = {
-            let parent = self.states.get(commit.first_parent())
+            let parent = self.state(commit.first_parent())
                 .ok_or(PatchOp::NoParent)?;
-            PartState::from_state_commit(parent, &commit)?
-        };  // end borrow on self (from parent)
+            PartState::from_state_commit(&parent, &commit)?
+        };
         Ok(self.add_pair(commit, state))
     }
     
     /// Add a new state, assumed to be derived from an existing known state.
     /// 
-    /// This creates a commit from the given state, converts the `MutPartState`
+    /// This creates a commit from the changes recorded by the given state
+    /// (see `MutPartState::changes`), converts the `MutPartState`
     /// to a `PartState` and adds it to the list of internal states, and
     /// updates the tip. The commit is added to the internal list
     /// waiting to be written to permanent storage (see `write()`).
@@ -612,20 +1129,81 @@ impl<C: Control> Partition<C> {
     /// parent (i.e. hasn't been changed) or another already known state.
     pub fn push_state(&mut self, state: MutPartState<C::Element>) -> Result<bool, PatchOp> {
         let parent_sum = state.parent().clone();
+        if !self.is_known(&parent_sum) {
+            return Err(PatchOp::NoParent);
+        }
+        let changes = state.changes();
+        if changes.is_empty() {
+            return Ok(false);
+        }
         let new_state = PartState::from_mut(state, self.control.as_mcm_ref_mut());
+        let commit = Commit::new_explicit(new_state.statesum().clone(), vec![parent_sum],
+                changes, new_state.meta().clone());
+        Ok(self.add_pair(commit, new_state))
+    }
+    
+    /// Start a transaction on the current tip (see `commit_txn`).
+    /// 
+    /// Fails when `tip()` fails.
+    pub fn begin(&self) -> result::Result<Transaction<C::Element>, TipError> {
+        Ok(Transaction::new(self.tip()?.clone_mut()))
+    }
+    
+    /// Commit a transaction (see `begin`).
+    /// 
+    /// If the tip is still the state the transaction started from, this is
+    /// equivalent to `push_state`. Otherwise, the changes made since are
+    /// compared with the elements the transaction read and wrote: if these
+    /// are disjoint, the transaction's writes are re-applied on top of the
+    /// current tip; if not, this fails with `TxnError::Conflict` and nothing
+    /// is changed.
+    /// 
+    /// Returns `Ok(true)` if a new state was added, `Ok(false)` if the result
+    /// matches an already known state (e.g. nothing was changed).
+    pub fn commit_txn(&mut self, txn: Transaction<C::Element>) ->
+            result::Result<bool, TxnError>
+    {
+        let tip_key = self.tip_key()?.clone();
+        if *txn.base() == tip_key {
+            let (state, _, _, _) = txn.into_parts();
+            return Ok(self.push_state(state)?);
+        }
         
-        // #0019: Commit::from_diff compares old and new states and code be slow.
-        // #0019: Instead, we could record each alteration as it happens.
-        Ok(if let Some(commit) =
-                Commit::from_diff(
-                    self.states.get(&parent_sum).ok_or(PatchOp::NoParent)?,
-                    &new_state)
-            {
-                self.add_pair(commit, new_state)
-            } else {
-                false
+        let (state, read, read_all, written) = txn.into_parts();
+        let rebased = {
+            let base = self.state(state.parent()).ok_or(TxnError::NoBase)?;
+            let tip = self.states.get(&tip_key).unwrap();
+            if let Some(commit) = Commit::from_diff(&base, tip)? {
+                let mut conflicts: Vec<_> = commit.changes_iter()
+                    .map(|(id, _)| *id)
+                    .filter(|id| read_all || read.contains(id) || written.contains(id))
+                    .collect();
+                if !conflicts.is_empty() {
+                    conflicts.sort();
+                    return Err(TxnError::Conflict(conflicts));
+                }
+            }
+            
+            let mut rebased = tip.clone_mut();
+            for id in written {
+                if state.is_avail(id) {
+                    let elt = state.get_rc(id)?.clone();
+                    let sum = state.get_sum(id)?.clone();
+                    if rebased.is_avail(id) {
+                        rebased.set_rc_sum(id, elt, sum)?;
+                    } else {
+                        rebased.insert_rc_sum(id, elt, sum)?;
+                    }
+                } else if rebased.is_avail(id) {
+                    rebased.discard(id)?;
+                }
             }
-        )
+            *rebased.meta_mut().ext_flags_mut() = state.meta().ext_flags();
+            rebased
+        };
+        trace!("Partition {}: rebased transaction from {} onto tip {}",
+                self.name, state.parent(), tip_key);
+        Ok(self.push_state(rebased)?)
     }
     
     /// The number of commits waiting to be written to permanent storage by
@@ -643,6 +1221,12 @@ impl<C: Control> Partition<C> {
     /// This will write all unsaved commits to a log on the disk. Does nothing
     /// if there are no queued changes.
     /// 
+    /// Commits are appended to the log last written by this partition where
+    /// possible; a new log is started after a new snapshot, when the limits
+    /// given by `Control::log_rollover()` would be exceeded, or when no log
+    /// has yet been written by this partition. Each commit is written with a
+    /// single write operation.
+    /// 
     /// Also see `write_full()`.
     /// 
     /// Returns true if any commits were written (i.e. unsaved commits
@@ -656,36 +1240,57 @@ impl<C: Control> Partition<C> {
             return Ok(false);
         }
         
-        let header = self.make_header(FileType::CommitLog(0))?;
+        let rollover = self.control.log_rollover();
+        let ss = self.ss1 - 1;
+        let mut buf = Vec::new();
+        write_commit(self.unsaved.front().unwrap(), &mut buf)?;
         
-        // #0012: extend existing logs instead of always writing a new log file.
-        let mut cl_num = self.control.io().ss_cl_len(self.ss1 - 1);
-        debug!("Partition {}: writing {} commits to log {}-{}",
-                self.name, self.unsaved.len(), self.ss1-1, cl_num);
-        loop {
-            if let Some(mut writer) = self.control.io_mut().new_ss_cl(self.ss1 - 1, cl_num)? {
-                // Write a header since this is a new file:
-                write_head(&header, &mut writer)?;
-                start_log(&mut writer)?;
-                
-                // Now write commits:
-                while !self.unsaved.is_empty() {
-                    // We try to write the commit, then when successful remove it
-                    // from the list of 'unsaved' commits.
-                    write_commit(self.unsaved.front().unwrap(), &mut writer)?;
-                    self.unsaved.pop_front().expect("pop_front");
+        while !self.unsaved.is_empty() {
+            // Forget the current log while writing, so that we never append
+            // after a failed write.
+            if let Some(mut log) = self.log.take() {
+                if log.ss == ss && log.fits(&rollover, buf.len()) {
+                    if let Some(mut writer) = self.control.io_mut().append_ss_cl(log.ss, log.cl)? {
+                        trace!("Partition {}: appending to log {}-{}", self.name, log.ss, log.cl);
+                        let n = write_commits(&mut writer, &self.unsaved, &mut log, &rollover, &mut buf)?;
+                        self.unsaved.drain(..n);
+                        self.log_lens.insert((log.ss, log.cl), (log.bytes, log.last.clone()));
+                        self.log = Some(log);
+                        continue;
+                    }
                 }
-                
-                return Ok(true);
-            } else {
-                // Log file already exists! So try another number.
-                if cl_num > 1000_000 {
-                    // We should give up eventually. When is arbitrary.
-                    return Err(Box::new(OtherError::new("Commit log number too high")));
+            }
+            
+            let header = self.make_header(FileType::CommitLog(0))?;
+            let mut head = Vec::new();
+            write_head(&header, &mut head)?;
+            start_log(&mut head)?;
+            
+            let mut cl_num = self.control.io().ss_cl_len(ss);
+            loop {
+                if let Some(mut writer) = self.control.io_mut().new_ss_cl(ss, cl_num)? {
+                    debug!("Partition {}: writing to new log {}-{}", self.name, ss, cl_num);
+                    // Write a header since this is a new file:
+                    writer.write_all(&head)?;
+                    let mut log = CurrentLog { ss: ss, cl: cl_num, commits: 0, bytes: head.len(), last: None };
+                    let n = write_commits(&mut writer, &self.unsaved, &mut log, &rollover, &mut buf)?;
+                    // The new log may not be visible until flushed:
+                    writer.flush()?;
+                    self.unsaved.drain(..n);
+                    self.log_lens.insert((log.ss, log.cl), (log.bytes, log.last.clone()));
+                    self.log = Some(log);
+                    break;
+                } else {
+                    // Log file already exists! So try another number.
+                    if cl_num > 1000_000 {
+                        // We should give up eventually. When is arbitrary.
+                        return Err(Box::new(OtherError::new("Commit log number too high")));
+                    }
+                    cl_num += 1;
                 }
-                cl_num += 1;
             }
         }
+        Ok(true)
     }
     
     /// This will write all unsaved commits to a log on the disk, then write a
@@ -729,6 +1334,7 @@ impl<C: Control> Partition<C> {
                 
                 write_head(&header, &mut writer)?;
                 write_snapshot(self.states.get(&tip_key).unwrap(), &mut writer)?;
+                writer.flush()?;
             } else {
                 // Snapshot file already exists! So try another number.
                 if ss_num > 1000_000 {
@@ -747,6 +1353,349 @@ impl<C: Control> Partition<C> {
     }
 }
 
+// Read the header of snapshot `ss` and, if `read_data`, its state. Returns
+// `None` if not found.
+// 
+// Snapshots are read from a memory map where the I/O provider supports this.
+// Elements are loaded on demand if the control asks for this and the file and
+// I/O provider support it.
+fn read_ss_file<C: Control>(control: &C, ss: usize, read_data: bool) ->
+        Result<Option<(FileHeader, Option<PartState<C::Element>>)>>
+{
+    if read_data {
+        if let Some(map) = control.io().map_ss(ss)? {
+            let data = (*map).as_ref();
+            let mut r = data;
+            let head = read_head(&mut r)?;
+            let ver = head.ftype.ver();
+            let state = match lazy_source(control, ss, ver)? {
+                Some(source) => {
+                    let base = (data.len() - r.len()) as u64;
+                    read_snapshot_lazy(&mut r, ver, source, base)?
+                },
+                None => read_snapshot_slice(r, ver)?,
+            };
+            return Ok(Some((head, Some(state))));
+        }
+    }
+    
+    let mut r = match control.io().read_ss(ss)? {
+        Some(r) => CountingReader::new(r),
+        None => return Ok(None),
+    };
+    let head = read_head(&mut r)?;
+    let state = if read_data {
+        let ver = head.ftype.ver();
+        Some(match lazy_source(control, ss, ver)? {
+            Some(source) => {
+                let base = r.count() as u64;
+                read_snapshot_lazy(&mut r, ver, source, base)?
+            },
+            None => control.read_snapshot(&mut r, ver)?,
+        })
+    } else {
+        None
+    };
+    Ok(Some((head, state)))
+}
+
+// Make a commit creating `state` (e.g. a state read from a snapshot) from its
+// first parent
+fn snapshot_commit<E: Element>(parent: &PartState<E>, state: &PartState<E>) ->
+        Result<Commit<E>, ElementOp>
+{
+    let changes = Commit::from_diff(parent, state)?
+            .map_or(HashMap::new(), |commit| commit.changes_iter()
+                .map(|(id, change)| (*id, change.clone())).collect());
+    Ok(Commit::new_explicit(state.statesum().clone(), state.parents().to_vec(),
+            changes, state.meta().clone()))
+}
+
+// Get a source for on-demand loading of elements, if enabled and supported
+fn lazy_source<C: Control>(control: &C, ss: usize, format_ver: u32) -> Result<Option<Arc<EltSource>>> {
+    if control.lazy_elements() && has_index(format_ver) {
+        control.io().ss_source(ss)
+    } else {
+        Ok(None)
+    }
+}
+
+// Write commits from the front of `unsaved`, each via a single write
+// operation, until done or the log is full. On entry `buf` must hold the
+// first commit, serialised; on exit it holds the next unwritten commit, if any.
+// 
+// Returns the number of commits written. These are not removed from `unsaved`
+// (on failure, some commits may therefore be written twice; this is harmless).
+fn write_commits<E: Element>(writer: &mut Write, unsaved: &VecDeque<Commit<E>>,
+        log: &mut CurrentLog, rollover: &LogRollover, buf: &mut Vec<u8>) -> Result<usize>
+{
+    let mut n = 0;
+    loop {
+        writer.write_all(buf)?;
+        n += 1;
+        log.commits += 1;
+        log.bytes += buf.len();
+        // A commit ends with its checksum:
+        log.last = Some(Sum::load(&buf[buf.len() - SUM_BYTES..]));
+        
+        buf.clear();
+        if let Some(commit) = unsaved.get(n) {
+            write_commit(commit, buf)?;
+        } else {
+            return Ok(n);
+        }
+        if !log.fits(rollover, buf.len()) {
+            return Ok(n);
+        }
+    }
+}
+
+// Maintenance operations
+impl<C: Control> Partition<C> {
+    /// Delete old snapshots and commit logs from the `RepoIO`, keeping the
+    /// history selected by `keep` (see `Keep`).
+    /// 
+    /// All snapshots and logs on disk are read to find which states can be
+    /// rebuilt from the files which would remain. Files are only removed if
+    /// all tips (and any states required by `keep`) can still be rebuilt,
+    /// taking unsaved commits into account; where `keep` would remove too
+    /// much, less is removed. Whole snapshots are removed together with their
+    /// logs and all older files.
+    /// 
+    /// Elements of states in memory which are still read on demand (see
+    /// `Control::lazy_elements`) are loaded before any file is removed; if
+    /// this fails, nothing is removed. Snapshots are otherwise only read up
+    /// to their element index, where the file version has one.
+    /// 
+    /// States in memory are not affected; `Pruned::lost` lists states which
+    /// can no longer be loaded from disk and thus will not be available as
+    /// common ancestors when merging after the partition is reloaded.
+    /// 
+    /// Requires that the partition is loaded (see `is_loaded`).
+    pub fn prune(&mut self, keep: Keep) -> Result<Pruned> {
+        if !self.is_loaded() {
+            return Err(Box::new(TipError::NotReady));
+        }
+        self.control.io_mut().rescan()?;
+        let ss_len = self.control.io().ss_len();
+        
+        // Read history from disk: (ss, statesum, timestamp) of each snapshot
+        // and (ss, first parent, statesum) of each commit.
+        let mut snapshots = Vec::new();
+        let mut commits = Vec::new();
+        for ss in 0..ss_len {
+            let opt_result = if let Some(mut r) = self.control.io().read_ss(ss)? {
+                let head = read_head(&mut r)?;
+                let (sum, meta) = read_snapshot_summary(&mut r, head.ftype.ver())?;
+                Some((head, sum, meta.timestamp()))
+            } else {
+                None
+            };
+            if let Some((header, sum, timestamp)) = opt_result {
+                self.verify_header(header)?;
+                snapshots.push((ss, sum, timestamp));
+            }
+            
+            for cl in 0..self.control.io().ss_cl_len(ss) {
+                let mut queue: Vec<Commit<C::Element>> = vec![];
+                if let Some(mut r) = self.control.io().read_ss_cl(ss, cl)? {
+                    let header = read_head(&mut r)?;
+                    // A damaged log only means less can be rebuilt
+                    read_log_recover(&mut r, &mut queue, header.ftype.ver(), LogPos::default())?;
+                }
+                for commit in queue {
+                    commits.push((ss, commit.first_parent().clone(), commit.statesum().clone()));
+                }
+            }
+        }
+        let mut pruned = Pruned::default();
+        if snapshots.is_empty() {
+            return Ok(pruned);
+        }
+        
+        // States which could be rebuilt from snapshot ss0 and later files:
+        let unsaved = &self.unsaved;
+        let rebuildable = |ss0: usize| -> HashSet<Sum> {
+            let mut avail: HashSet<Sum> = snapshots.iter()
+                    .filter(|s| s.0 >= ss0).map(|s| s.1.clone()).collect();
+            let pending: Vec<(&Sum, &Sum)> = commits.iter()
+                    .filter(|c| c.0 >= ss0).map(|c| (&c.1, &c.2))
+                    .chain(unsaved.iter().map(|c| (c.first_parent(), c.statesum())))
+                    .collect();
+            loop {
+                let len = avail.len();
+                for &(parent, sum) in &pending {
+                    if avail.contains(parent) {
+                        avail.insert(sum.clone());
+                    }
+                }
+                if avail.len() == len { return avail; }
+            }
+        };
+        
+        let mut required: HashSet<Sum> = self.tips.clone();
+        let mut i = match keep {
+            Keep::Snapshots(n) => snapshots.len() - min(max(n, 1), snapshots.len()),
+            Keep::Since(time) => snapshots.iter().rposition(|s| s.2 <= time).unwrap_or(0),
+            Keep::States(states) => {
+                required.extend(states);
+                snapshots.len() - 1
+            },
+        };
+        let mut avail = rebuildable(snapshots[i].0);
+        while i > 0 && !required.is_subset(&avail) {
+            i -= 1;
+            avail = rebuildable(snapshots[i].0);
+        }
+        pruned.ss0 = snapshots[i].0;
+        if i == 0 {
+            debug!("Partition {}: nothing to prune", self.name);
+            return Ok(pruned);
+        }
+        pruned.lost = rebuildable(snapshots[0].0).difference(&avail).cloned().collect();
+        
+        // States in memory may still read elements from the snapshots to be
+        // removed; load these first (refusing to prune on failure).
+        for state in self.states.iter().chain(self.cache.lock().expect("state cache lock").iter()) {
+            if state.num_loaded() < state.num_avail() {
+                state.load_all()?;
+            }
+        }
+        
+        info!("Partition {}: pruning history before snapshot {}", self.name, pruned.ss0);
+        for ss in 0..pruned.ss0 {
+            for cl in 0..self.control.io().ss_cl_len(ss) {
+                if self.control.io_mut().remove_ss_cl(ss, cl)? {
+                    pruned.logs.push((ss, cl));
+                }
+                self.log_lens.remove(&(ss, cl));
+            }
+            if self.control.io_mut().remove_ss(ss)? {
+                pruned.snapshots.push(ss);
+            }
+        }
+        
+        self.ss0 = min(max(self.ss0, pruned.ss0), self.ss1);
+        if self.log.as_ref().map_or(false, |log| log.ss < pruned.ss0) {
+            self.log = None;
+        }
+        Ok(pruned)
+    }
+    
+    /// Rewrite all commit logs of snapshot `ss` into a single new log,
+    /// omitting duplicate commits, then remove the old logs.
+    /// 
+    /// The new log is written under a new log number and verified by reading
+    /// it back before any old log is removed. It is then copied to log number
+    /// zero (likewise verified before the new log is removed), so that no
+    /// gaps are left in the log numbers. A crash at any point therefore
+    /// leaves either the old logs, or one or two copies of the new log plus
+    /// some old logs (which only duplicate commits in the new log).
+    /// 
+    /// Commits appended to the old logs by another process while compacting
+    /// would be lost; use a lock (e.g. `RepoFileIO::lock`) to prevent this.
+    /// 
+    /// Returns `None` if the snapshot has fewer than two logs; otherwise
+    /// a summary of the compaction.
+    pub fn compact_logs(&mut self, ss: usize) -> Result<Option<Compacted>> {
+        let cl_len = self.control.io().ss_cl_len(ss);
+        let mut logs = vec![];
+        let mut commits: Vec<Commit<C::Element>> = vec![];
+        let mut seen = HashSet::new();
+        let mut n_read = 0;
+        for cl in 0..cl_len {
+            let mut queue = vec![];
+            let opt_header = if let Some(mut r) = self.control.io().read_ss_cl(ss, cl)? {
+                let header = read_head(&mut r)?;
+                read_log(&mut r, &mut queue, header.ftype.ver())?;
+                Some(header)
+            } else {
+                None
+            };
+            if let Some(header) = opt_header {
+                self.verify_header(header)?;
+                logs.push(cl);
+                n_read += queue.len();
+                for commit in queue {
+                    if seen.insert(commit.statesum().clone()) {
+                        commits.push(commit);
+                    }
+                }
+            }
+        }
+        if logs.len() < 2 {
+            return Ok(None);
+        }
+        
+        let header = self.make_header(FileType::CommitLog(0))?;
+        let mut buf = Vec::new();
+        write_head(&header, &mut buf)?;
+        start_log(&mut buf)?;
+        for commit in &commits {
+            write_commit(commit, &mut buf)?;
+        }
+        
+        let mut cl_num = cl_len;
+        while !self.write_log_verified(ss, cl_num, &buf, &commits)? {
+            if cl_num > 1000_000 {
+                return Err(Box::new(OtherError::new("Commit log number too high")));
+            }
+            cl_num += 1;
+        }
+        debug!("Partition {}: compacted {} logs of snapshot {} into log {}-{}",
+                self.name, logs.len(), ss, ss, cl_num);
+        
+        for &cl in &logs {
+            self.control.io_mut().remove_ss_cl(ss, cl)?;
+            self.log_lens.remove(&(ss, cl));
+        }
+        if self.write_log_verified(ss, 0, &buf, &commits)? {
+            self.control.io_mut().remove_ss_cl(ss, cl_num)?;
+            cl_num = 0;
+        }
+        // A commit ends with its checksum:
+        let last = commits.last().map(|_| Sum::load(&buf[buf.len() - SUM_BYTES..]));
+        self.log_lens.insert((ss, cl_num), (buf.len(), last));
+        if self.log.as_ref().map_or(false, |log| log.ss == ss) {
+            self.log = None;
+        }
+        
+        Ok(Some(Compacted {
+            cl: cl_num,
+            logs: logs.len(),
+            commits: commits.len(),
+            duplicates: n_read - commits.len(),
+        }))
+    }
+    
+    // Write `buf` as a new log `ss`-`cl`, then verify that reading it yields
+    // `commits` (removing it and failing otherwise). Returns false if the log
+    // already exists.
+    fn write_log_verified(&mut self, ss: usize, cl: usize, buf: &[u8],
+            commits: &[Commit<C::Element>]) -> Result<bool>
+    {
+        if let Some(mut writer) = self.control.io_mut().new_ss_cl(ss, cl)? {
+            writer.write_all(buf)?;
+            writer.flush()?;
+        } else {
+            return Ok(false);
+        }
+        
+        let mut reread: Vec<Commit<C::Element>> = vec![];
+        if let Some(mut r) = self.control.io().read_ss_cl(ss, cl)? {
+            let header = read_head(&mut r)?;
+            read_log(&mut r, &mut reread, header.ftype.ver())?;
+        }
+        if reread.len() != commits.len() ||
+            reread.iter().zip(commits.iter()).any(|(a, b)| a.statesum() != b.statesum())
+        {
+            self.control.io_mut().remove_ss_cl(ss, cl)?;
+            return OtherError::err("verification of compacted commit log failed");
+        }
+        Ok(true)
+    }
+}
+
 // Internal support functions
 impl<C: Control> Partition<C> {
     // Take self and two sums. Return a copy of a key to avoid lifetime issues.
@@ -754,14 +1703,17 @@ impl<C: Control> Partition<C> {
         // #0019: there are multiple strategies here; we just find all
         // ancestors of one, then of the other. This simplifies lopic.
         let mut a1 = HashSet::new();
+        // True if either search hit the shallow boundary
+        let mut truncated = false;
         
         let mut next = VecDeque::new();
         next.push_back(k1);
         while let Some(k) = next.pop_back() {
             if a1.contains(k) { continue; }
             a1.insert(k);
-            if let Some(state) = self.states.get(k) {
-                for p in state.parents() {
+            truncated |= self.truncated_at(k);
+            if let Some(parents) = self.parents_of(k) {
+                for p in parents {
                     next.push_back(p);
                 }
             }
@@ -775,17 +1727,29 @@ impl<C: Control> Partition<C> {
         while let Some(k) = next.pop_back() {
             if a2.contains(k) { continue; }
             a2.insert(k);
-            if a1.contains(k) {
+            // Only a known state is any use as a common ancestor:
+            if a1.contains(k) && self.is_known(k) {
                 return Ok(k.clone());
             }
-            if let Some(state) = self.states.get(k) {
-                for p in state.parents() {
+            truncated |= self.truncated_at(k);
+            if let Some(parents) = self.parents_of(k) {
+                for p in parents {
                     next.push_back(p);
                 }
             }
         }
         
-        Err(MergeError::NoCommonAncestor)
+        if truncated {
+            Err(MergeError::HistoryTruncated)
+        } else {
+            Err(MergeError::NoCommonAncestor)
+        }
+    }
+    
+    // True if `key` is on the shallow boundary and some parent is missing
+    fn truncated_at(&self, key: &Sum) -> bool {
+        self.boundary.contains(key) && self.parents_of(key)
+                .map_or(true, |parents| parents.iter().any(|p| !self.is_known(p)))
     }
     
     /// Add a state, assuming that this isn't a new one (i.e. it's been loaded
@@ -794,15 +1758,13 @@ impl<C: Control> Partition<C> {
     /// Unlike add_pair, mutating isn't possible, so this just returns false
     /// if the sum is known without checking whether the state is different.
     /// 
-    /// `n_edits` is the number of changes in a commit. Normally the state is
-    /// created from a commit, and `n_edits = commit.num_changes()`; in the
-    /// case the state is a loaded snapshot the "snapshot policy" should be
-    /// reset afterwards, hence n_edits does not matter.
-    /// 
-    /// Returns true unless the given state's sum equals an existing one.
-    fn add_state(&mut self, state: PartState<C::Element>, n_edits: usize) {
+    /// `commit` is the commit creating this state, if any; it is kept so
+    /// that the state can be rebuilt if dropped from memory. Its number of
+    /// changes is counted by the "snapshot policy"; in the case the state is
+    /// a loaded snapshot the policy should be reset afterwards.
+    fn add_state(&mut self, state: PartState<C::Element>, commit: Option<Commit<C::Element>>) {
         trace!("Partition {}: add state {}", self.name, state.statesum());
-        if self.states.contains(state.statesum()) {
+        if self.is_known(state.statesum()) {
             trace!("Partition {} already contains state {}", self.name, state.statesum());
             return;
         }
@@ -810,35 +1772,106 @@ impl<C: Control> Partition<C> {
         for parent in state.parents() {
             // Remove from 'tips' if it happened to be there:
             self.tips.remove(parent);
-            // Add to 'ancestors' if not in 'states':
-            if !self.states.contains(parent) {
+            // Add to 'ancestors' if not known:
+            if !self.is_known(parent) {
                 self.ancestors.insert(parent.clone());
             }
         }
-        // We know from above 'state' is not in 'self.states'; if it's not in
+        // We know from above 'state' is not known; if it's not in
         // 'self.ancestors' either then it must be a tip:
         if !self.ancestors.contains(state.statesum()) {
+            let n_edits = commit.as_ref().map_or(0, |c| c.num_changes());
             self.control.snapshot_policy().count(1, n_edits);
             self.tips.insert(state.statesum().clone());
         }
+        
+        // Parents which are no longer tips need not be held in memory, except
+        // at checkpoints:
+        let interval = max(1, self.control.state_checkpoint_interval());
+        for parent in state.parents() {
+            let release = !self.tips.contains(parent) &&
+                    self.history.get(parent).map_or(false, |&(_, depth)| depth % interval != 0);
+            if release {
+                self.states.remove(parent);
+            }
+        }
+        if let Some(commit) = commit {
+            let depth = self.history.get(commit.first_parent()).map_or(0, |&(_, depth)| depth);
+            self.history.insert(state.statesum().clone(), (commit, depth + 1));
+        }
         // TODO: check that classification in state equals that of this partition?
-        self.states.insert(state);
+        self.states.insert(Arc::new(state));
     }
     
     /// Creates a state from the commit and adds to self. Updates tip if this
     /// state is new.
     pub fn add_commit(&mut self, commit: Commit<C::Element>) -> Result<(), PatchOp> {
-        if self.states.contains(commit.statesum()) { return Ok(()); }
+        if self.is_known(commit.statesum()) { return Ok(()); }
         
         let state = {
-            let parent = self.states.get(commit.first_parent())
+            let parent = self.state(commit.first_parent())
                 .ok_or(PatchOp::NoParent)?;
-            PartState::from_state_commit(parent, &commit)?
-        };  // end borrow on self (from parent)
-        self.add_state(state, commit.num_changes());
+            PartState::from_state_commit(&parent, &commit)?
+        };
+        self.add_state(state, Some(commit));
         Ok(())
     }
     
+    /// Add commits from another source (e.g. another replica), in any order.
+    /// 
+    /// Each commit not already known is added as by `add_commit` and queued
+    /// to be written by `write_fast`. Commits whose parent is not known (even
+    /// after adding the others) are skipped with a warning. Returns the
+    /// number of commits added.
+    /// 
+    /// All commits are applied before any is added: if one fails to apply,
+    /// the error is returned and none are added.
+    pub fn import_commits(&mut self, mut commits: Vec<Commit<C::Element>>) ->
+            result::Result<usize, PatchOp>
+    {
+        // Commits may depend on commits later in the list; retry until no
+        // more can be applied:
+        let mut new_states = HashMap::new();
+        let mut applied = vec![];
+        loop {
+            let mut skipped = vec![];
+            let n = commits.len();
+            for commit in commits {
+                if self.is_known(commit.statesum()) || new_states.contains_key(commit.statesum()) {
+                    continue;
+                }
+                let state = if let Some(parent) = new_states.get(commit.first_parent()) {
+                    PartState::from_state_commit(parent, &commit)?
+                } else if self.is_known(commit.first_parent()) {
+                    let parent = self.state(commit.first_parent()).ok_or(PatchOp::NoParent)?;
+                    PartState::from_state_commit(&parent, &commit)?
+                } else {
+                    skipped.push(commit);
+                    continue;
+                };
+                new_states.insert(commit.statesum().clone(), state);
+                applied.push(commit);
+            }
+            commits = skipped;
+            if commits.is_empty() || commits.len() == n {
+                break;
+            }
+        }
+        if !commits.is_empty() {
+            warn!("Partition {}: skipped {} imported commits with unknown parent",
+                    self.name, commits.len());
+        }
+        
+        // Parents were applied before their children:
+        let imported = applied.len();
+        for commit in applied {
+            let state = new_states.remove(commit.statesum()).expect("applied state");
+            self.add_state(state, Some(commit.clone()));
+            self.unsaved.push_back(commit);
+        }
+        Ok(imported)
+    }
+    
     /// Add a paired commit and state, asserting that the checksums match and
     /// the parent state is present. Also add to the queue awaiting `write()`.
     /// 
@@ -852,9 +1885,9 @@ impl<C: Control> Partition<C> {
         trace!("Partition {}: add commit {}", self.name, commit.statesum());
         assert_eq!(commit.parents(), state.parents());
         assert_eq!(commit.statesum(), state.statesum());
-        assert!(self.states.contains(commit.first_parent()));
+        assert!(self.is_known(commit.first_parent()));
         
-        while let Some(old_state) = self.states.get(state.statesum()) {
+        while let Some(old_state) = self.state(state.statesum()) {
             if state == *old_state {
                 trace!("Partition {} already contains commit {}", self.name, commit.statesum());
                 return false;
@@ -864,7 +1897,7 @@ impl<C: Control> Partition<C> {
             }
         }
         
-        self.add_state(state, commit.num_changes());
+        self.add_state(state, Some(commit.clone()));
         self.unsaved.push_back(commit);
         true
     }
@@ -894,7 +1927,7 @@ impl<'a> ExactSizeIterator for TipIter<'a> {
 
 /// Wrapper around a `PartState<E>`. Dereferences to this type.
 pub struct StateItem<'a, E: Element+'a> {
-    state: &'a PartState<E>,
+    state: Arc<PartState<E>>,
     tips: &'a HashSet<Sum>,
 }
 impl<'a, E: Element+'a> StateItem<'a, E> {
@@ -910,26 +1943,28 @@ impl<'a, E: Element+'a> StateItem<'a, E> {
 impl<'a, E: Element+'a> Deref for StateItem<'a, E> {
     type Target = PartState<E>;
     fn deref(&self) -> &Self::Target {
-        self.state
+        &self.state
     }
 }
 
 /// Iterator over a partition's (historical or current) states
-pub struct StateIter<'a, E: Element+'a> {
-    iter: Iter<'a, PartState<E>, Sum, PartStateSumComparator>,
-    tips: &'a HashSet<Sum>,
+pub struct StateIter<'a, C: Control+'a> {
+    part: &'a Partition<C>,
+    keys: vec::IntoIter<Sum>,
 }
-impl<'a, E: Element+'a> Iterator for StateIter<'a, E> {
-    type Item = StateItem<'a, E>;
+impl<'a, C: Control+'a> Iterator for StateIter<'a, C> {
+    type Item = StateItem<'a, C::Element>;
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|item|
-            StateItem {
-                state: item,
-                tips: self.tips,
+        // Rebuilding fails only if data in memory is inconsistent; such
+        // states are skipped (a warning is logged)
+        while let Some(key) = self.keys.next() {
+            if let Some(state) = self.part.state(&key) {
+                return Some(StateItem { state: state, tips: &self.part.tips });
             }
-        )
+        }
+        None
     }
-    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+    fn size_hint(&self) -> (usize, Option<usize>) { (0, Some(self.keys.len())) }
 }
 
 
@@ -938,13 +1973,39 @@ mod tests {
     use super::*;
     use elt::EltId;
     use commit::{Commit, MakeCommitMeta};
-    use control::DefaultControl;
-    use io::DummyRepoIO;
+    use control::{DefaultControl, LogRollover};
+    use merge::AncestorSolver2W;
+    use io::{DummyRepoIO, RepoIO};
+    use io::mem::MemRepoIO;
     use state::*;
     
     struct MCM;
     impl MakeCommitMeta for MCM {}
     
+    // Insert element `i` on the tip, commit and write
+    fn push<C: Control<Element = String>>(part: &mut Partition<C>, i: u64) {
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert(EltId::from(i), format!("element {}", i)).expect("insert");
+        part.push_state(state).expect("push");
+        part.write_full().expect("write");
+    }
+    
+    // Copy all files of a partition to a new MemRepoIO
+    fn copy_replica(io: &MemRepoIO) -> MemRepoIO {
+        let mut copy = MemRepoIO::new();
+        for ss in 0..io.ss_len() {
+            if let Some(data) = io.ss_data(ss) {
+                copy.new_ss(ss).unwrap().unwrap().write_all(&data).unwrap();
+            }
+            for cl in 0..io.ss_cl_len(ss) {
+                if let Some(data) = io.cl_data(ss, cl) {
+                    copy.new_ss_cl(ss, cl).unwrap().unwrap().write_all(&data).unwrap();
+                }
+            }
+        }
+        copy
+    }
+    
     #[test]
     fn commit_creation_and_replay(){
         let mut queue = vec![];
@@ -964,7 +2025,7 @@ mod tests {
         insert(&mut state, 4, "four").unwrap();
         insert(&mut state, 5, "five").unwrap();
         let state_b = PartState::from_mut(state, &mut mcm);
-        let commit = Commit::from_diff(&state_a, &state_b).unwrap();
+        let commit = Commit::from_diff(&state_a, &state_b).unwrap().unwrap();
         queue.push(commit);
         
         let mut state = state_b.clone_mut();
@@ -973,19 +2034,19 @@ mod tests {
         state.remove(EltId::from(4)).unwrap();
         state.replace(EltId::from(3), "half six".to_string()).unwrap();
         let state_c = PartState::from_mut(state, &mut mcm);
-        let commit = Commit::from_diff(&state_b, &state_c).unwrap();
+        let commit = Commit::from_diff(&state_b, &state_c).unwrap().unwrap();
         queue.push(commit);
         
         let mut state = state_c.clone_mut();
         insert(&mut state, 8, "eight").unwrap();
         insert(&mut state, 4, "half eight").unwrap();
         let state_d = PartState::from_mut(state, &mut mcm);
-        let commit = Commit::from_diff(&state_c, &state_d).unwrap();
+        let commit = Commit::from_diff(&state_c, &state_d).unwrap().unwrap();
         queue.push(commit);
         
         let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
         let mut part = Partition::create(control, "replay part").unwrap();
-        part.add_state(state_a, 0);
+        part.add_state(state_a, None);
         for commit in queue {
             part.push_commit(commit).unwrap();
         }
@@ -995,6 +2056,146 @@ mod tests {
         assert_eq!(*replayed_state, state_d);
     }
     
+    #[test]
+    fn tracked_changes() {
+        let mut mcm = MCM;
+        let mut state = PartState::new(&mut mcm).clone_mut();
+        for i in 1..6 {
+            state.insert(EltId::from(i), i.to_string()).unwrap();
+        }
+        let parent = PartState::from_mut(state, &mut mcm);
+        
+        let mut state = parent.clone_mut();
+        assert!(state.changes().is_empty());
+        state.replace(EltId::from(1), "one".to_string()).unwrap();
+        state.replace(EltId::from(2), "2".to_string()).unwrap();      // unchanged
+        state.remove(EltId::from(3)).unwrap();
+        state.remove(EltId::from(4)).unwrap();
+        state.insert(EltId::from(4), "four".to_string()).unwrap();    // replaced
+        state.insert(EltId::from(6), "six".to_string()).unwrap();
+        state.insert(EltId::from(7), "seven".to_string()).unwrap();
+        state.remove(EltId::from(7)).unwrap();                          // unchanged
+        state.remove(EltId::from(5)).unwrap();
+        state.insert(EltId::from(5), "5".to_string()).unwrap();        // unchanged
+        
+        let changes = state.changes();
+        assert_eq!(changes.len(), 4);
+        let new_state = PartState::from_mut(state, &mut mcm);
+        let commit = Commit::from_diff(&parent, &new_state).unwrap().unwrap();
+        assert_eq!(commit.num_changes(), changes.len());
+        for (id, change) in commit.changes_iter() {
+            assert_eq!(changes.get(id), Some(change));
+        }
+        
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "tracked").unwrap();
+        let mut state = part.tip().unwrap().clone_mut();
+        state.insert(EltId::from(1), "one".to_string()).unwrap();
+        state.remove(EltId::from(1)).unwrap();
+        assert_eq!(part.push_state(state), Ok(false));
+        assert_eq!(part.unsaved_len(), 0);
+    }
+    
+    #[test]
+    fn history_checkpoints() {
+        let mut control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        control.set_state_checkpoint_interval(4);
+        let mut part = Partition::create(control, "history").unwrap();
+        let mut saved = vec![part.tip().unwrap().clone_exact()];
+        for i in 1..21 {
+            let mut state = part.tip().unwrap().clone_mut();
+            state.insert(EltId::from(i), i.to_string()).unwrap();
+            if i > 1 {
+                state.remove(EltId::from(i - 1)).unwrap();
+            }
+            assert_eq!(part.push_state(state), Ok(true));
+            saved.push(part.tip().unwrap().clone_exact());
+        }
+        assert_eq!(part.states_len(), 21);
+        assert!(part.states.len() < 10);
+        assert_eq!(part.states_iter().count(), 21);
+        
+        for old in saved.iter().rev() {
+            let state = part.state(old.statesum()).unwrap();
+            assert_eq!(*state, *old);
+        }
+        let string = saved[6].statesum().as_string(false);
+        assert_eq!(*part.state_from_string(string).unwrap(), saved[6]);
+        
+        // Branch from a historical state, then merge:
+        let mut state = part.state(saved[9].statesum()).unwrap().clone_mut();
+        state.insert(EltId::from(100), "100".to_string()).unwrap();
+        assert_eq!(part.push_state(state), Ok(true));
+        assert_eq!(part.tips_len(), 2);
+        let tips: Vec<Sum> = part.tips_iter().cloned().collect();
+        let merge = part.merge_two(&tips[0], &tips[1]).unwrap()
+                .solve_inline(&AncestorSolver2W::new());
+        assert!(merge.is_solved());
+        let commit = merge.make_commit(&MCM).unwrap();
+        assert_eq!(part.push_commit(commit), Ok(true));
+        let tip = part.tip().unwrap();
+        assert!(tip.is_avail(EltId::from(20)) && tip.is_avail(EltId::from(100)));
+        assert!(!tip.is_avail(EltId::from(9)));
+    }
+    
+    #[test]
+    fn cached_elt_sums() {
+        use std::io::Write;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use error::Result;
+        
+        // Counts serialisations
+        static WRITES: AtomicUsize = AtomicUsize::new(0);
+        #[derive(PartialEq, Eq, Debug)]
+        struct Counted(String);
+        impl Element for Counted {
+            fn write_buf(&self, writer: &mut Write) -> Result<()> {
+                WRITES.fetch_add(1, Ordering::SeqCst);
+                self.0.write_buf(writer)
+            }
+            fn read_buf(buf: &[u8]) -> Result<Self> {
+                Ok(Counted(String::read_buf(buf)?))
+            }
+        }
+        let elt = |s: &str| Counted(s.to_string());
+        
+        let io = MemRepoIO::new();
+        let control = DefaultControl::<Counted, _>::new(io.clone());
+        let mut part = Partition::create(control, "sums").unwrap();
+        let mut state = part.tip().unwrap().clone_mut();
+        for i in 1..11 {
+            state.insert(EltId::from(i), elt(&i.to_string())).unwrap();
+        }
+        assert_eq!(WRITES.load(Ordering::SeqCst), 10);
+        assert_eq!(part.push_state(state), Ok(true));
+        
+        let tip = part.tip_key().unwrap().clone();
+        let mut state = part.tip().unwrap().clone_mut();
+        state.replace(EltId::from(1), elt("one")).unwrap();
+        state.remove(EltId::from(3)).unwrap();
+        assert_eq!(part.push_state(state), Ok(true));
+        let mut state = part.state(&tip).unwrap().clone_mut();
+        state.replace(EltId::from(2), elt("two")).unwrap();
+        assert_eq!(part.push_state(state), Ok(true));
+        assert_eq!(WRITES.load(Ordering::SeqCst), 12);
+        
+        // Merging does not serialise elements:
+        part.merge(&AncestorSolver2W::new(), false).unwrap();
+        assert_eq!(WRITES.load(Ordering::SeqCst), 12);
+        let merged = part.tip().unwrap().clone_exact();
+        assert_eq!(merged.get(EltId::from(1)), Ok(&elt("one")));
+        assert_eq!(merged.get(EltId::from(2)), Ok(&elt("two")));
+        assert!(!merged.is_avail(EltId::from(3)));
+        
+        // Sums are correct (they are verified on reading):
+        part.write_full().unwrap();
+        part.write_snapshot().unwrap();
+        let control = DefaultControl::<Counted, _>::new(io.clone());
+        let mut part2 = Partition::open(control, true).unwrap();
+        part2.load_all().unwrap();
+        assert_eq!(*part2.tip().unwrap(), merged);
+    }
+    
     #[test]
     fn on_new_partition() {
         let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
@@ -1028,4 +2229,563 @@ mod tests {
         
         assert_eq!(part.push_state(state).expect("committing"), false);
     }
+    
+    #[test]
+    fn append_to_log() {
+        let io = MemRepoIO::new();
+        let mut control = DefaultControl::<String, _>::new(io.clone());
+        control.set_log_rollover(LogRollover { max_commits: 3, .. LogRollover::default() });
+        let mut part = Partition::create(control, "append_to_log").expect("create");
+        
+        for i in 0..5 {
+            push(&mut part, i);
+        }
+        // Three commits in the first log, then two in a second:
+        assert_eq!(io.ss_cl_len(0), 2);
+        assert_eq!(io.num_cl(), 2);
+        let tip = part.tip_key().expect("tip").clone();
+        
+        let part2 = Partition::open(DefaultControl::<String, _>::new(io), true)
+                .expect("reopen");
+        assert_eq!(*part2.tip_key().expect("tip"), tip);
+        assert_eq!(part2.tip().expect("tip").num_avail(), 5);
+    }
+    
+    #[test]
+    fn recover_damaged_log() {
+        let io = MemRepoIO::new();
+        let control = DefaultControl::<String, _>::new(io.clone());
+        let mut part = Partition::create(control, "damaged log").expect("create");
+        let mut sums = vec![];
+        for i in 0..3 {
+            let mut state = part.tip().expect("tip").clone_mut();
+            state.insert(EltId::from(i), format!("element {}", i)).expect("insert");
+            part.push_state(state).expect("push");
+            sums.push(part.tip_key().expect("tip").clone());
+        }
+        part.write_fast().expect("write");
+        
+        // Simulate an interrupted append of the last commit:
+        let len = io.cl_data(0, 0).expect("log").len();
+        io.clone().truncate_ss_cl(0, 0, len - 20).expect("truncate");
+        
+        let control = DefaultControl::<String, _>::new(io.clone());
+        assert!(Partition::open(control, true).is_err());
+        
+        let mut control = DefaultControl::<String, _>::new(io.clone());
+        control.set_log_recovery(LogRecovery::Truncate);
+        let part = Partition::open(control, true).expect("open with recovery");
+        assert_eq!(*part.tip_key().expect("tip"), sums[1]);
+        let good_len = io.cl_data(0, 0).expect("log").len();
+        assert!(good_len < len - 20);
+        
+        // Once truncated, the log reads cleanly:
+        let part = Partition::open(DefaultControl::<String, _>::new(io.clone()), true)
+                .expect("open");
+        assert_eq!(*part.tip_key().expect("tip"), sums[1]);
+        
+        let replace_log = |data: &[u8]| {
+            let mut io = io.clone();
+            io.remove_ss_cl(0, 0).expect("remove");
+            io.new_ss_cl(0, 0).expect("new log").expect("writer").write_all(data).expect("write");
+        };
+        let open_truncating = || {
+            let mut control = DefaultControl::<String, _>::new(io.clone());
+            control.set_log_recovery(LogRecovery::Truncate);
+            Partition::open(control, true).expect("open with recovery")
+        };
+        let good = io.cl_data(0, 0).expect("log");
+        
+        // Corrupt element data in the last commit:
+        let mut data = good.clone();
+        let pos = data.windows(8).rposition(|w| w == b"ELT DATA").expect("element");
+        data[pos + 16] ^= 0xFF;
+        replace_log(&data);
+        assert_eq!(*open_truncating().tip_key().expect("tip"), sums[0]);
+        assert!(io.cl_data(0, 0).expect("log").len() < good.len());
+        
+        // Zeros after the last commit:
+        let mut data = good.clone();
+        data.extend_from_slice(&[0; 64]);
+        replace_log(&data);
+        assert_eq!(*open_truncating().tip_key().expect("tip"), sums[1]);
+        assert_eq!(io.cl_data(0, 0).expect("log"), good);
+        
+        // Corruption followed by more commits is not recovered by truncation:
+        let mut data = good.clone();
+        let pos = data.windows(8).position(|w| w == b"COMMIT\x00U").expect("commit");
+        data[pos + 20] ^= 0xFF;
+        replace_log(&data);
+        let mut control = DefaultControl::<String, _>::new(io.clone());
+        control.set_log_recovery(LogRecovery::Truncate);
+        assert!(Partition::open(control, true).is_err());
+        assert_eq!(io.cl_data(0, 0).expect("log"), data);
+    }
+    
+    #[test]
+    fn refresh() {
+        let io = MemRepoIO::new();
+        let control = DefaultControl::<String, _>::new(io.clone());
+        let mut part = Partition::create(control, "refresh").expect("create");
+        let mut reader = Partition::open(DefaultControl::<String, _>::new(io.clone()), true)
+                .expect("open");
+        assert!(reader.refresh().expect("refresh").is_empty());
+        
+        push(&mut part, 1);
+        push(&mut part, 2);
+        let summary = reader.refresh().expect("refresh");
+        assert_eq!((summary.logs, summary.commits, summary.states), (1, 2, 2));
+        assert!(summary.tips_changed);
+        assert_eq!(reader.tip_key().expect("tip"), part.tip_key().expect("tip"));
+        assert!(reader.refresh().expect("refresh").is_empty());
+        
+        // Only the appended commit is read:
+        push(&mut part, 3);
+        let summary = reader.refresh().expect("refresh");
+        assert_eq!((summary.logs, summary.commits, summary.states), (1, 1, 1));
+        
+        part.write_snapshot().expect("snapshot");
+        push(&mut part, 4);
+        let summary = reader.refresh().expect("refresh");
+        assert_eq!((summary.snapshots, summary.commits, summary.states), (1, 1, 1));
+        assert_eq!(reader.tip_key().expect("tip"), part.tip_key().expect("tip"));
+        assert_eq!(reader.tip().expect("tip").num_avail(), 4);
+        
+        // A snapshot of a state already superseded leaves the tip alone:
+        let mut writer = Partition::open(DefaultControl::<String, _>::new(io.clone()), true)
+                .expect("open");
+        push(&mut writer, 5);
+        part.write_snapshot().expect("snapshot");
+        let summary = reader.refresh().expect("refresh");
+        assert_eq!((summary.snapshots, summary.states), (1, 1));
+        assert!(summary.tips_changed);
+        assert_eq!(reader.tips.len(), 1);
+        assert_eq!(reader.tip_key().expect("tip"), writer.tip_key().expect("tip"));
+    }
+    
+    #[test]
+    fn pull_from_replica() {
+        let io1 = MemRepoIO::new();
+        let mut part1 = Partition::create(DefaultControl::<String, _>::new(io1.clone()), "pull")
+                .expect("create");
+        push(&mut part1, 1);
+        push(&mut part1, 2);
+        
+        // Copy files to make a second replica:
+        let io2 = copy_replica(&io1);
+        let mut part2 = Partition::open(DefaultControl::<String, _>::new(io2.clone()), true)
+                .expect("open");
+        
+        push(&mut part1, 3);
+        push(&mut part1, 4);
+        push(&mut part2, 5);
+        let tips = part2.pull_from(&io1).expect("pull");
+        assert_eq!(tips, vec![part1.tip_key().unwrap().clone()]);
+        assert_eq!(part2.tips_len(), 2);
+        assert!(part2.pull_from(&io1).expect("pull").is_empty());
+        
+        part2.merge(&AncestorSolver2W::new(), false).expect("merge");
+        part2.write_full().expect("write");
+        let tip = part2.tip_key().unwrap().clone();
+        
+        // Pulled commits were written to the replica's own logs:
+        let mut part3 = Partition::open(DefaultControl::<String, _>::new(io2), true)
+                .expect("open");
+        part3.load_all().expect("load");
+        assert_eq!(part3.tip_key().unwrap(), &tip);
+        assert_eq!(part3.tip().unwrap().num_avail(), 5);
+        
+        // Replicas of other partitions are rejected:
+        let mut other = Partition::create(DefaultControl::<String, _>::new(MemRepoIO::new()),
+                "other").expect("create");
+        assert!(other.pull_from(&io1).is_err());
+    }
+    
+    #[test]
+    fn import_commits_all_or_none() {
+        let io = MemRepoIO::new();
+        let mut part1 = Partition::create(DefaultControl::<String, _>::new(io.clone()), "import")
+                .expect("create");
+        let mut part2 = Partition::open(DefaultControl::<String, _>::new(io), true)
+                .expect("open");
+        let root = part2.tip_key().unwrap().clone();
+        let mut commits = vec![];
+        for i in 1..3 {
+            let mut state = part1.tip().expect("tip").clone_mut();
+            state.insert(EltId::from(i), format!("element {}", i)).expect("insert");
+            part1.push_state(state).expect("push");
+            commits.push(part1.commit(part1.tip_key().unwrap()).unwrap().unwrap());
+        }
+        // A commit which does not produce its state sum:
+        let bad = Commit::new_explicit(Sum::elt_sum(EltId::from(9), b"bad"),
+                vec![part1.tip_key().unwrap().clone()], HashMap::new(),
+                commits[1].meta().clone());
+        
+        let mut with_bad = commits.clone();
+        with_bad.push(bad);
+        assert_eq!(part2.import_commits(with_bad), Err(PatchOp::PatchApply));
+        assert_eq!(part2.tips_iter().collect::<Vec<_>>(), vec![&root]);
+        assert!(!part2.is_known(commits[0].statesum()));
+        assert!(part2.unsaved.is_empty());
+        
+        // Children may come before parents:
+        commits.reverse();
+        assert_eq!(part2.import_commits(commits), Ok(2));
+        assert_eq!(part2.tip_key().unwrap(), part1.tip_key().unwrap());
+        assert_eq!(part2.unsaved.len(), 2);
+    }
+    
+    #[test]
+    fn bundles() {
+        let io1 = MemRepoIO::new();
+        let mut part1 = Partition::create(DefaultControl::<String, _>::new(io1.clone()), "bundle")
+                .expect("create");
+        push(&mut part1, 1);
+        
+        // Copy files to make a second replica:
+        let io2 = copy_replica(&io1);
+        let mut part2 = Partition::open(DefaultControl::<String, _>::new(io2), true)
+                .expect("open");
+        let known: Vec<Sum> = part2.tips_iter().cloned().collect();
+        
+        push(&mut part1, 2);
+        push(&mut part1, 3);
+        let tips: Vec<Sum> = part1.tips_iter().cloned().collect();
+        let mut bundle = vec![];
+        assert_eq!(part1.export_bundle(&tips, &known, &mut bundle).expect("export"), 2);
+        assert_eq!(part2.import_bundle(&mut &bundle[..]).expect("import"), tips);
+        assert_eq!(*part2.tip().unwrap(), *part1.tip().unwrap());
+        assert!(part2.import_bundle(&mut &bundle[..]).expect("import").is_empty());
+        
+        // Nothing is imported without the required states:
+        let known = tips;
+        push(&mut part1, 4);
+        push(&mut part1, 5);
+        let tips: Vec<Sum> = part1.tips_iter().cloned().collect();
+        let mut bundle = vec![];
+        assert_eq!(part1.export_bundle(&tips, &[], &mut bundle).expect("export"), 5);
+        let mut partial = vec![];
+        let required = part1.parents_of(&tips[0]).unwrap().to_vec();
+        assert_eq!(part1.export_bundle(&tips, &required, &mut partial).expect("export"), 1);
+        let e = part2.import_bundle(&mut &partial[..]).unwrap_err();
+        assert_eq!(e.downcast_ref::<BundleError>(),
+                Some(&BundleError::MissingStates(required.clone())));
+        assert_eq!(part2.tips_iter().cloned().collect::<Vec<_>>(), known);
+        // ... nor is header data used:
+        let mut rest = &partial[..];
+        let mut header = read_head(&mut rest).expect("header");
+        header.shallow = required.clone();
+        let mut shallow = vec![];
+        write_head(&header, &mut shallow).expect("header");
+        shallow.extend_from_slice(rest);
+        assert!(part2.import_bundle(&mut &shallow[..]).is_err());
+        assert!(part2.boundary.is_empty());
+        
+        // A bundle from the initial state applies anywhere with that state:
+        assert_eq!(part2.import_bundle(&mut &bundle[..]).expect("import"), tips);
+        
+        // Bundles of other partitions are rejected:
+        let mut other = Partition::create(DefaultControl::<String, _>::new(MemRepoIO::new()),
+                "other").expect("create");
+        assert!(other.import_bundle(&mut &bundle[..]).is_err());
+    }
+    
+    #[test]
+    fn prune() {
+        let io = MemRepoIO::new();
+        let control = DefaultControl::<String, _>::new(io.clone());
+        let mut part = Partition::create(control, "prune").expect("create");
+        let mut sums = vec![];
+        for i in 0..6 {
+            if i % 2 == 0 && i > 0 {
+                part.write_snapshot().expect("snapshot");
+            }
+            push(&mut part, i);
+            sums.push(part.tip_key().expect("tip").clone());
+        }
+        assert_eq!((io.num_ss(), io.num_cl()), (3, 3));
+        
+        // Keeping the first commit's state prevents removal:
+        let keep = Keep::States(vec![sums[0].clone()].into_iter().collect());
+        let pruned = part.prune(keep).expect("prune");
+        assert_eq!((pruned.ss0, pruned.snapshots.len()), (0, 0));
+        
+        let keep = Keep::States(vec![sums[2].clone()].into_iter().collect());
+        let pruned = part.prune(keep).expect("prune");
+        assert_eq!(pruned.ss0, 1);
+        assert_eq!((pruned.snapshots, pruned.logs), (vec![0], vec![(0, 0)]));
+        assert!(pruned.lost.contains(&sums[0]) && !pruned.lost.contains(&sums[2]));
+        
+        let pruned = part.prune(Keep::Snapshots(1)).expect("prune");
+        assert_eq!((pruned.ss0, pruned.snapshots.len()), (2, 1));
+        assert_eq!((io.num_ss(), io.num_cl()), (1, 1));
+        
+        // Further commits still work, and the partition can be reloaded:
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert(EltId::from(6), "element 6".to_string()).expect("insert");
+        part.push_state(state).expect("push");
+        part.write_fast().expect("write");
+        let tip = part.tip_key().expect("tip").clone();
+        
+        let mut part2 = Partition::open(DefaultControl::<String, _>::new(io.clone()), false)
+                .expect("open");
+        part2.load_all().expect("load");
+        assert_eq!(part2.tips_len(), 1);
+        assert_eq!(*part2.tip_key().expect("tip"), tip);
+        assert_eq!(part2.tip().expect("tip").num_avail(), 7);
+    }
+    
+    #[test]
+    fn compact_logs() {
+        let io = MemRepoIO::new();
+        let mut control = DefaultControl::<String, _>::new(io.clone());
+        control.set_log_rollover(LogRollover { max_commits: 1, .. LogRollover::default() });
+        let mut part = Partition::create(control, "compact").expect("create");
+        for i in 0..3 {
+            push(&mut part, i);
+        }
+        // A copy of the first log, as if a write were repeated:
+        let data = io.cl_data(0, 0).expect("log");
+        io.clone().new_ss_cl(0, 3).expect("new").expect("writer").write_all(&data).expect("write");
+        assert_eq!(io.num_cl(), 4);
+        let tip = part.tip_key().expect("tip").clone();
+        
+        let compacted = part.compact_logs(0).expect("compact").expect("compacted");
+        assert_eq!(compacted, Compacted { cl: 0, logs: 4, commits: 3, duplicates: 1 });
+        assert_eq!(io.num_cl(), 1);
+        // No gaps are left in log numbers:
+        assert_eq!(io.ss_cl_len(0), 1);
+        assert!(part.compact_logs(0).expect("compact").is_none());
+        
+        let part2 = Partition::open(DefaultControl::<String, _>::new(io), true)
+                .expect("reopen");
+        assert_eq!(*part2.tip_key().expect("tip"), tip);
+        assert_eq!(part2.tip().expect("tip").num_avail(), 3);
+    }
+    
+    #[test]
+    fn refresh_after_compaction() {
+        let io = MemRepoIO::new();
+        let mut control = DefaultControl::<String, _>::new(io.clone());
+        control.set_log_rollover(LogRollover { max_commits: 1, .. LogRollover::default() });
+        let mut part = Partition::create(control, "refresh compact").expect("create");
+        for i in 0..3 {
+            push(&mut part, i);
+        }
+        assert_eq!(io.num_cl(), 3);
+        let mut reader = Partition::open(DefaultControl::<String, _>::new(io.clone()), true)
+                .expect("open");
+        
+        // The next commit goes to log 1, which now has other contents:
+        part.compact_logs(0).expect("compact").expect("compacted");
+        push(&mut part, 3);
+        assert_eq!(io.ss_cl_len(0), 2);
+        let summary = reader.refresh().expect("refresh");
+        assert_eq!((summary.commits, summary.states), (3, 1));
+        assert_eq!(reader.tip_key().expect("tip"), part.tip_key().expect("tip"));
+        
+        // Only new commits are read from the replaced logs thereafter:
+        push(&mut part, 4);
+        let summary = reader.refresh().expect("refresh");
+        assert_eq!((summary.commits, summary.states), (1, 1));
+        assert_eq!(reader.tip().expect("tip").num_avail(), 5);
+    }
+    
+    #[test]
+    fn lazy_elements() {
+        use error::ElementOp;
+        
+        let io = MemRepoIO::new();
+        let control = DefaultControl::<String, _>::new(io.clone());
+        let mut part = Partition::create(control, "lazy").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        for i in 0..4 {
+            state.insert(EltId::from(i), format!("element {}", i)).expect("insert");
+        }
+        part.push_state(state).expect("push");
+        part.write_snapshot().expect("snapshot");
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.replace(EltId::from(1), "replaced".to_string()).expect("replace");
+        state.remove(EltId::from(2)).expect("remove");
+        part.push_state(state).expect("push");
+        part.write_fast().expect("write");
+        let tip = part.tip_key().expect("tip").clone();
+        
+        let mut control = DefaultControl::<String, _>::new(io.clone());
+        control.set_lazy_elements(true);
+        let part2 = Partition::open(control, true).expect("open");
+        assert_eq!(*part2.tip_key().expect("tip"), tip);
+        let state = part2.tip().expect("tip");
+        assert_eq!(state.num_avail(), 3);
+        // Only the replaced element was loaded (from the commit log):
+        assert_eq!(state.num_loaded(), 1);
+        assert_eq!(state.get(EltId::from(3)), Ok(&"element 3".to_string()));
+        assert_eq!(state.num_loaded(), 2);
+        assert_eq!(state.get(EltId::from(1)), Ok(&"replaced".to_string()));
+        assert_eq!(*state, *part.tip().expect("tip"));
+        
+        // Corrupt element data is detected on access:
+        let mut data = io.ss_data(1).expect("snapshot");
+        let pos = data.windows(9).position(|w| w == b"element 0").expect("find");
+        data[pos] = b'E';
+        let mut io2 = MemRepoIO::new();
+        io2.new_ss(0).unwrap().expect("new ss").write_all(&data).unwrap();
+        let mut control = DefaultControl::<String, _>::new(io2);
+        control.set_lazy_elements(true);
+        let mut part3 = Partition::open(control, true).expect("open");
+        {
+            let state = part3.tip().expect("tip");
+            assert_eq!(state.get(EltId::from(3)), Ok(&"element 3".to_string()));
+            assert_eq!(state.get(EltId::from(0)), Err(ElementOp::LoadFailed));
+            assert!(state.load_all().is_err());
+            
+            // Operations needing the element fail rather than panic:
+            let parent = part.state(&state.parents()[0]).expect("parent");
+            assert_eq!(Commit::from_diff(&parent, state).err(), Some(ElementOp::LoadFailed));
+        }
+        assert!(part3.write_snapshot().is_err());
+        
+        // Pruning the snapshot elements are read from loads them first:
+        let mut control = DefaultControl::<String, _>::new(io.clone());
+        control.set_lazy_elements(true);
+        let mut part4 = Partition::open(control, true).expect("open");
+        assert_eq!(part4.tip().expect("tip").num_loaded(), 1);
+        part.write_snapshot().expect("snapshot");
+        let pruned = part4.prune(Keep::Snapshots(1)).expect("prune");
+        assert_eq!(pruned.snapshots, vec![0, 1]);
+        let state = part4.tip().expect("tip");
+        assert_eq!(state.num_loaded(), 3);
+        assert_eq!(state.get(EltId::from(0)), Ok(&"element 0".to_string()));
+        
+        // Elements not in memory are copied to a new snapshot without loading:
+        let mut control = DefaultControl::<String, _>::new(io.clone());
+        control.set_lazy_elements(true);
+        let mut part5 = Partition::open(control, true).expect("open");
+        assert_eq!(part5.tip().expect("tip").num_loaded(), 0);
+        part5.write_snapshot().expect("snapshot");
+        assert_eq!(part5.tip().expect("tip").num_loaded(), 0);
+        let part6 = Partition::open(DefaultControl::<String, _>::new(io.clone()), true)
+                .expect("open");
+        assert_eq!(*part6.tip().expect("tip"), *part.tip().expect("tip"));
+    }
+    
+    #[test]
+    fn thread_safety() {
+        use std::sync::Arc;
+        use std::thread;
+        use io::file::RepoFileIO;
+        
+        fn send_sync<T: Send+Sync>() {}
+        send_sync::<PartState<String>>();
+        send_sync::<MutPartState<String>>();
+        send_sync::<Commit<String>>();
+        send_sync::<Partition<DefaultControl<String, MemRepoIO>>>();
+        send_sync::<Partition<DefaultControl<String, RepoFileIO>>>();
+        
+        let io = MemRepoIO::new();
+        let mut part = Partition::create(DefaultControl::<String, _>::new(io.clone()), "threads")
+                .expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        for i in 0..10 {
+            state.insert(EltId::from(i), format!("element {}", i)).expect("insert");
+        }
+        part.push_state(state).expect("push");
+        part.write_snapshot().expect("snapshot");
+        
+        // Read a shared tip (with elements loaded on demand) from several threads:
+        let mut control = DefaultControl::<String, _>::new(io.clone());
+        control.set_lazy_elements(true);
+        let part2 = Partition::open(control, true).expect("open");
+        let tip = Arc::new(part2.tip().expect("tip").clone_exact());
+        let handles: Vec<_> = (0..4).map(|_| {
+            let tip = tip.clone();
+            thread::spawn(move || {
+                (0..10).filter(|i| {
+                    tip.get(EltId::from(*i)) == Ok(&format!("element {}", i))
+                }).count()
+            })
+        }).collect();
+        for handle in handles {
+            assert_eq!(handle.join().expect("join"), 10);
+        }
+        
+        // Snapshots may be decoded on several threads:
+        let mut control = DefaultControl::<String, _>::new(io);
+        control.set_read_threads(4);
+        let part3 = Partition::open(control, true).expect("open");
+        assert_eq!(*part3.tip().expect("tip"), *part.tip().expect("tip"));
+        
+        // A whole partition can be moved to another thread:
+        let handle = thread::spawn(move || part.tip().expect("tip").num_avail());
+        assert_eq!(handle.join().expect("join"), 10);
+    }
+    
+    #[test]
+    fn transactions() {
+        use error::TxnError;
+        
+        let control = DefaultControl::<String, _>::new(DummyRepoIO::new());
+        let mut part = Partition::create(control, "txn").expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        for i in 1..5 {
+            state.insert(EltId::from(i), i.to_string()).expect("insert");
+        }
+        part.push_state(state).expect("push");
+        let base = part.tip_key().expect("tip").clone();
+        
+        // Fast-forward:
+        let mut txn = part.begin().expect("begin");
+        txn.replace(EltId::from(1), "one".to_string()).expect("replace");
+        assert_eq!(part.commit_txn(txn), Ok(true));
+        assert_eq!(part.tip().expect("tip").get(EltId::from(1)), Ok(&"one".to_string()));
+        
+        // Two transactions touching disjoint elements: the second is rebased.
+        let mut txn1 = part.begin().expect("begin");
+        let mut txn2 = part.begin().expect("begin");
+        assert_eq!(txn1.get(EltId::from(2)), Ok(&"2".to_string()));
+        txn1.replace(EltId::from(2), "two".to_string()).expect("replace");
+        txn2.remove(EltId::from(3)).expect("remove");
+        txn2.insert(EltId::from(5), "five".to_string()).expect("insert");
+        assert_eq!(part.commit_txn(txn1), Ok(true));
+        assert_eq!(part.commit_txn(txn2), Ok(true));
+        assert_eq!(part.tips_len(), 1);
+        {
+            let tip = part.tip().expect("tip");
+            assert_eq!(tip.get(EltId::from(2)), Ok(&"two".to_string()));
+            assert!(!tip.is_avail(EltId::from(3)));
+            assert_eq!(tip.get(EltId::from(5)), Ok(&"five".to_string()));
+            assert_eq!(tip.num_avail(), 4);
+        }
+        
+        // Conflicts: reading or writing an element changed in the meantime.
+        let mut txn1 = part.begin().expect("begin");
+        let mut txn2 = part.begin().expect("begin");
+        let txn3 = part.begin().expect("begin");
+        let txn4 = part.begin().expect("begin");
+        txn1.replace(EltId::from(4), "four".to_string()).expect("replace");
+        txn1.remove(EltId::from(5)).expect("remove");
+        txn2.replace(EltId::from(5), "5".to_string()).expect("replace");
+        assert!(txn3.is_avail(EltId::from(4)));
+        assert_eq!(txn4.get(EltId::from(2)), Ok(&"two".to_string()));
+        let tip = part.tip_key().expect("tip").clone();
+        assert_eq!(part.commit_txn(txn1), Ok(true));
+        let after = part.tip_key().expect("tip").clone();
+        assert_eq!(part.commit_txn(txn2), Err(TxnError::Conflict(vec![EltId::from(5)])));
+        assert_eq!(part.commit_txn(txn3), Err(TxnError::Conflict(vec![EltId::from(4)])));
+        assert_eq!(part.commit_txn(txn4), Ok(false));
+        assert_eq!(*part.tip_key().expect("tip"), after);
+        assert!(tip != after);
+        
+        let txn = part.begin().expect("begin");
+        assert_eq!(txn.num_avail(), 3);
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert(EltId::from(6), "six".to_string()).expect("insert");
+        part.push_state(state).expect("push");
+        assert_eq!(part.commit_txn(txn), Err(TxnError::Conflict(vec![EltId::from(6)])));
+        
+        // Base must still be known:
+        let mut txn = Transaction::new(part.state(&base).expect("base").clone_mut());
+        txn.insert(EltId::from(7), "seven".to_string()).expect("insert");
+        assert_eq!(part.commit_txn(txn), Ok(true));
+    }
 }
@@ -9,18 +9,27 @@
 pub use ::LIB_VERSION;
 
 pub use commit::{UserMeta, CommitMeta, CommitMetaPartial, Commit, MakeCommitMeta, EltChange};
-pub use control::{Control, SnapshotPolicy, DefaultControl, DefaultSnapshot};
+pub use control::{Control, SnapshotPolicy, DefaultControl, DefaultSnapshot, LogRollover,
+        LogDamage, LogRecovery};
 pub use elt::{EltId, Element};
 pub use error::{Result, Error, ReadError, ReadErrorFormatter, ArgError, ElementOp, PatchOp,
-        PathError, MatchError, TipError, MergeError, ReadOnly, UserError,
-        OtherError, make_io_err};
-pub use io::{DummyRepoIO, RepoIO};
-pub use io::discover::{part_from_path, discover_basename};
-pub use io::file::{PartPaths, RepoFileIO};
+        PathError, MatchError, TipError, MergeError, TxnError, ReadOnly, LockError, UserError,
+        SyncError, BundleError, OtherError, make_io_err};
+pub use io::{DummyRepoIO, RepoIO, EltSource};
+pub use io::discover::{part_from_path, discover_basename, clean_temp_files, discover_repo,
+        part_number, RepoDiscovery, DirParts, DiscoveredPart, Diagnostic};
+pub use io::file::{PartPaths, RepoFileIO, LockMode};
+pub use io::mem::MemRepoIO;
+#[cfg(all(target_os = "linux", feature = "inotify"))]
+pub use io::watch::PartWatcher;
 pub use merge::{TwoWayMerge, EltMerge, TwoWaySolver, TwoWaySolveUseA, TwoWaySolveUseB,
         TwoWaySolveUseC, TwoWaySolveFail, TwoWaySolverChain, AncestorSolver2W, RenamingSolver2W};
-pub use part::{Partition, TipIter, StateItem, StateIter};
+pub use part::{Partition, Refresh, Keep, Pruned, Compacted, TipIter, StateItem, StateIter};
 pub use rw::header::{FileType, UserData, FileHeader, validate_repo_name};
-pub use state::{PartState, MutPartState, StateRead, StateWrite, EltIter};
+pub use shared::{SharedPartition, TipSnapshot, PartitionWriter};
+pub use state::{PartState, MutPartState, StateRead, StateWrite, EltIter, EltSumIter};
 pub use sum::{Sum, SUM_BYTES};
-pub use util::{rtrim, ByteFormatter, HexFormatter};
+pub use sync::{Request, Served, Reconciled, PROTOCOL_VERSION};
+pub use sync::tcp::TcpServer;
+pub use txn::Transaction;
+pub use util::{rtrim, ByteFormatter, HexFormatter, CountingReader};
@@ -0,0 +1,398 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: a persistent map from element identifiers to values.
+//!
+//! This is a hash-array-mapped trie keyed directly on the bits of the `EltId`
+//! (identifiers are usually random, so need no further hashing). Each node
+//! has up to sixteen children, indexed by four bits of the key. Nodes are
+//! reference-counted, so cloning a map is O(1) and modifying a clone copies
+//! only the nodes on the path to the modified entry. States derived from one
+//! another thus share most of their memory.
+//!
+//! The shape of the trie depends only on the set of keys present (each entry
+//! is held at the shallowest level where its key prefix is unique), which
+//! allows comparisons to skip shared sub-trees.
+
+use std::fmt;
+use std::iter::FromIterator;
+use std::mem;
+use std::slice;
+use std::sync::Arc;
+
+use elt::EltId;
+
+const BITS: u32 = 4;
+const MASK: u64 = (1 << BITS) - 1;
+
+/// Persistent map; see module documentation.
+pub struct PMap<V> {
+    // Always a branch (possibly empty)
+    root: Arc<Node<V>>,
+    len: usize,
+}
+
+#[derive(Clone)]
+enum Node<V> {
+    Leaf(EltId, V),
+    Branch(Branch<V>),
+}
+
+// Children are stored densely; bit i of `bitmap` is set if child i is present.
+#[derive(Clone)]
+struct Branch<V> {
+    bitmap: u16,
+    children: Vec<Arc<Node<V>>>,
+}
+
+fn index(id: EltId, shift: u32) -> u32 {
+    let key: u64 = id.into();
+    ((key >> shift) & MASK) as u32
+}
+
+impl<V> Node<V> {
+    fn is_leaf(&self) -> bool {
+        match *self {
+            Node::Leaf(..) => true,
+            Node::Branch(_) => false,
+        }
+    }
+}
+
+impl<V> Branch<V> {
+    fn new() -> Branch<V> {
+        Branch { bitmap: 0, children: Vec::new() }
+    }
+    // Position in `children` of child `i`, if present
+    fn pos(&self, i: u32) -> Option<usize> {
+        let bit = 1u16 << i;
+        if self.bitmap & bit == 0 {
+            None
+        } else {
+            Some((self.bitmap & (bit - 1)).count_ones() as usize)
+        }
+    }
+    fn child(&self, i: u32) -> Option<&Arc<Node<V>>> {
+        self.pos(i).map(|pos| &self.children[pos])
+    }
+    // Insert a new child; `i` must not be present
+    fn add(&mut self, i: u32, node: Arc<Node<V>>) {
+        let bit = 1u16 << i;
+        let pos = (self.bitmap & (bit - 1)).count_ones() as usize;
+        self.bitmap |= bit;
+        self.children.insert(pos, node);
+    }
+    fn take(&mut self, i: u32, pos: usize) -> Arc<Node<V>> {
+        self.bitmap &= !(1u16 << i);
+        self.children.remove(pos)
+    }
+}
+
+impl<V> PMap<V> {
+    /// Create an empty map
+    pub fn new() -> PMap<V> {
+        PMap { root: Arc::new(Node::Branch(Branch::new())), len: 0 }
+    }
+
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    /// True if there are no entries
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get a value
+    pub fn get(&self, id: EltId) -> Option<&V> {
+        let mut node = &*self.root;
+        let mut shift = 0;
+        loop {
+            match *node {
+                Node::Leaf(leaf_id, ref value) => {
+                    return if leaf_id == id { Some(value) } else { None };
+                },
+                Node::Branch(ref branch) => {
+                    match branch.child(index(id, shift)) {
+                        Some(child) => node = child,
+                        None => return None,
+                    }
+                    shift += BITS;
+                },
+            }
+        }
+    }
+    /// True if an entry with key `id` is present
+    pub fn contains_key(&self, id: EltId) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// Iterate over all entries, in an unspecified (but deterministic) order
+    pub fn iter(&self) -> Iter<V> {
+        Iter { stack: vec![slice::from_ref(&self.root).iter()], len: self.len }
+    }
+    /// Iterate over all values
+    pub fn values(&self) -> Values<V> {
+        Values { iter: self.iter() }
+    }
+
+    /// Compare with another map, using `f` to compare values under the same
+    /// key. Sub-trees shared by both maps are assumed equal without calling
+    /// `f`.
+    pub fn eq_by<F: Fn(EltId, &V, &V) -> bool>(&self, other: &PMap<V>, f: F) -> bool {
+        self.len == other.len && node_eq(&self.root, &other.root, &f)
+    }
+}
+
+fn node_eq<V, F: Fn(EltId, &V, &V) -> bool>(a: &Arc<Node<V>>, b: &Arc<Node<V>>, f: &F) -> bool {
+    if Arc::ptr_eq(a, b) {
+        return true;
+    }
+    match (&**a, &**b) {
+        (&Node::Leaf(id_a, ref va), &Node::Leaf(id_b, ref vb)) => id_a == id_b && f(id_a, va, vb),
+        (&Node::Branch(ref ba), &Node::Branch(ref bb)) => {
+            ba.bitmap == bb.bitmap &&
+                ba.children.iter().zip(bb.children.iter()).all(|(ca, cb)| node_eq(ca, cb, f))
+        },
+        _ => false,
+    }
+}
+
+impl<V: Clone> PMap<V> {
+    /// Insert a value, returning the previous value under this key if any.
+    pub fn insert(&mut self, id: EltId, value: V) -> Option<V> {
+        let old = insert_in(&mut self.root, id, value, 0);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Remove a value, returning it if found.
+    pub fn remove(&mut self, id: EltId) -> Option<V> {
+        if !self.contains_key(id) {
+            // avoid copying nodes
+            return None;
+        }
+        let old = remove_from(&mut self.root, id, 0);
+        debug_assert!(old.is_some());
+        self.len -= 1;
+        old
+    }
+}
+
+// `node` must be a branch
+fn insert_in<V: Clone>(node: &mut Arc<Node<V>>, id: EltId, value: V, shift: u32) -> Option<V> {
+    let branch = match *Arc::make_mut(node) {
+        Node::Branch(ref mut branch) => branch,
+        Node::Leaf(..) => unreachable!(),
+    };
+    let i = index(id, shift);
+    let pos = match branch.pos(i) {
+        Some(pos) => pos,
+        None => {
+            branch.add(i, Arc::new(Node::Leaf(id, value)));
+            return None;
+        }
+    };
+    let child = &mut branch.children[pos];
+    match **child {
+        Node::Branch(_) => return insert_in(child, id, value, shift + BITS),
+        Node::Leaf(leaf_id, _) if leaf_id != id => {
+            // Push the existing leaf down a level, then insert beside it
+            let mut sub = Branch::new();
+            sub.add(index(leaf_id, shift + BITS), child.clone());
+            *child = Arc::new(Node::Branch(sub));
+            return insert_in(child, id, value, shift + BITS);
+        },
+        Node::Leaf(..) => {},
+    }
+    let old = mem::replace(child, Arc::new(Node::Leaf(id, value)));
+    match Arc::try_unwrap(old) {
+        Ok(Node::Leaf(_, value)) => Some(value),
+        Err(shared) => match *shared {
+            Node::Leaf(_, ref value) => Some(value.clone()),
+            Node::Branch(_) => unreachable!(),
+        },
+        Ok(Node::Branch(_)) => unreachable!(),
+    }
+}
+
+// `node` must be a branch and `id` must be present below it
+fn remove_from<V: Clone>(node: &mut Arc<Node<V>>, id: EltId, shift: u32) -> Option<V> {
+    let branch = match *Arc::make_mut(node) {
+        Node::Branch(ref mut branch) => branch,
+        Node::Leaf(..) => unreachable!(),
+    };
+    let i = index(id, shift);
+    let pos = branch.pos(i)?;
+    if branch.children[pos].is_leaf() {
+        return match Arc::try_unwrap(branch.take(i, pos)) {
+            Ok(Node::Leaf(_, value)) => Some(value),
+            Err(shared) => match *shared {
+                Node::Leaf(_, ref value) => Some(value.clone()),
+                Node::Branch(_) => unreachable!(),
+            },
+            Ok(Node::Branch(_)) => unreachable!(),
+        };
+    }
+    let old = remove_from(&mut branch.children[pos], id, shift + BITS);
+    // A sub-branch left holding a single leaf is replaced by that leaf
+    let single = match *branch.children[pos] {
+        Node::Branch(ref sub) if sub.children.len() == 1 && sub.children[0].is_leaf() =>
+            Some(sub.children[0].clone()),
+        _ => None,
+    };
+    if let Some(leaf) = single {
+        branch.children[pos] = leaf;
+    }
+    old
+}
+
+impl<V> Clone for PMap<V> {
+    fn clone(&self) -> PMap<V> {
+        PMap { root: self.root.clone(), len: self.len }
+    }
+}
+impl<V> Default for PMap<V> {
+    fn default() -> PMap<V> {
+        PMap::new()
+    }
+}
+impl<V: fmt::Debug> fmt::Debug for PMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+impl<V: Clone> FromIterator<(EltId, V)> for PMap<V> {
+    fn from_iter<I: IntoIterator<Item = (EltId, V)>>(iter: I) -> PMap<V> {
+        let mut map = PMap::new();
+        for (id, value) in iter {
+            map.insert(id, value);
+        }
+        map
+    }
+}
+impl<'a, V> IntoIterator for &'a PMap<V> {
+    type Item = (EltId, &'a V);
+    type IntoIter = Iter<'a, V>;
+    fn into_iter(self) -> Iter<'a, V> {
+        self.iter()
+    }
+}
+
+/// Iterator over entries of a `PMap`
+pub struct Iter<'a, V: 'a> {
+    stack: Vec<slice::Iter<'a, Arc<Node<V>>>>,
+    len: usize,
+}
+impl<'a, V> Clone for Iter<'a, V> {
+    fn clone(&self) -> Iter<'a, V> {
+        Iter { stack: self.stack.clone(), len: self.len }
+    }
+}
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (EltId, &'a V);
+    fn next(&mut self) -> Option<(EltId, &'a V)> {
+        loop {
+            let node = match self.stack.last_mut() {
+                None => return None,
+                Some(iter) => iter.next(),
+            };
+            match node.map(|node| &**node) {
+                None => { self.stack.pop(); },
+                Some(&Node::Leaf(id, ref value)) => {
+                    self.len -= 1;
+                    return Some((id, value));
+                },
+                Some(&Node::Branch(ref branch)) => self.stack.push(branch.children.iter()),
+            }
+        }
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+impl<'a, V> ExactSizeIterator for Iter<'a, V> {}
+
+/// Iterator over values of a `PMap`
+pub struct Values<'a, V: 'a> {
+    iter: Iter<'a, V>,
+}
+impl<'a, V> Iterator for Values<'a, V> {
+    type Item = &'a V;
+    fn next(&mut self) -> Option<&'a V> {
+        self.iter.next().map(|(_, value)| value)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use rand::{Rng, thread_rng};
+    use super::*;
+
+    fn check(map: &PMap<u32>, model: &HashMap<EltId, u32>) {
+        assert_eq!(map.len(), model.len());
+        assert_eq!(map.iter().len(), model.len());
+        for (id, value) in map.iter() {
+            assert_eq!(model.get(&id), Some(value));
+        }
+        for (id, value) in model {
+            assert_eq!(map.get(*id), Some(value));
+        }
+    }
+
+    #[test]
+    fn against_hash_map() {
+        let mut rng = thread_rng();
+        let mut map = PMap::new();
+        let mut model = HashMap::new();
+        // Small keys give deep, narrow paths; random keys spread out
+        let mut keys: Vec<EltId> = (0..200).map(|i| EltId::from(i * 0x10_0000)).collect();
+        keys.extend((0..300).map(|_| EltId::from(rng.gen::<u64>())));
+        for (i, id) in keys.iter().enumerate() {
+            assert_eq!(map.insert(*id, i as u32), model.insert(*id, i as u32));
+        }
+        check(&map, &model);
+
+        let copy = map.clone();
+        let copy_model = model.clone();
+        for (i, id) in keys.iter().enumerate() {
+            match i % 3 {
+                0 => assert_eq!(map.remove(*id), model.remove(id)),
+                1 => assert_eq!(map.insert(*id, 7), model.insert(*id, 7)),
+                _ => {},
+            }
+        }
+        assert_eq!(map.remove(EltId::from(1)), None);
+        check(&map, &model);
+        check(&copy, &copy_model);
+        assert!(!map.eq_by(&copy, |_, a, b| a == b));
+
+        for id in &keys {
+            assert_eq!(map.remove(*id), model.remove(id));
+        }
+        check(&map, &model);
+        assert!(map.eq_by(&PMap::new(), |_, _, _| false));
+    }
+
+    #[test]
+    fn shape_and_sharing() {
+        let a: PMap<u32> = (0..100).map(|i| (EltId::from(i), i as u32)).collect();
+        let b: PMap<u32> = (0..100).rev().map(|i| (EltId::from(i), i as u32)).collect();
+        // Same keys give the same shape regardless of insertion order:
+        assert!(a.eq_by(&b, |_, x, y| x == y));
+
+        let mut c = a.clone();
+        c.insert(EltId::from(500), 500);
+        c.remove(EltId::from(500));
+        // Unmodified leaves are shared, thus never compared:
+        assert!(a.eq_by(&c, |_, _, _| false));
+    }
+}
@@ -0,0 +1,89 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin bundle reading and writing
+//!
+//! A bundle holds a set of commits for transfer between replicas without a
+//! connection between them (see `Partition::export_bundle`). After the file
+//! header, it lists the states required to apply these commits, then holds
+//! the commits themselves in the same encoding as a commit log.
+
+use std::io::{Read, Write};
+use std::u32;
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use commit::Commit;
+use elt::Element;
+use error::{Result, ReadError};
+use rw::commitlog::{start_log, write_commit};
+use rw::sum;
+use sum::{Sum, SUM_BYTES};
+
+/// Read the list of states required by a bundle (i.e. parents of its commits
+/// which it does not contain). This follows the header.
+///
+/// The commits follow this and may be read with `commitlog::read_log`.
+///
+/// `format_ver` is the decimalised file format version
+pub fn read_requires(reader: &mut Read, _format_ver: u32) -> Result<Vec<Sum>> {
+    // A reader which calculates the checksum of what was read:
+    let mut r = sum::HashReader::new(reader);
+    let mut buf = vec![0; 32];
+
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..8] != *b"REQUIRES" {
+        return ReadError::err("unexpected contents (expected REQUIRES)", 0, (0, 8));
+    }
+    let num = BigEndian::read_u64(&buf[8..16]);
+    if num > u32::MAX as u64 {
+        return ReadError::err("too many required states", 0, (8, 16));
+    }
+    let mut pos = 16;
+
+    let mut requires = Vec::with_capacity(num as usize);
+    for _ in 0..num {
+        r.read_exact(&mut buf[0..SUM_BYTES])?;
+        requires.push(Sum::load(&buf[0..SUM_BYTES]));
+        pos += SUM_BYTES;
+    }
+
+    let sum = r.sum();
+    r.into_inner().read_exact(&mut buf[0..SUM_BYTES])?;
+    if sum != buf[0..SUM_BYTES] {
+        return ReadError::err("checksum invalid", pos, (0, SUM_BYTES));
+    }
+    Ok(requires)
+}
+
+/// Write the contents of a bundle (without header) to a stream: the list of
+/// states required (`requires`) and the commits.
+///
+/// Commits must be ordered such that each follows any of its parents which
+/// are included.
+pub fn write_bundle<E: Element>(requires: &[Sum], commits: &[Commit<E>],
+        writer: &mut Write) -> Result<()>
+{
+    trace!("Writing bundle ({} commits, {} required states)",
+            commits.len(), requires.len());
+    {
+        // A writer which calculates the checksum of what was written:
+        let mut w = sum::HashWriter::new(&mut *writer);
+
+        w.write_all(b"REQUIRES")?;
+        w.write_u64::<BigEndian>(requires.len() as u64)?;
+        for sum in requires {
+            sum.write_to(&mut w)?;
+        }
+
+        let sum = w.sum();
+        sum.write_to(&mut w.into_inner())?;
+    }
+
+    start_log(writer)?;
+    for commit in commits {
+        write_commit(commit, writer)?;
+    }
+    Ok(())
+}
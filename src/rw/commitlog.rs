@@ -6,18 +6,18 @@
 
 //! Support for reading and writing Rust snapshots
 
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::u32;
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
 use rw::{sum, read_meta, write_meta};
-use commit::{Commit, EltChange};
-use elt::Element;
+use commit::{Commit, CommitMeta, EltChange};
+use elt::{Element, EltId};
 use sum::{Sum, SUM_BYTES};
-use error::{Result, ReadError};
+use error::{Result, Error, ReadError};
 
 /// Implement this to use `read_log()`.
 /// 
@@ -40,137 +40,422 @@ impl<E: Element> CommitReceiver<E> for Vec<Commit<E>> {
 /// Read a commit log from a stream
 /// 
 /// `format_ver` is the decimalised file format version
-pub fn read_log<E: Element>(mut reader: &mut Read,
+pub fn read_log<E: Element>(reader: &mut Read,
         receiver: &mut CommitReceiver<E>, format_ver: u32) -> Result<()>
 {
-    let mut pos: usize = 0;
     let mut buf = vec![0; 32];
-    
-    reader.read_exact(&mut buf[0..16])?;
-    if buf[0..16] != *b"COMMIT LOG\x00\x00\x00\x00\x00\x00" {
-        return ReadError::err("unexpected contents (expected \
-            COMMIT LOG\\x00\\x00\\x00\\x00\\x00\\x00)", pos, (0, 16));
-    }
-    pos += 16;
+    read_start(reader, &mut buf)?;
+    let mut pos: usize = 16;
     
     // We now read commits. Since new commits can simply be appended to the
     // file, we only know we're at the end if we hit EOF. This is the only
     // condition where encountering EOF is not an error.
+    while let Some(commit) = read_commit(reader, &mut buf, &mut pos, format_ver)? {
+        if !receiver.receive(commit) { break; }
+    }
+    Ok(())
+}
+
+/// Position up to which a commit log has been read; see `read_log_recover`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogPos {
+    /// Number of bytes read, ending at the end of a commit (or of the section
+    /// identifier at the start of the log)
+    pub len: usize,
+    /// Checksum of the commit ending at `len`, if any. This identifies the
+    /// log, should the file since have been replaced by another.
+    pub last: Option<Sum>,
+}
+
+/// Read a commit log from a stream, stopping without failing where the log
+/// ends with a truncated or corrupt commit.
+/// 
+/// If `from.len` is non-zero, the stream must already have been advanced
+/// this far (see `skip_log`); reading then continues with the next commit,
+/// allowing new commits appended to a log to be read. Otherwise `from`
+/// should be `LogPos::default()` and the log is read from the start.
+/// 
+/// All commits read before any damage are passed to `receiver`. Returns the
+/// position (counting from the start of the stream, including skipped
+/// bytes) of the end of the last complete commit, i.e. the length to which
+/// the log could be truncated or the position to resume from when reading
+/// again, together with the error encountered if reading stopped early.
+/// 
+/// Only damage to the last commit is handled this way: the log ending part
+/// way through a commit, or corrupt data after which no valid commit can be
+/// found. Other I/O errors and corruption followed by more commits are
+/// returned as errors, since discarding the rest of the log would lose valid
+/// commits.
+/// 
+/// `format_ver` is the decimalised file format version
+pub fn read_log_recover<E: Element>(reader: &mut Read, receiver: &mut CommitReceiver<E>,
+        format_ver: u32, from: LogPos) -> Result<(LogPos, Option<Error>)>
+{
+    let mut r = Recorder { inner: reader, count: 0, data: Vec::new() };
+    let mut buf = vec![0; 32];
+    if from.len == 0 {
+        if let Err(e) = read_start(&mut r, &mut buf) {
+            return Ok((LogPos::default(), Some(tail_damage(e, &mut r, format_ver)?)));
+        }
+    }
+    let mut pos: usize = from.len + r.count;
+    let mut last = LogPos { len: pos, last: from.last };
+    
     loop {
-        // A reader which calculates the checksum of what was read:
-        let mut r = sum::HashReader::new(reader);
-        
-        let l = r.read(&mut buf[0..16])?;
-        if l == 0 { break; /*end of file (EOF)*/ }
-        if l < 16 { r.read_exact(&mut buf[l..16])?; /*not EOF, buf haven't filled buffer*/ }
-        
-        let n_parents = if buf[0..6] == *b"COMMIT" {
-            1
-        } else if buf[0..5] == *b"MERGE" {
-            let n: u8 = buf[5];
-            if n < 2 { return ReadError::err("bad number of parents", pos, (5, 6)); }
-            n as usize
-        } else {
-            return ReadError::err("unexpected contents (expected COMMIT or MERGE)", pos, (0, 6));
-        };
-        if buf[6..8] != *b"\x00U" {
-            return ReadError::err("unexpected contents (expected \\x00U)", pos, (6, 8));
+        r.data.clear();
+        match read_commit(&mut r, &mut buf, &mut pos, format_ver) {
+            Ok(Some(commit)) => {
+                // read_commit leaves the commit's checksum in buf
+                last = LogPos { len: from.len + r.count, last: Some(Sum::load(&buf[0..SUM_BYTES])) };
+                if !receiver.receive(commit) { return Ok((last, None)); }
+            },
+            Ok(None) => return Ok((last, None)),
+            Err(e) => {
+                let e = tail_damage(e, &mut r, format_ver)?;
+                warn!("Commit log damaged after {} bytes: {}", last.len, e);
+                return Ok((last, Some(e)));
+            },
         }
-        let meta = read_meta(&mut r, &mut buf, &mut pos, format_ver)?;
-        
-        let mut parents = Vec::with_capacity(n_parents);
-        for _ in 0..n_parents {
-            r.read_exact(&mut buf[0..SUM_BYTES])?;
-            parents.push(Sum::load(&buf[0..SUM_BYTES]));
-            pos += SUM_BYTES;
+    }
+}
+
+/// Skip the part of a commit log read before, up to `pos` (as returned by
+/// `read_log_recover`), checking that the log still ends with the same commit
+/// there.
+/// 
+/// Returns false if the log is shorter or has a different commit at this
+/// position (e.g. because the file was replaced), in which case it should be
+/// read again from the start.
+pub fn skip_log(reader: &mut Read, pos: &LogPos) -> Result<bool> {
+    let len = match pos.last {
+        Some(_) if pos.len >= SUM_BYTES => pos.len - SUM_BYTES,
+        Some(_) => return Ok(false),
+        None => pos.len,
+    };
+    let skipped = io::copy(&mut reader.take(len as u64), &mut io::sink())?;
+    if skipped < len as u64 {
+        return Ok(false);
+    }
+    if let Some(ref last) = pos.last {
+        let mut buf = [0u8; SUM_BYTES];
+        if let Err(e) = reader.read_exact(&mut buf) {
+            return if e.kind() == io::ErrorKind::UnexpectedEof { Ok(false) } else { Err(Box::new(e)) };
         }
-        
-        r.read_exact(&mut buf[0..16])?;
-        if buf[0..8] != *b"ELEMENTS" {
-            return ReadError::err("unexpected contents (expected ELEMENTS)", pos, (0, 8));
+        return Ok(*last == buf[..]);
+    }
+    Ok(true)
+}
+
+// Reader counting bytes read and keeping a copy of those read since `data`
+// was last cleared, so that a damaged commit can be examined again
+struct Recorder<R> {
+    inner: R,
+    count: usize,
+    data: Vec<u8>,
+}
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len;
+        self.data.extend_from_slice(&buf[0..len]);
+        Ok(len)
+    }
+}
+
+// Check whether an error from reading a commit is damage at the end of the
+// log (returning the error) or should fail reading (returning `Err`). The
+// damaged commit starts at the beginning of `r.data`.
+fn tail_damage<R: Read>(e: Error, r: &mut Recorder<R>, format_ver: u32) -> Result<Error> {
+    match e.downcast_ref::<io::Error>().map(|e| e.kind()) {
+        Some(io::ErrorKind::UnexpectedEof) | None => {},
+        Some(_) => return Err(e),
+    }
+    // Truncated or corrupt data; only recoverable if no commit follows. A
+    // corrupt length may have caused more to be read than the damaged commit,
+    // so look for a commit anywhere after its start.
+    r.inner.read_to_end(&mut r.data)?;
+    let data = &r.data;
+    if (1..data.len()).any(|i| is_commit(&data[i..], format_ver)) {
+        warn!("Commit log corrupt with commits following: {}", e);
+        Err(e)
+    } else {
+        Ok(e)
+    }
+}
+
+// True if `data` starts with a complete, valid commit
+fn is_commit(data: &[u8], format_ver: u32) -> bool {
+    let tag = data.starts_with(b"COMMIT\x00U") ||
+            (data.starts_with(b"MERGE") && data.len() >= 8 && data[6..8] == *b"\x00U");
+    if !tag {
+        return false;
+    }
+    let mut r = sum::HashReader::new(data);
+    let mut buf = vec![0; 32];
+    let mut pos = 0;
+    match read_commit_head(&mut r, &mut buf, &mut pos, format_ver) {
+        Ok(Some((_, _, num_elts))) => {
+            (0..num_elts).all(|_| read_change(&mut r, &mut buf, &mut pos).is_ok()) &&
+                read_commit_end(&mut r, &mut buf, &mut pos).is_ok()
+        },
+        _ => false,
+    }
+}
+
+// Read the section identifier at the start of a commit log
+fn read_start(reader: &mut Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(&mut buf[0..16])?;
+    if buf[0..16] != *b"COMMIT LOG\x00\x00\x00\x00\x00\x00" {
+        return ReadError::err("unexpected contents (expected \
+            COMMIT LOG\\x00\\x00\\x00\\x00\\x00\\x00)", 0, (0, 16));
+    }
+    Ok(())
+}
+
+// Read a single commit. Returns `Ok(None)` on EOF before the commit.
+fn read_commit<E: Element>(reader: &mut Read, buf: &mut [u8], pos: &mut usize,
+        format_ver: u32) -> Result<Option<Commit<E>>>
+{
+    // A reader which calculates the checksum of what was read:
+    let mut r = sum::HashReader::new(reader);
+    
+    let (parents, meta, num_elts) = match read_commit_head(&mut r, buf, pos, format_ver)? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    
+    let mut changes = HashMap::new();
+    for _ in 0..num_elts {
+        let (elt_id, change) = match read_change(&mut r, buf, pos)? {
+            LogItem::Deletion(id) => (id, EltChange::deletion()),
+            LogItem::Insertion(id, data, elt_sum) =>
+                (id, EltChange::insertion(Arc::new(E::from_vec_sum(data, elt_sum.clone())?), elt_sum)),
+            LogItem::Replacement(id, data, elt_sum) =>
+                (id, EltChange::replacement(Arc::new(E::from_vec_sum(data, elt_sum.clone())?), elt_sum)),
+            _ => panic!("read_change returned unexpected item"),
+        };
+        changes.insert(elt_id, change);
+    }
+    
+    let commit_sum = read_commit_end(&mut r, buf, pos)?;
+    
+    trace!("Read commit ({} changes): {}; first parent: {}", changes.len(), commit_sum, parents[0]);
+    Ok(Some(Commit::new_explicit(commit_sum, parents, changes, meta)))
+}
+
+// Read the start of a commit: parents, metadata and number of changes.
+// Returns `Ok(None)` on EOF before the commit.
+fn read_commit_head<R: Read>(r: &mut sum::HashReader<R>, buf: &mut [u8], pos: &mut usize,
+        format_ver: u32) -> Result<Option<(Vec<Sum>, CommitMeta, usize)>>
+{
+    let l = r.read(&mut buf[0..16])?;
+    if l == 0 { return Ok(None); /*end of file (EOF)*/ }
+    if l < 16 { r.read_exact(&mut buf[l..16])?; /*not EOF, buf haven't filled buffer*/ }
+    let n_parents = if buf[0..6] == *b"COMMIT" {
+        1
+    } else if buf[0..5] == *b"MERGE" {
+        let n: u8 = buf[5];
+        if n < 2 { return ReadError::err("bad number of parents", *pos, (5, 6)); }
+        n as usize
+    } else {
+        return ReadError::err("unexpected contents (expected COMMIT or MERGE)", *pos, (0, 6));
+    };
+    if buf[6..8] != *b"\x00U" {
+        return ReadError::err("unexpected contents (expected \\x00U)", *pos, (6, 8));
+    }
+    let meta = read_meta(r, buf, pos, format_ver)?;
+    
+    let mut parents = Vec::with_capacity(n_parents);
+    for _ in 0..n_parents {
+        r.read_exact(&mut buf[0..SUM_BYTES])?;
+        parents.push(Sum::load(&buf[0..SUM_BYTES]));
+        *pos += SUM_BYTES;
+    }
+    
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..8] != *b"ELEMENTS" {
+        return ReadError::err("unexpected contents (expected ELEMENTS)", *pos, (0, 8));
+    }
+    let num_elts = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
+    *pos += 16;
+    
+    Ok(Some((parents, meta, num_elts)))
+}
+
+// Read one change of a commit, returned as `Deletion`, `Insertion` or
+// `Replacement`.
+fn read_change<R: Read>(r: &mut sum::HashReader<R>, buf: &mut [u8], pos: &mut usize)
+        -> Result<LogItem>
+{
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..4] != *b"ELT " {
+        return ReadError::err("unexpected contents (expected ELT\\x20)", *pos, (0, 4));
+    }
+    let elt_id = BigEndian::read_u64(&buf[8..16]).into();
+    let change_t = match &buf[4..8] {
+        b"DEL\x00" => { Change::Delete },
+        b"INS\x00" => { Change::Insert },
+        b"REPL" => { Change::Replace },
+        _ => {
+            return ReadError::err("unexpected contents (expected one \
+                of DEL\\x00, INS\\x00, REPL)", *pos, (4, 8));
         }
-        let num_elts = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
-        pos += 16;
-        
-        let mut changes = HashMap::new();
-        
-        for _ in 0..num_elts {
+    };
+    *pos += 16;
+    
+    return Ok(match change_t {
+        Change::Delete => LogItem::Deletion(elt_id),
+        Change::Insert | Change::Replace => {
             r.read_exact(&mut buf[0..16])?;
-            if buf[0..4] != *b"ELT " {
-                return ReadError::err("unexpected contents (expected ELT\\x20)", pos, (0, 4));
+            if buf[0..8] != *b"ELT DATA" {
+                return ReadError::err("unexpected contents (expected ELT DATA)", *pos, (0, 8));
             }
-            let elt_id = BigEndian::read_u64(&buf[8..16]).into();
-            let change_t = match &buf[4..8] {
-                b"DEL\x00" => { Change::Delete },
-                b"INS\x00" => { Change::Insert },
-                b"REPL" => { Change::Replace },
-                _ => {
-                    return ReadError::err("unexpected contents (expected one \
-                        of DEL\\x00, INS\\x00, REPL)", pos, (4, 8));
-                }
-            };
-            pos += 16;
+            let data_len = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
+            *pos += 16;
             
-            let change = match change_t {
-                Change::Delete => EltChange::deletion(),
-                Change::Insert | Change::Replace => {
-                    r.read_exact(&mut buf[0..16])?;
-                    if buf[0..8] != *b"ELT DATA" {
-                        return ReadError::err("unexpected contents (expected ELT DATA)", pos, (0, 8));
-                    }
-                    let data_len = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
-                    pos += 16;
-                    
-                    let mut data = vec![0; data_len];
-                    r.read_exact(&mut data)?;
-                    pos += data_len;
-                    
-                    let pad_len = 16 * ((data_len + 15) / 16) - data_len;
-                    if pad_len > 0 {
-                        r.read_exact(&mut buf[0..pad_len])?;
-                        pos += pad_len;
-                    }
-                    
-                    let elt_sum = Sum::elt_sum(elt_id, &data);
-                    r.read_exact(&mut buf[0..SUM_BYTES])?;
-                    if elt_sum != buf[0..SUM_BYTES] {
-                        return ReadError::err("element checksum mismatch", pos, (0, SUM_BYTES));
-                    }
-                    pos += SUM_BYTES;
-                    
-                    let elt = Rc::new(E::from_vec_sum(data, elt_sum)?);
-                    match change_t {
-                        Change::Insert => EltChange::insertion(elt),
-                        Change::Replace => EltChange::replacement(elt),
-                        _ => panic!()
-                    }
-                },
-            };
-            changes.insert(elt_id, change);
-        }
-        
-        r.read_exact(&mut buf[0..SUM_BYTES])?;
-        let commit_sum = Sum::load(&buf[0..SUM_BYTES]);
-        pos += SUM_BYTES;
-        
-        let sum = r.sum();
-        reader = r.into_inner();
-        reader.read_exact(&mut buf[0..SUM_BYTES])?;
-        if sum != buf[0..SUM_BYTES] {
-            return ReadError::err("checksum invalid", pos, (0, SUM_BYTES));
-        }
-        
-        trace!("Read commit ({} changes): {}; first parent: {}", changes.len(), commit_sum, parents[0]);
-        let cont = receiver.receive(Commit::new_explicit(commit_sum, parents, changes, meta));
-        if !cont { break; }
-    }
+            let mut data = vec![0; data_len];
+            r.read_exact(&mut data)?;
+            *pos += data_len;
+            
+            let pad_len = 16 * ((data_len + 15) / 16) - data_len;
+            if pad_len > 0 {
+                r.read_exact(&mut buf[0..pad_len])?;
+                *pos += pad_len;
+            }
+            
+            let elt_sum = Sum::elt_sum(elt_id, &data);
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            if elt_sum != buf[0..SUM_BYTES] {
+                return ReadError::err("element checksum mismatch", *pos, (0, SUM_BYTES));
+            }
+            *pos += SUM_BYTES;
+            
+            if change_t == Change::Insert {
+                LogItem::Insertion(elt_id, data, elt_sum)
+            } else {
+                LogItem::Replacement(elt_id, data, elt_sum)
+            }
+        },
+    });
     
     #[derive(Eq, PartialEq, Copy, Clone, Debug)]
     enum Change {
         Delete, Insert, Replace
     }
+}
+
+// Read the end of a commit, verify the commit's checksum and return its state
+// sum.
+fn read_commit_end<R: Read>(r: &mut sum::HashReader<R>, buf: &mut [u8], pos: &mut usize)
+        -> Result<Sum>
+{
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    let commit_sum = Sum::load(&buf[0..SUM_BYTES]);
+    *pos += SUM_BYTES;
     
-    Ok(())
+    let sum = r.partial_sum();
+    r.inner().read_exact(&mut buf[0..SUM_BYTES])?;
+    if sum != buf[0..SUM_BYTES] {
+        return ReadError::err("checksum invalid", *pos, (0, SUM_BYTES));
+    }
+    *pos += SUM_BYTES;
+    Ok(commit_sum)
+}
+
+/// An item read by `LogReader`.
+#[derive(Debug, PartialEq)]
+pub enum LogItem {
+    /// Start of a commit: state sums of its parents (the first being the
+    /// state it is a diff against), metadata and the number of changes
+    /// following.
+    Commit(Vec<Sum>, CommitMeta, usize),
+    /// The current commit deletes this element
+    Deletion(EltId),
+    /// The current commit inserts an element: identifier, data and checksum
+    Insertion(EltId, Vec<u8>, Sum),
+    /// The current commit replaces an element: identifier, data and checksum
+    Replacement(EltId, Vec<u8>, Sum),
+    /// End of the current commit; this is the commit's state sum.
+    End(Sum),
+}
+
+/// Reads a commit log from a stream one item at a time, without creating
+/// commits or elements.
+/// 
+/// For each commit this yields a `LogItem::Commit`, then one item per change,
+/// then `LogItem::End`. Element checksums are verified as each change is read;
+/// the checksum of the commit as a whole is verified before `End` is yielded,
+/// thus items of a commit should not be trusted until its `End` item has been
+/// read. Memory use is bounded by the largest element. Iteration stops at the
+/// end of the log or after the first error.
+pub struct LogReader<'a> {
+    r: sum::HashReader<&'a mut Read>,
+    buf: Vec<u8>,
+    pos: usize,
+    format_ver: u32,
+    // changes remaining in the current commit, if within a commit
+    remaining: Option<usize>,
+    done: bool,
+}
+impl<'a> LogReader<'a> {
+    /// Read the start of a commit log. Pass the stream after the file header
+    /// and the file version, as for `read_log`.
+    pub fn new(reader: &'a mut Read, format_ver: u32) -> Result<LogReader<'a>> {
+        let mut buf = vec![0; 32];
+        read_start(reader, &mut buf)?;
+        Ok(LogReader {
+            r: sum::HashReader::new(reader),
+            buf: buf,
+            pos: 16,
+            format_ver: format_ver,
+            remaining: None,
+            done: false,
+        })
+    }
+    
+    fn read_item(&mut self) -> Result<Option<LogItem>> {
+        let (r, buf, pos) = (&mut self.r, &mut self.buf, &mut self.pos);
+        Ok(match self.remaining {
+            None => {
+                r.reset();
+                match read_commit_head(r, buf, pos, self.format_ver)? {
+                    Some((parents, meta, n)) => {
+                        self.remaining = Some(n);
+                        Some(LogItem::Commit(parents, meta, n))
+                    },
+                    None => None,
+                }
+            },
+            Some(0) => {
+                self.remaining = None;
+                Some(LogItem::End(read_commit_end(r, buf, pos)?))
+            },
+            Some(n) => {
+                self.remaining = Some(n - 1);
+                Some(read_change(r, buf, pos)?)
+            },
+        })
+    }
+}
+impl<'a> Iterator for LogReader<'a> {
+    type Item = Result<LogItem>;
+    fn next(&mut self) -> Option<Result<LogItem>> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_item();
+        match result {
+            Ok(Some(item)) => Some(Ok(item)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
 }
 
 /// Write the section identifier at the start of a commit log
@@ -216,8 +501,8 @@ pub fn write_commit<E: Element>(commit: &Commit<E>, writer: &mut Write) -> Resul
         let change = commit.change(*elt_id).expect("get change");
         let marker = match *change {
             EltChange::Deletion => b"ELT DEL\x00",
-            EltChange::Insertion(_) => b"ELT INS\x00",
-            EltChange::Replacement(_) => b"ELT REPL",
+            EltChange::Insertion(..) => b"ELT INS\x00",
+            EltChange::Replacement(..) => b"ELT REPL",
         };
         w.write_all(marker)?;
         w.write_u64::<BigEndian>((*elt_id).into())?;
@@ -234,7 +519,7 @@ pub fn write_commit<E: Element>(commit: &Commit<E>, writer: &mut Write) -> Resul
                 w.write_all(&padding[0..pad_len])?;
             }
             
-            elt.sum(*elt_id).write_to(&mut w)?;
+            Sum::elt_sum(*elt_id, &elt_buf).write_to(&mut w)?;
         }
     }
     
@@ -264,17 +549,27 @@ fn commit_write_read(){
     v = (1u8..).map(|x| x.wrapping_mul(x).wrapping_add(5u8.wrapping_mul(x)).wrapping_add(11u8)).take(SUM_BYTES).collect();
     let quadr = Sum::load(&v);
     
+    let elt = |id: u64, s: &str| {
+        let elt = Arc::new(s.to_string());
+        let sum = elt.sum(EltId::from(id));
+        (elt, sum)
+    };
     let mut changes = HashMap::new();
-    changes.insert(EltId::from(3), EltChange::insertion(Rc::new("three".to_string())));
-    changes.insert(EltId::from(4), EltChange::insertion(Rc::new("four".to_string())));
-    changes.insert(EltId::from(5), EltChange::insertion(Rc::new("five".to_string())));
+    let (three, sum) = elt(3, "three");
+    changes.insert(EltId::from(3), EltChange::insertion(three, sum));
+    let (four, sum) = elt(4, "four");
+    changes.insert(EltId::from(4), EltChange::insertion(four, sum));
+    let (five, sum) = elt(5, "five");
+    changes.insert(EltId::from(5), EltChange::insertion(five, sum));
     let meta1 = CommitMeta::new_explicit(1, 123456, MetaFlags::zero(), vec![], UserMeta::None).expect("new meta");
     let commit_1 = Commit::new_explicit(seq, vec![squares], changes, meta1);
     
     changes = HashMap::new();
     changes.insert(EltId::from(1), EltChange::deletion());
-    changes.insert(EltId::from(9), EltChange::replacement(Rc::new("NINE!".to_string())));
-    changes.insert(EltId::from(5), EltChange::insertion(Rc::new("five again?".to_string())));
+    let (nine, sum) = elt(9, "NINE!");
+    changes.insert(EltId::from(9), EltChange::replacement(nine, sum));
+    let (five, sum) = elt(5, "five again?");
+    changes.insert(EltId::from(5), EltChange::insertion(five, sum));
     let meta2 = CommitMeta::new_explicit(1, 321654, MetaFlags::zero(), vec![], UserMeta::Text("123".to_string())).expect("new meta");
     let commit_2 = Commit::new_explicit(nonsense, vec![quadr], changes, meta2);
     
@@ -296,4 +591,26 @@ fn commit_write_read(){
     assert_eq!(commits.len(), 2);
     assert_eq!(commits[0], commit_1);
     assert_eq!(commits[1], commit_2);
+    
+    let mut r = &obj[..];
+    let items = LogReader::new(&mut r, HEAD_VERSIONS[HEAD_VERSIONS.len() - 1]).expect("start")
+        .collect::<Result<Vec<_>>>().expect("read items");
+    assert_eq!(items.len(), 10);
+    assert_eq!(items[0], LogItem::Commit(commit_1.parents().to_vec(), commit_1.meta().clone(), 3));
+    assert_eq!(items[4], LogItem::End(commit_1.statesum().clone()));
+    assert!(items.contains(&LogItem::Deletion(EltId::from(1))));
+    let nine = b"NINE!".to_vec();
+    let nine_sum = Sum::elt_sum(EltId::from(9), &nine);
+    assert!(items.contains(&LogItem::Replacement(EltId::from(9), nine, nine_sum)));
+    assert_eq!(items[9], LogItem::End(commit_2.statesum().clone()));
+    
+    // Corruption is detected, at the latest at the end of the commit:
+    let mut obj2 = obj.clone();
+    let len = obj2.len();
+    obj2[len - 1] ^= 1;
+    let mut r = &obj2[..];
+    let items: Vec<_> = LogReader::new(&mut r, HEAD_VERSIONS[HEAD_VERSIONS.len() - 1])
+        .expect("start").collect();
+    assert_eq!(items.len(), 10);
+    assert!(items[9].is_err());
 }
@@ -10,18 +10,22 @@ use std::result::Result as stdResult;
 
 use error::{Result, ArgError, ReadError, make_io_err};
 use rw::{HEAD_VERSIONS, sum};
-use sum::SUM_BYTES;
+use sum::{Sum, SUM_BYTES};
 use util::rtrim;
 
 // Snapshot header. This is the latest version.
-const HEAD_SNAPSHOT : [u8; 16] = *b"PIPPINSS20160815";
+const HEAD_SNAPSHOT : [u8; 16] = *b"PIPPINSS20160919";
 // Commit log header. This is the latest version.
 const HEAD_COMMITLOG : [u8; 16] = *b"PIPPINCL20160815";
+// Bundle header. This is the latest version.
+const HEAD_BUNDLE : [u8; 16] = *b"PIPPINBN20160815";
 
 const SUM_SHA256 : [u8; 16] = *b"HSUM SHA-2 256\x00\x00";
 const SUM_BLAKE2_16 : [u8; 16] = *b"HSUM BLAKE2 16\x00\x00";
 const PARTID : [u8; 8] = *b"HPARTID ";
 const CLASS_RANGE : [u8; 4] = *b"HCSF";
+// Start of the shallow boundary block (inessential: readers may ignore it)
+const SHALLOW : [u8; 8] = *b"shallow\x00";
 
 /// File type and version.
 /// 
@@ -34,13 +38,15 @@ pub enum FileType {
     Snapshot(u32),
     /// File is a commit log
     CommitLog(u32),
+    /// File is a bundle of commits (see `rw::bundle`)
+    Bundle(u32),
 }
 impl FileType {
     /// Extract the version number regardless of file type (should be one of
     /// the HEAD_VERSIONS numbers or zero).
     pub fn ver(&self) -> u32 {
         match *self {
-            FileType::Snapshot(v) | FileType::CommitLog(v) => v,
+            FileType::Snapshot(v) | FileType::CommitLog(v) | FileType::Bundle(v) => v,
         }
     }
 }
@@ -59,12 +65,15 @@ pub enum UserData {
 
 /// Information stored in a file header
 pub struct FileHeader {
-    /// File type: snapshot or log file.
+    /// File type: snapshot, log file or bundle.
     pub ftype: FileType,
     /// Repo name. Always present.
     pub name: String,
     /// User data fields, remarks, etc.
     pub user: Vec<UserData>,
+    /// Shallow boundary: states whose parents were intentionally not copied
+    /// (see `Partition::shallow_boundary`). Usually empty.
+    pub shallow: Vec<Sum>,
 }
 
 // Decodes from a string to the format used in HEAD_VERSIONS. Returns zero on
@@ -107,6 +116,8 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
         FileType::Snapshot(head_version)
     } else if buf[0..8] == HEAD_COMMITLOG[0..8] {
         FileType::CommitLog(head_version)
+    } else if buf[0..8] == HEAD_BUNDLE[0..8] {
+        FileType::Bundle(head_version)
     } else {
         return ReadError::err("not a known Pippin file format", pos, (0, 16));
     };
@@ -120,6 +131,7 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
     pos += 16;
     
     let mut user_fields = Vec::new();
+    let mut shallow = Vec::new();
     loop {
         r.read_exact(&mut buf[0..16])?;
         let (block, off): (&[u8], usize) = if buf[0] == b'H' {
@@ -164,6 +176,13 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
             // ignore; feature removed
         } else if block[0..3] == CLASS_RANGE[1..] {
             // ignore; feature removed
+        } else if block.len() >= 8 && block[0..8] == SHALLOW {
+            if (block.len() - 8) % SUM_BYTES != 0 {
+                return ReadError::err("shallow boundary has invalid length", pos, (off, off+block.len()));
+            }
+            for sum in block[8..].chunks(SUM_BYTES) {
+                shallow.push(Sum::load(sum));
+            }
         } else if block[0] == b'R' {
             user_fields.push(UserData::Text(String::from_utf8(rtrim(&block[1..], 0).to_vec())?));
         } else if block[0] == b'U' {
@@ -197,6 +216,7 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
         ftype: ftype,
         name: repo_name,
         user: user_fields,
+        shallow: shallow,
     })
 }
 
@@ -213,6 +233,9 @@ pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
         FileType::CommitLog(_) => {
             w.write_all(&HEAD_COMMITLOG)?;
         },
+        FileType::Bundle(_) => {
+            w.write_all(&HEAD_BUNDLE)?;
+        },
     };
     validate_repo_name(&header.name)?;
     w.write_all(header.name.as_bytes())?;
@@ -250,6 +273,19 @@ pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
         }
     }
     
+    if !header.shallow.is_empty() {
+        let len = 4 + SHALLOW.len() + header.shallow.len() * SUM_BYTES;
+        if len >= 1 << 24 {
+            return ArgError::err("shallow boundary too long");
+        }
+        w.write_all(&[b'B', (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+        w.write_all(&SHALLOW)?;
+        for sum in &header.shallow {
+            sum.write_to(&mut w)?;
+        }
+        pad(&mut w, ((len + 15) / 16) * 16 - len)?;
+    }
+    
     w.write_all(&SUM_BLAKE2_16)?;
     
     // Write the checksum of everything above:
@@ -315,11 +351,12 @@ fn write_header() {
             UserData::Data(b"0123456789abcdefghijklmnopqrs".to_vec()),
             UserData::Data(b" rsei noasr auyv 10()% xovn".to_vec()),
         ],
+        shallow: vec![],
     };
     let mut buf = Vec::new();
     write_head(&header, &mut buf).unwrap();
     
-    let head_bytes = b"PIPPINSS20160815\
+    let head_bytes = b"PIPPINSS20160919\
             \xc3\x84hnliche Unsinn\
             HRRemark \xcf\x89\x00\x00\x00\x00\x00\
             Q2R Quatsch Quatsch \
@@ -329,7 +366,7 @@ fn write_header() {
             B\x00\x00\x20U rsei noasr a\
             uyv 10()% xovn\
             HSUM BLAKE2 16\x00\x00\
-            \xdd0N\"\xceb#\x0d\x88\xa1\x19\xceu\xdd\x0e\xdej\xc8\xea|\xc3\xb3q\xdaO\x92)u\xa1\xf6\xe9\x01";
+            \xcfz@J\x0b\x06\xe7N\x05C\xacN\x16\x92\x17\x94\x17c\xfaL\xcc\xb1%\x90?\xc1\x93VO*QW";
     use ::util::ByteFormatter;
     println!("Checksum: '{}'", ByteFormatter::from(&buf[buf.len()-SUM_BYTES..buf.len()]));
     println!("(Replace last line of head_bytes with new checksum.)");
@@ -339,3 +376,22 @@ fn write_header() {
         assert!(false);
     }
 }
+
+#[test]
+fn shallow_boundary() {
+    use sum::Sum;
+    let header = FileHeader {
+        ftype: FileType::CommitLog(0),
+        name: "shallow".to_string(),
+        user: vec![UserData::Text("Remark".to_string())],
+        shallow: vec![Sum::calculate(b"one"), Sum::calculate(b"two")],
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert_eq!(buf.len() % 16, 0);
+    
+    let header2 = read_head(&mut &buf[..]).unwrap();
+    assert_eq!(header2.name, header.name);
+    assert_eq!(header2.user, header.user);
+    assert_eq!(header2.shallow, header.shallow);
+}
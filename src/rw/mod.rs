@@ -10,6 +10,7 @@ mod sum;
 pub mod header;
 pub mod snapshot;
 pub mod commitlog;
+pub mod bundle;
 
 use std::io::{Read, Write};
 use std::iter::repeat;
@@ -29,7 +30,7 @@ use error::{Result, ReadError};
 // Note: new versions can be implemented just by updating the three HEAD_...
 // constants and updating code, so long as the code will still read old
 // versions. The file format documentation should also be updated.
-const HEAD_VERSIONS : [u32; 3] = [
+const HEAD_VERSIONS : [u32; 4] = [
     /* unsupported versions:
     2015_09_29, // initial standardisation
     2016_01_05, // add 'PARTID' to header blocks (snapshot only)
@@ -41,6 +42,7 @@ const HEAD_VERSIONS : [u32; 3] = [
     2016_03_10, // new element and state sums break compatibility
     2016_05_16, // support Bbbb header sections
     2016_08_15, // allow non-breaking extensions to commit-meta
+    2016_09_19, // add element index (snapshots only)
 ];
 
 /// Read metadata
@@ -4,18 +4,44 @@
 
 //! Support for reading and writing Rust snapshots
 
+use std::borrow::Cow;
 use std::io::{Read, Write};
-use std::rc::Rc;
+use std::mem::replace;
+use std::sync::Arc;
+use std::result;
+use std::sync::{mpsc, Mutex};
+use std::thread;
 use std::{u8, u32};
 use std::collections::hash_map::{HashMap, Entry};
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
-use elt::Element;
-use error::{Result, ReadError, ElementOp, OtherError};
+use commit::CommitMeta;
+use elt::{Element, EltId};
+use error::{Result, Error, ArgError, ReadError, ElementOp, OtherError};
+use io::EltSource;
 use rw::{sum, read_meta, write_meta};
 use state::{PartState, StateRead};
 use sum::{Sum, SUM_BYTES};
+use util::CountingReader;
+
+// Version from which snapshots include an element index (see HEAD_VERSIONS).
+const INDEX_VERSION: u32 = 2016_09_19;
+
+/// True if snapshots of this file version include an element index, and thus
+/// can be read with `read_snapshot_lazy`.
+pub fn has_index(format_ver: u32) -> bool {
+    format_ver >= INDEX_VERSION
+}
+
+// Entry of the element index: identifier, position of data relative to the
+// `ELEMENTS` identifier, length of data and element sum.
+type IndexEntry = (EltId, u64, usize, Sum);
+
+// Round up to a multiple of 16
+fn pad16(len: usize) -> usize {
+    16 * ((len + 15) / 16)
+}
 
 /// Read a snapshot of a set of elements from a stream.
 /// 
@@ -29,115 +55,543 @@ pub fn read_snapshot<T: Element>(reader: &mut Read,
         format_ver: u32) -> Result<PartState<T>>
 {
     // A reader which calculates the checksum of what was read:
-    let mut r = sum::HashReader::new(reader);
+    read_body(sum::HashReader::new(reader), format_ver)
+}
+
+/// Read a snapshot from a buffer, usually a memory-mapped file (see
+/// `RepoIO::map_ss`).
+/// 
+/// This is equivalent to `read_snapshot`, except that element data is not
+/// copied into temporary buffers: each element is created directly from
+/// `data` with `Element::from_slice_sum`.
+pub fn read_snapshot_slice<T: Element>(data: &[u8], format_ver: u32) -> Result<PartState<T>> {
+    read_body(sum::HashReader::new(data), format_ver)
+}
+
+// Source of element data: either copied into a new buffer or borrowed.
+trait EltData<'a>: Read + Sized {
+    fn read_data(r: &mut sum::HashReader<Self>, len: usize) -> Result<Cow<'a, [u8]>>;
+}
+impl<'a, 'r> EltData<'a> for &'r mut Read {
+    fn read_data(r: &mut sum::HashReader<Self>, len: usize) -> Result<Cow<'a, [u8]>> {
+        let mut data = vec![0; len];
+        r.read_exact(&mut data)?;
+        Ok(Cow::Owned(data))
+    }
+}
+impl<'a> EltData<'a> for &'a [u8] {
+    fn read_data(r: &mut sum::HashReader<Self>, len: usize) -> Result<Cow<'a, [u8]>> {
+        Ok(Cow::Borrowed(r.read_slice(len)?))
+    }
+}
+
+fn read_body<'a, T: Element, R: EltData<'a>>(r: sum::HashReader<R>,
+        format_ver: u32) -> Result<PartState<T>>
+{
+    let mut body = Body::new(r, format_ver, true)?;
+    let mut elts = HashMap::new();
+    while let Some((ident, data, elt_sum)) = body.next_elt()? {
+        let elt = match data {
+            Cow::Owned(data) => T::from_vec_sum(data, elt_sum.clone())?,
+            Cow::Borrowed(data) => T::from_slice_sum(data, elt_sum.clone())?,
+        };
+        match elts.entry(ident) {
+            Entry::Occupied(_) => { return Err(Box::new(ElementOp::IdClash)); },
+            Entry::Vacant(e) => e.insert((Arc::new(elt), elt_sum)),
+        };
+    }
     
-    let mut pos: usize = 0;
-    let mut buf = vec![0; 32];
-    assert!(buf.len() >= SUM_BYTES);
+    // The state sum was verified by `next_elt`.
+    let state = PartState::new_explicit(body.parents,
+            elts, body.meta, body.combined_elt_sum);
+    trace!("Read snapshot (with {} elements): {}", body.num_elts, state.statesum());
+    Ok(state)
+}
+
+/// Read a snapshot as `read_snapshot`, verifying element checksums and
+/// decoding elements on `threads` worker threads.
+/// 
+/// Data is read (and the checksum of the whole snapshot calculated) on the
+/// calling thread while workers process elements in batches. The result is
+/// identical to that of `read_snapshot`, though if several errors are present
+/// a different one may be reported. With `threads` at most 1 this simply
+/// calls `read_snapshot`.
+pub fn read_snapshot_parallel<T: Element+Send>(reader: &mut Read, format_ver: u32,
+        threads: usize) -> Result<PartState<T>>
+{
+    if threads <= 1 {
+        return read_snapshot(reader, format_ver);
+    }
+    let mut body = Body::new(sum::HashReader::new(reader), format_ver, true)?;
     
-    r.read_exact(&mut buf[0..16])?;
-    if buf[0..6] != *b"SNAPSH" || buf[7] != b'U' {
-        return ReadError::err("unexpected contents (expected SNAPSH_U where _ is any)", pos, (0, 8));
+    const STOPPED: &'static str = "snapshot reader: worker threads stopped";
+    // The work queue is held only by workers, so that sending fails (rather
+    // than blocking) should all workers stop.
+    let (work_tx, work_rx) = mpsc::sync_channel::<Vec<RawElt>>(2 * threads);
+    let work_rx = Arc::new(Mutex::new(work_rx));
+    let (result_tx, result_rx) = mpsc::channel();
+    
+    let (read_result, elts, panicked) = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|_| {
+            let (work_rx, result_tx) = (work_rx.clone(), result_tx.clone());
+            scope.spawn(move || {
+                loop {
+                    let batch = match work_rx.lock().expect("lock work queue").recv() {
+                        Ok(batch) => batch,
+                        Err(_) => break,    // reading finished
+                    };
+                    if result_tx.send(decode_batch::<T>(batch)).is_err() {
+                        break;
+                    }
+                }
+            })
+        }).collect();
+        drop(work_rx);
+        drop(result_tx);
+        
+        // Read on this thread, passing batches of roughly BATCH_BYTES to workers:
+        const BATCH_BYTES: usize = 1 << 16;
+        let read_result = (|| -> Result<()> {
+            let mut batch = Vec::new();
+            let mut batch_bytes = 0;
+            while let Some((ident, data, elt_sum)) = body.next_raw()? {
+                batch_bytes += data.len();
+                batch.push((body.pos - SUM_BYTES, ident, data.into_owned(), elt_sum));
+                if batch_bytes >= BATCH_BYTES {
+                    work_tx.send(replace(&mut batch, Vec::new())).map_err(|_| OtherError::new(STOPPED))?;
+                    batch_bytes = 0;
+                }
+            }
+            if !batch.is_empty() {
+                work_tx.send(batch).map_err(|_| OtherError::new(STOPPED))?;
+            }
+            Ok(())
+        })();
+        drop(work_tx);
+        
+        let mut elts: Result<HashMap<EltId, (Arc<T>, Sum)>> = Ok(HashMap::new());
+        for result in result_rx {
+            match (&mut elts, result) {
+                (&mut Ok(ref mut map), Ok(decoded)) => {
+                    let clash = decoded.into_iter().any(|(ident, elt, elt_sum)| {
+                        map.insert(ident, (Arc::new(elt), elt_sum)).is_some()
+                    });
+                    if clash {
+                        elts = Err(Box::new(ElementOp::IdClash));
+                    }
+                },
+                (&mut Ok(_), Err((pos, msg))) => {
+                    elts = Err(Box::new(match msg {
+                        None => ReadError::new("element checksum mismatch", pos, (0, SUM_BYTES)),
+                        Some(msg) => ReadError::new_wrap(Error::from(msg), pos, (0, 0)),
+                    }));
+                },
+                (&mut Err(_), _) => {},
+            }
+        }
+        // Join explicitly, since otherwise a panic would be propagated:
+        let panicked = workers.into_iter().fold(false, |p, w| w.join().is_err() || p);
+        (read_result, elts, panicked)
+    });
+    if panicked {
+        return OtherError::err("snapshot reader: worker thread panicked");
     }
-    let num_parents = buf[6] as usize;
-    let meta = read_meta(&mut r, &mut buf, &mut pos, format_ver)?;
+    read_result?;
+    let elts = elts?;
     
-    let mut parents = Vec::with_capacity(num_parents);
-    for _ in 0..num_parents {
-        r.read_exact(&mut buf[0..SUM_BYTES])?;
-        parents.push(Sum::load(&buf[0..SUM_BYTES]));
+    // The state sum was verified by `next_raw`.
+    let state = PartState::new_explicit(body.parents,
+            elts, body.meta, body.combined_elt_sum);
+    trace!("Read snapshot (with {} elements, {} threads): {}", body.num_elts, threads,
+            state.statesum());
+    Ok(state)
+}
+
+// Element read but not yet verified: position of checksum, identifier, data
+// and checksum.
+type RawElt = (usize, EltId, Vec<u8>, Sum);
+
+// Verify and decode a batch of elements. On failure, returns the position and,
+// unless the checksum is at fault, an error message (errors themselves cannot
+// be passed between threads).
+fn decode_batch<T: Element>(batch: Vec<RawElt>) -> result::Result<Vec<(EltId, T, Sum)>, (usize, Option<String>)> {
+    let mut decoded = Vec::with_capacity(batch.len());
+    for (pos, ident, data, elt_sum) in batch {
+        if Sum::elt_sum(ident, &data) != elt_sum {
+            return Err((pos, None));
+        }
+        match T::from_vec_sum(data, elt_sum.clone()) {
+            Ok(elt) => decoded.push((ident, elt, elt_sum)),
+            Err(e) => return Err((pos, Some(e.to_string()))),
+        }
+    }
+    Ok(decoded)
+}
+
+/// Reads a snapshot from a stream one element at a time, without building a
+/// `PartState`.
+/// 
+/// Start and end of the snapshot and each element's checksum are verified as
+/// read; memory use is bounded by the largest element. The iterator yields
+/// each element's identifier, data and checksum, in file order. After the
+/// last element the state sum and the checksum of the whole snapshot are
+/// verified; iteration is only successful if the final item is not an error.
+/// Iteration stops after the first error.
+/// 
+/// Pass the stream after the file header and the file version, as for
+/// `read_snapshot`.
+pub struct SnapshotReader<'a> {
+    body: Body<&'a mut Read>,
+}
+impl<'a> SnapshotReader<'a> {
+    /// Read the start of a snapshot, up to its elements.
+    pub fn new(reader: &'a mut Read, format_ver: u32) -> Result<SnapshotReader<'a>> {
+        let body = Body::new(sum::HashReader::new(reader), format_ver, false)?;
+        Ok(SnapshotReader { body: body })
+    }
+    /// Get the state sums of the snapshot's parents
+    pub fn parents(&self) -> &[Sum] {
+        &self.body.parents
+    }
+    /// Get the state's metadata
+    pub fn meta(&self) -> &CommitMeta {
+        &self.body.meta
+    }
+    /// Get the number of elements in the snapshot
+    pub fn num_elts(&self) -> usize {
+        self.body.num_elts
+    }
+    /// Get the (verified) state sum. Available only once all elements have
+    /// been read successfully.
+    pub fn statesum(&self) -> Option<&Sum> {
+        self.body.statesum.as_ref()
+    }
+}
+impl<'a> Iterator for SnapshotReader<'a> {
+    type Item = Result<(EltId, Vec<u8>, Sum)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.body.next_elt() {
+            Ok(Some((ident, data, elt_sum))) => Some(Ok((ident, data.into_owned(), elt_sum))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+// Reads the part of a snapshot following the file header.
+struct Body<R> {
+    r: sum::HashReader<R>,
+    buf: Vec<u8>,
+    pos: usize,
+    parents: Vec<Sum>,
+    meta: CommitMeta,
+    num_elts: usize,
+    index: Option<Vec<IndexEntry>>,
+    // number of elements read
+    n: usize,
+    // position relative to ELEMENTS
+    rel_pos: u64,
+    combined_elt_sum: Sum,
+    // set when the end has been read and verified
+    statesum: Option<Sum>,
+    // set when reading is finished, or has failed
+    done: bool,
+}
+impl<'a, R: EltData<'a>> Body<R> {
+    // Read up to the first element. If `keep_index`, elements are checked
+    // against the index (if any); otherwise the index is verified but not
+    // held in memory.
+    fn new(mut r: sum::HashReader<R>, format_ver: u32, keep_index: bool) -> Result<Body<R>> {
+        let mut pos: usize = 0;
+        let mut buf = vec![0; 32];
+        assert!(buf.len() >= SUM_BYTES);
+        
+        let (parents, meta) = read_start(&mut r, &mut buf, &mut pos, format_ver)?;
+        let index = if has_index(format_ver) {
+            let (num, index, _) = read_index(&mut r, &mut buf, &mut pos, keep_index)?;
+            Some((num, index))
+        } else {
+            None
+        };
+        
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0..8] != *b"ELEMENTS" {
+            return ReadError::err("unexpected contents (expected ELEMENTS)", pos, (0, 8));
+        }
+        let num_elts = BigEndian::read_u64(&buf[8..16]) as usize;    // #0015
+        pos += 16;
+        if index.as_ref().map_or(false, |&(num, _)| num != num_elts) {
+            return ReadError::err("unexpected contents (number of elements \
+                differs from index)", pos, (8, 16));
+        }
+        
+        Ok(Body {
+            r: r,
+            buf: buf,
+            pos: pos,
+            parents: parents,
+            meta: meta,
+            num_elts: num_elts,
+            index: index.and_then(|(_, index)| if keep_index { Some(index) } else { None }),
+            n: 0,
+            rel_pos: 16,
+            combined_elt_sum: Sum::zero(),
+            statesum: None,
+            done: false,
+        })
     }
     
-    r.read_exact(&mut buf[0..16])?;
-    if buf[0..8] != *b"ELEMENTS" {
-        return ReadError::err("unexpected contents (expected ELEMENTS)", pos, (0, 8));
+    // Read the next element, or if there are no more, read and verify the
+    // end of the snapshot and return `None`.
+    fn next_elt(&mut self) -> Result<Option<(EltId, Cow<'a, [u8]>, Sum)>> {
+        let result = self.next_raw().and_then(|opt| match opt {
+            Some((ident, data, elt_sum)) => {
+                if Sum::elt_sum(ident, &data) != elt_sum {
+                    return ReadError::err("element checksum mismatch",
+                            self.pos - SUM_BYTES, (0, SUM_BYTES));
+                }
+                Ok(Some((ident, data, elt_sum)))
+            },
+            None => Ok(None),
+        });
+        if result.is_err() {
+            self.done = true;
+        }
+        result
     }
-    let num_elts = BigEndian::read_u64(&buf[8..16]) as usize;    // #0015
-    pos += 16;
     
-    let mut elts = HashMap::new();
-    let mut combined_elt_sum = Sum::zero();
-    for _ in 0..num_elts {
+    // As `next_elt`, but without verifying the element checksum: the sum
+    // returned is that read from the file, which ends at `self.pos`.
+    fn next_raw(&mut self) -> Result<Option<(EltId, Cow<'a, [u8]>, Sum)>> {
+        if self.done {
+            return Ok(None);
+        }
+        let result = if self.n < self.num_elts {
+            self.read_elt().map(|elt| Some(elt))
+        } else {
+            self.read_end().map(|_| None)
+        };
+        if !result.as_ref().map_or(false, |x| x.is_some()) {
+            self.done = true;
+        }
+        result
+    }
+    
+    fn read_elt(&mut self) -> Result<(EltId, Cow<'a, [u8]>, Sum)> {
+        let (r, buf) = (&mut self.r, &mut self.buf);
         r.read_exact(&mut buf[0..32])?;
         if buf[0..8] != *b"ELEMENT\x00" {
-            println!("buf: \"{}\", {:?}", String::from_utf8_lossy(&buf[0..8]), &buf[0..8]);
-            return ReadError::err("unexpected contents (expected ELEMENT\\x00)", pos, (0, 8));
+            return ReadError::err("unexpected contents (expected ELEMENT\\x00)", self.pos, (0, 8));
         }
         let ident = BigEndian::read_u64(&buf[8..16]).into();
-        pos += 16;
+        self.pos += 16;
         
         if buf[16..24] != *b"BYTES\x00\x00\x00" {
-            return ReadError::err("unexpected contents (expected BYTES\\x00\\x00\\x00)", pos, (16, 24));
+            return ReadError::err("unexpected contents (expected BYTES\\x00\\x00\\x00)", self.pos, (16, 24));
         }
         let data_len = BigEndian::read_u64(&buf[24..32]) as usize;   // #0015
-        pos += 16;
+        self.pos += 16;
         
-        let mut data = vec![0; data_len];
-        r.read_exact(&mut data)?;
-        pos += data_len;
+        let data = R::read_data(r, data_len)?;
+        self.pos += data_len;
         
-        let pad_len = 16 * ((data_len + 15) / 16) - data_len;
+        let pad_len = pad16(data_len) - data_len;
         if pad_len > 0 {
             r.read_exact(&mut buf[0..pad_len])?;
-            pos += pad_len;
+            self.pos += pad_len;
         }
         
-        let elt_sum = Sum::elt_sum(ident, &data);
         r.read_exact(&mut buf[0..SUM_BYTES])?;
-        if elt_sum != buf[0..SUM_BYTES] {
-            return ReadError::err("element checksum mismatch", pos, (0, SUM_BYTES));
-        }
-        pos += SUM_BYTES;
+        let elt_sum = Sum::load(&buf[0..SUM_BYTES]);
         
-        combined_elt_sum.permute(&elt_sum);
+        if let Some(ref index) = self.index {
+            if index[self.n] != (ident, self.rel_pos + 32, data_len, elt_sum.clone()) {
+                return ReadError::err("element does not match index", self.pos, (0, SUM_BYTES));
+            }
+        }
+        self.rel_pos += 32 + pad16(data_len) as u64 + SUM_BYTES as u64;
+        self.n += 1;
+        self.pos += SUM_BYTES;
         
-        let elt = T::from_vec_sum(data, elt_sum)?;
-        match elts.entry(ident) {
-            Entry::Occupied(_) => { return Err(Box::new(ElementOp::IdClash)); },
-            Entry::Vacant(e) => e.insert(Rc::new(elt)),
-        };
+        self.combined_elt_sum.permute(&elt_sum);
+        Ok((ident, data, elt_sum))
     }
     
-    r.read_exact(&mut buf[0..16])?;
-    if buf[0..8] == *b"ELTMOVES" /*versions from 20160201, optional*/ {
-        // feature removed
-        let n_moves = BigEndian::read_u64(&buf[8..16]) as usize;    // #0015
-        if n_moves != 0 {
-            return OtherError::err("element move support removed");
+    fn read_end(&mut self) -> Result<()> {
+        let (r, buf) = (&mut self.r, &mut self.buf);
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0..8] == *b"ELTMOVES" /*versions from 20160201, optional*/ {
+            // feature removed
+            let n_moves = BigEndian::read_u64(&buf[8..16]) as usize;    // #0015
+            if n_moves != 0 {
+                return OtherError::err("element move support removed");
+            }
+            
+            // re-fill buffer for next section:
+            r.read_exact(&mut buf[0..16])?;
         }
         
-        // re-fill buffer for next section:
-        r.read_exact(&mut buf[0..16])?;
+        if buf[0..8] != *b"STATESUM" {
+            return ReadError::err("unexpected contents (expected STATESUM or ELTMOVES)", self.pos, (0, 8));
+        }
+        self.pos += 8;
+        if (BigEndian::read_u64(&buf[8..16]) as usize) != self.num_elts {
+            return ReadError::err("unexpected contents (number of elements \
+                differs from that previously stated)", self.pos, (8, 16));
+        }
+        self.pos += 8;
+        
+        let statesum = &Sum::state_meta_sum(&self.parents, &self.meta) ^ &self.combined_elt_sum;
+        r.read_exact(&mut buf[0..SUM_BYTES])?;
+        if statesum != buf[0..SUM_BYTES] {
+            return ReadError::err("state checksum mismatch", self.pos, (0, SUM_BYTES));
+        }
+        self.pos += SUM_BYTES;
+        
+        let sum = r.partial_sum();
+        r.inner().read_exact(&mut buf[0..SUM_BYTES])?;
+        if sum != buf[0..SUM_BYTES] {
+            return ReadError::err("checksum invalid", self.pos, (0, SUM_BYTES));
+        }
+        
+        self.statesum = Some(statesum);
+        Ok(())
+    }
+}
+
+/// Read a snapshot's element index, without reading elements.
+/// 
+/// Reading stops after the index; the returned state loads each element from
+/// `source` when it is first accessed, verifying its checksum then. `base`
+/// is the position within `source` of the first byte read from `reader`
+/// (i.e. the length of the file header).
+/// 
+/// The snapshot's final checksum is not verified, since the data it covers is
+/// not all read. Requires a file version with an index (see `has_index`).
+pub fn read_snapshot_lazy<T: Element>(reader: &mut Read, format_ver: u32,
+        source: Arc<EltSource>, base: u64) -> Result<PartState<T>>
+{
+    if !has_index(format_ver) {
+        return ArgError::err("snapshot file version has no element index");
+    }
+    let mut r = sum::HashReader::new(CountingReader::new(reader));
+    
+    let mut pos: usize = 0;
+    let mut buf = vec![0; 32];
+    
+    let (parents, meta) = read_start(&mut r, &mut buf, &mut pos, format_ver)?;
+    let (_, mut index, statesum) = read_index(&mut r, &mut buf, &mut pos, true)?;
+    
+    let elts_pos = base + r.inner().count() as u64;
+    let mut combined_elt_sum = Sum::zero();
+    for entry in &mut index {
+        entry.1 += elts_pos;
+        combined_elt_sum.permute(&entry.3);
+    }
+    let num_elts = index.len();
+    let state = PartState::new_lazy(parents, index, source, meta, combined_elt_sum);
+    if *state.statesum() != statesum {
+        return ReadError::err("state checksum mismatch", pos, (0, SUM_BYTES));
+    }
+    
+    trace!("Read snapshot index (with {} elements): {}", num_elts, state.statesum());
+    Ok(state)
+}
+
+/// Read a snapshot's state sum and metadata, without creating any elements.
+/// 
+/// Where the file version has an index (see `has_index`), reading stops after
+/// the index; otherwise element data is read (one element at a time) in
+/// order to verify the state sum.
+pub fn read_snapshot_summary(reader: &mut Read, format_ver: u32) -> Result<(Sum, CommitMeta)> {
+    if has_index(format_ver) {
+        let mut r = sum::HashReader::new(reader);
+        let mut pos: usize = 0;
+        let mut buf = vec![0; 32];
+        let (_, meta) = read_start(&mut r, &mut buf, &mut pos, format_ver)?;
+        let (_, _, statesum) = read_index(&mut r, &mut buf, &mut pos, false)?;
+        return Ok((statesum, meta));
     }
     
-    let state = PartState::new_explicit(parents,
-            elts, meta, combined_elt_sum);
+    let mut snapshot = SnapshotReader::new(reader, format_ver)?;
+    while let Some(result) = snapshot.next() {
+        result?;
+    }
+    let statesum = snapshot.statesum().expect("read to end").clone();
+    Ok((statesum, snapshot.meta().clone()))
+}
+
+// Read the start of a snapshot: identifier, metadata and parents.
+fn read_start<R: Read>(r: &mut sum::HashReader<R>, buf: &mut [u8], pos: &mut usize,
+        format_ver: u32) -> Result<(Vec<Sum>, CommitMeta)>
+{
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..6] != *b"SNAPSH" || buf[7] != b'U' {
+        return ReadError::err("unexpected contents (expected SNAPSH_U where _ is any)", *pos, (0, 8));
+    }
+    let num_parents = buf[6] as usize;
+    let meta = read_meta(r, buf, pos, format_ver)?;
     
-    if buf[0..8] != *b"STATESUM" {
-        return ReadError::err("unexpected contents (expected STATESUM or ELTMOVES)", pos, (0, 8));
+    let mut parents = Vec::with_capacity(num_parents);
+    for _ in 0..num_parents {
+        r.read_exact(&mut buf[0..SUM_BYTES])?;
+        parents.push(Sum::load(&buf[0..SUM_BYTES]));
+        *pos += SUM_BYTES;
+    }
+    Ok((parents, meta))
+}
+
+// Read the element index (versions from 2016_09_19), returning the number of
+// entries, the entries (only if `keep`) and the state sum. Verifies the
+// checksum of everything read so far.
+fn read_index<R: Read>(r: &mut sum::HashReader<R>, buf: &mut [u8], pos: &mut usize,
+        keep: bool) -> Result<(usize, Vec<IndexEntry>, Sum)>
+{
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..8] != *b"ELTINDEX" {
+        return ReadError::err("unexpected contents (expected ELTINDEX)", *pos, (0, 8));
+    }
+    let num_elts = BigEndian::read_u64(&buf[8..16]) as usize;    // #0015
+    *pos += 16;
+    
+    let mut index = Vec::with_capacity(if keep { num_elts } else { 0 });
+    for _ in 0..num_elts {
+        r.read_exact(&mut buf[0..32])?;
+        let ident = BigEndian::read_u64(&buf[0..8]).into();
+        let offset = BigEndian::read_u64(&buf[8..16]);
+        let len = BigEndian::read_u64(&buf[16..24]) as usize;    // #0015
+        if buf[24..32] != [0u8; 8] {
+            return ReadError::err("unexpected contents (expected zero padding)", *pos, (24, 32));
+        }
+        *pos += 32;
+        r.read_exact(&mut buf[0..SUM_BYTES])?;
+        if keep {
+            index.push((ident, offset, len, Sum::load(&buf[0..SUM_BYTES])));
+        }
+        *pos += SUM_BYTES;
+    }
+    
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..8] != *b"IDXSTATE" {
+        return ReadError::err("unexpected contents (expected IDXSTATE)", *pos, (0, 8));
     }
-    pos += 8;
     if (BigEndian::read_u64(&buf[8..16]) as usize) != num_elts {
         return ReadError::err("unexpected contents (number of elements \
-            differs from that previously stated)", pos, (8, 16));
+            differs from that previously stated)", *pos, (8, 16));
     }
-    pos += 8;
-    
+    *pos += 16;
     r.read_exact(&mut buf[0..SUM_BYTES])?;
-    if *state.statesum() != buf[0..SUM_BYTES] {
-        return ReadError::err("state checksum mismatch", pos, (0, SUM_BYTES));
-    }
-    pos += SUM_BYTES;
+    let statesum = Sum::load(&buf[0..SUM_BYTES]);
+    *pos += SUM_BYTES;
     
-    let sum = r.sum();
-    let mut r = r.into_inner();
+    let sum = r.partial_sum();
     r.read_exact(&mut buf[0..SUM_BYTES])?;
     if sum != buf[0..SUM_BYTES] {
-        return ReadError::err("checksum invalid", pos, (0, SUM_BYTES));
+        return ReadError::err("index checksum invalid", *pos, (0, SUM_BYTES));
     }
+    *pos += SUM_BYTES;
     
-    trace!("Read snapshot (with {} elements): {}", num_elts, state.statesum());
-    Ok(state)
+    Ok((num_elts, index, statesum))
 }
 
 /// Write a snapshot of a set of elements to a stream
@@ -162,34 +616,59 @@ pub fn write_snapshot<T: Element>(state: &PartState<T>,
         parent.write_to(&mut w)?;
     }
     
-    w.write_all(b"ELEMENTS")?;
-    
     let mut elt_buf = Vec::new();
     
-    let mut keys: Vec<_> = state.elts_iter().map(|(k,_)| k).collect();
+    let mut keys: Vec<_> = state.elt_sums_iter().map(|(k,_)| k).collect();
     keys.sort();
     
     let num_elts = keys.len() as u64;  // #0015
+    
+    // The index requires data lengths, thus we serialise each element in
+    // memory twice (rather than holding all serialised data in memory).
+    // Elements read on demand are copied from their source, not loaded.
+    w.write_all(b"ELTINDEX")?;
+    w.write_u64::<BigEndian>(num_elts)?;
+    let mut rel_pos = 16;
+    for ident in &keys {
+        let len = match state.elt_data_len(*ident) {
+            Some(len) => len,
+            None => {
+                state.elt_data(*ident, &mut elt_buf)?;
+                elt_buf.len()
+            },
+        };
+        w.write_u64::<BigEndian>((*ident).into())?;
+        w.write_u64::<BigEndian>(rel_pos + 32)?;
+        w.write_u64::<BigEndian>(len as u64 /* #0015 */)?;
+        w.write_all(&[0u8; 8])?;
+        state.get_sum(*ident)?.write_to(&mut w)?;
+        rel_pos += 32 + pad16(len) as u64 + SUM_BYTES as u64;
+    }
+    w.write_all(b"IDXSTATE")?;
+    w.write_u64::<BigEndian>(num_elts)?;
+    state.statesum().write_to(&mut w)?;
+    let sum = w.partial_sum();
+    sum.write_to(&mut w)?;
+    
+    w.write_all(b"ELEMENTS")?;
     w.write_u64::<BigEndian>(num_elts)?;
     
     for ident in keys {
         w.write_all(b"ELEMENT\x00")?;
         w.write_u64::<BigEndian>(ident.into())?;
         
-        let elt = state.get_rc(ident).expect("get elt by key");
         w.write_all(b"BYTES\x00\x00\x00")?;
-        elt_buf.clear();
-        elt.write_buf(&mut &mut elt_buf)?;
+        state.elt_data(ident, &mut elt_buf)?;
         w.write_u64::<BigEndian>(elt_buf.len() as u64 /* #0015 */)?;
         
         w.write_all(&elt_buf)?;
-        let pad_len = 16 * ((elt_buf.len() + 15) / 16) - elt_buf.len();
+        let pad_len = pad16(elt_buf.len()) - elt_buf.len();
         if pad_len > 0 {
             let padding = [0u8; 15];
             w.write_all(&padding[0..pad_len])?;
         }
         
-        elt.sum(ident).write_to(&mut w)?;
+        state.get_sum(ident)?.write_to(&mut w)?;
     }
     
     // We write the checksum we kept in memory, the idea being that in-memory
@@ -253,4 +732,70 @@ fn snapshot_writing() {
     
     let state2 = read_snapshot(&mut &result[..], HEAD_VERSIONS[HEAD_VERSIONS.len() - 1]).unwrap();
     assert_eq!(state, state2);
+    
+    let state3 = read_snapshot_slice(&result, HEAD_VERSIONS[HEAD_VERSIONS.len() - 1]).unwrap();
+    assert_eq!(state, state3);
+    
+    let mut r = &result[..];
+    let mut reader = SnapshotReader::new(&mut r, HEAD_VERSIONS[HEAD_VERSIONS.len() - 1]).unwrap();
+    assert_eq!(reader.num_elts(), 2);
+    assert_eq!(reader.meta(), state.meta());
+    let elts = reader.by_ref().collect::<Result<Vec<_>>>().unwrap();
+    for (id, data, elt_sum) in elts {
+        let elt = state.get(id).unwrap();
+        assert_eq!(data, elt.as_bytes());
+        assert_eq!(elt_sum, elt.sum(id));
+    }
+    assert_eq!(reader.statesum(), Some(state.statesum()));
+}
+
+#[test]
+fn parallel_reading() {
+    use state::StateWrite;
+    use rw::HEAD_VERSIONS;
+    use commit::MakeCommitMeta;
+    
+    struct MCM;
+    impl MakeCommitMeta for MCM {}
+    
+    // Enough data for several batches:
+    let mut state = PartState::<String>::new(&mut MCM).clone_mut();
+    for i in 0..300 {
+        let text: String = (0..(i % 7 + 1) * 100).map(|j| (b'a' + ((i + j) % 26) as u8) as char).collect();
+        state.insert_new(text).unwrap();
+    }
+    let state = PartState::from_mut(state, &mut MCM);
+    let mut result = Vec::new();
+    write_snapshot(&state, &mut result).unwrap();
+    
+    let ver = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+    let state2 = read_snapshot_parallel(&mut &result[..], ver, 4).unwrap();
+    assert_eq!(state, state2);
+    assert_eq!(state.statesum(), state2.statesum());
+    
+    // Corrupt the data of an element near the end:
+    let pos = result.len() - 200;
+    result[pos] ^= 1;
+    assert!(read_snapshot_parallel::<String>(&mut &result[..], ver, 4).is_err());
+    
+    // Workers which panic are reported as an error:
+    #[derive(PartialEq, Eq, Debug)]
+    struct Fragile(String);
+    impl Element for Fragile {
+        fn write_buf(&self, writer: &mut Write) -> Result<()> {
+            writer.write_all(self.0.as_bytes())?;
+            Ok(())
+        }
+        fn read_buf(_buf: &[u8]) -> Result<Self> {
+            panic!("cannot decode");
+        }
+    }
+    let mut state = PartState::<Fragile>::new(&mut MCM).clone_mut();
+    for i in 0..300 {
+        state.insert_new(Fragile(format!("{:1000}", i))).unwrap();
+    }
+    let state = PartState::from_mut(state, &mut MCM);
+    let mut result = Vec::new();
+    write_snapshot(&state, &mut result).unwrap();
+    assert!(read_snapshot_parallel::<Fragile>(&mut &result[..], ver, 4).is_err());
 }
@@ -4,7 +4,7 @@
 
 //! For calculating checksums
 
-use std::io::{Read, Write, Result};
+use std::io::{Read, Write, Result, Error, ErrorKind};
 
 use crypto::digest::Digest;
 // use crypto::sha2::Sha256;
@@ -101,6 +101,14 @@ impl<R: Read> HashReader<R> {
         self.hasher.result(&mut buf);
         Sum::load(&buf)
     }
+    /// Make a Sum from the data so far, without finishing the digest
+    pub fn partial_sum(&self) -> Sum {
+        Sum::load_hasher(self.hasher.clone())
+    }
+    /// Restart the checksum (as if newly created)
+    pub fn reset(&mut self) {
+        self.hasher.reset();
+    }
     
     /// Get the inner reader
     pub fn inner(&mut self) -> &mut R { &mut self.inner }
@@ -108,6 +116,20 @@ impl<R: Read> HashReader<R> {
     pub fn into_inner(self) -> R { self.inner }
 }
 
+impl<'a> HashReader<&'a [u8]> {
+    /// Take the next `len` bytes from the underlying slice without copying
+    /// (but including them in the checksum).
+    pub fn read_slice(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.inner.len() < len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+        }
+        let (data, rest) = self.inner.split_at(len);
+        self.hasher.input(data);
+        self.inner = rest;
+        Ok(data)
+    }
+}
+
 impl<R: Read> Read for HashReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         let len = self.inner.read(buf)?;
@@ -140,6 +162,10 @@ impl<W: Write> HashWriter<W> {
         self.hasher.result(&mut buf);
         Sum::load(&buf)
     }
+    /// Make a Sum from the data so far, without finishing the digest
+    pub fn partial_sum(&self) -> Sum {
+        Sum::load_hasher(self.hasher.clone())
+    }
     
     /// Get the inner writer
     pub fn inner(&mut self) -> &mut W { &mut self.inner }
@@ -0,0 +1,238 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Shared access to a partition: one writer, many readers.
+
+use std::ops::{Deref, DerefMut};
+use std::result;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use control::Control;
+use elt::Element;
+use error::TipError;
+use part::Partition;
+use state::PartState;
+use sum::Sum;
+
+
+/// A `Partition` shared between threads (or parts of a program), allowing
+/// one writer at a time and any number of readers.
+///
+/// Readers take a `TipSnapshot`: an immutable view of the tip as it was when
+/// the snapshot was taken. Taking a snapshot requires only an `Arc` clone, so
+/// readers never wait on the writer beyond this; once taken, a snapshot is
+/// unaffected by further commits. Readers may check whether the tip has since
+/// moved with `is_current`.
+///
+/// The writer gets exclusive access to the `Partition` via `writer()`. Changes
+/// are published to readers when the `PartitionWriter` is dropped (or on
+/// `publish()`); readers therefore never see partially-applied changes. While
+/// a merge is required the previously published tip remains visible.
+///
+/// To share between threads, wrap in an `Arc`.
+pub struct SharedPartition<C: Control> {
+    part: Mutex<Partition<C>>,
+    tip: RwLock<Option<Arc<PartState<C::Element>>>>,
+    // incremented each time a different tip is published
+    generation: AtomicUsize,
+}
+
+/// An immutable view of a partition's tip, as published at some point in time
+/// (see `SharedPartition::snapshot`).
+///
+/// This dereferences to `PartState`.
+#[derive(Debug)]
+pub struct TipSnapshot<E: Element> {
+    state: Arc<PartState<E>>,
+    generation: usize,
+}
+
+/// Exclusive access to a shared partition (see `SharedPartition::writer`).
+///
+/// This dereferences to `Partition`. The tip is published when this is
+/// dropped.
+pub struct PartitionWriter<'a, C: Control+'a> {
+    shared: &'a SharedPartition<C>,
+    part: MutexGuard<'a, Partition<C>>,
+}
+
+impl<C: Control> SharedPartition<C> {
+    /// Share a partition, publishing its current tip (if it has a single
+    /// tip).
+    pub fn new(part: Partition<C>) -> SharedPartition<C> {
+        let tip = part.tip().ok().map(|state| Arc::new(state.clone_exact()));
+        SharedPartition {
+            part: Mutex::new(part),
+            tip: RwLock::new(tip),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get a snapshot of the latest published tip.
+    ///
+    /// Fails with `TipError::NotReady` if no tip was ever published (e.g. if
+    /// the partition was not loaded or required a merge when shared).
+    pub fn snapshot(&self) -> result::Result<TipSnapshot<C::Element>, TipError> {
+        let tip = self.tip.read().expect("tip lock");
+        match *tip {
+            Some(ref state) => Ok(TipSnapshot {
+                state: state.clone(),
+                // read under the lock, thus consistent with `state`
+                generation: self.generation.load(Ordering::Acquire),
+            }),
+            None => Err(TipError::NotReady),
+        }
+    }
+
+    /// Check whether a snapshot is of the latest published tip. This does
+    /// not take any lock.
+    pub fn is_current(&self, snapshot: &TipSnapshot<C::Element>) -> bool {
+        self.generation.load(Ordering::Acquire) == snapshot.generation
+    }
+
+    /// Get exclusive access to the partition, waiting for any other writer
+    /// to finish.
+    ///
+    /// Panics if a previous writer panicked while holding access.
+    pub fn writer(&self) -> PartitionWriter<C> {
+        PartitionWriter {
+            shared: self,
+            part: self.part.lock().expect("partition lock"),
+        }
+    }
+
+    /// Unwrap, returning the partition.
+    pub fn into_inner(self) -> Partition<C> {
+        self.part.into_inner().expect("partition lock")
+    }
+
+    // Publish the partition's tip, if it has a single tip which differs from
+    // that published.
+    fn publish(&self, part: &Partition<C>) {
+        let state = match part.tip() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if self.tip.read().expect("tip lock").as_ref()
+                .map_or(false, |tip| tip.statesum() == state.statesum()) {
+            return;
+        }
+        // Copy outside of the lock; readers are blocked only while swapping:
+        let new_tip = Arc::new(state.clone_exact());
+        let mut tip = self.tip.write().expect("tip lock");
+        *tip = Some(new_tip);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        trace!("Published tip {}", state.statesum());
+    }
+}
+
+impl<E: Element> TipSnapshot<E> {
+    /// Get the state
+    pub fn state(&self) -> &PartState<E> {
+        &self.state
+    }
+    /// Get the state sum of the snapshot's tip
+    pub fn statesum(&self) -> &Sum {
+        self.state.statesum()
+    }
+}
+impl<E: Element> Clone for TipSnapshot<E> {
+    fn clone(&self) -> TipSnapshot<E> {
+        TipSnapshot { state: self.state.clone(), generation: self.generation }
+    }
+}
+impl<E: Element> Deref for TipSnapshot<E> {
+    type Target = PartState<E>;
+    fn deref(&self) -> &PartState<E> {
+        &self.state
+    }
+}
+
+impl<'a, C: Control> PartitionWriter<'a, C> {
+    /// Publish the current tip to readers now, rather than waiting until
+    /// this is dropped. Does nothing unless there is a single tip.
+    pub fn publish(&mut self) {
+        self.shared.publish(&self.part);
+    }
+}
+impl<'a, C: Control> Deref for PartitionWriter<'a, C> {
+    type Target = Partition<C>;
+    fn deref(&self) -> &Partition<C> {
+        &self.part
+    }
+}
+impl<'a, C: Control> DerefMut for PartitionWriter<'a, C> {
+    fn deref_mut(&mut self) -> &mut Partition<C> {
+        &mut self.part
+    }
+}
+impl<'a, C: Control> Drop for PartitionWriter<'a, C> {
+    fn drop(&mut self) {
+        self.shared.publish(&self.part);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use super::*;
+    use control::DefaultControl;
+    use elt::EltId;
+    use io::mem::MemRepoIO;
+    use state::{StateRead, StateWrite};
+
+    #[test]
+    fn readers_and_writer() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let part = Partition::create(control, "shared").expect("create");
+        let shared = Arc::new(SharedPartition::new(part));
+
+        let snap0 = shared.snapshot().expect("snapshot");
+        assert_eq!(snap0.num_avail(), 0);
+        assert!(shared.is_current(&snap0));
+
+        {
+            let mut writer = shared.writer();
+            let mut state = writer.tip().expect("tip").clone_mut();
+            state.insert(EltId::from(1), "1".to_string()).expect("insert");
+            writer.push_state(state).expect("push");
+
+            // Not yet published:
+            assert!(shared.is_current(&snap0));
+            assert_eq!(shared.snapshot().expect("snapshot").num_avail(), 0);
+            writer.write_full().expect("write");
+        }
+        assert!(!shared.is_current(&snap0));
+        assert_eq!(snap0.num_avail(), 0);
+
+        // Readers on other threads see whole commits only:
+        let readers: Vec<_> = (0..4).map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    let snap = shared.snapshot().expect("snapshot");
+                    let n = snap.num_avail();
+                    for i in 1..(n as u64 + 1) {
+                        assert_eq!(snap.get(EltId::from(i)), Ok(&i.to_string()));
+                    }
+                }
+            })
+        }).collect();
+        for i in 2..20u64 {
+            let mut writer = shared.writer();
+            let mut state = writer.tip().expect("tip").clone_mut();
+            state.insert(EltId::from(i), i.to_string()).expect("insert");
+            writer.push_state(state).expect("push");
+        }
+        for reader in readers {
+            reader.join().expect("join");
+        }
+        let snap = shared.snapshot().expect("snapshot");
+        assert_eq!(snap.num_avail(), 19);
+        assert!(shared.is_current(&snap));
+    }
+}
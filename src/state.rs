@@ -16,16 +16,18 @@
 //! abstract over operations on partition and repository states.
 
 use std::collections::{HashMap};
-use std::collections::hash_map as hs;
 use std::clone::Clone;
-use std::rc::Rc;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
 
 use hashindexed::KeyComparator;
 
 use elt::{Element, EltId};
 use sum::Sum;
 use commit::*;
-use error::{ElementOp, PatchOp};
+use error::{Result, ElementOp, PatchOp, ReadError};
+use io::EltSource;
+use pmap::{self, PMap};
 
 /// Trait abstracting over read operations on the state of a partition or
 /// repository.
@@ -58,7 +60,7 @@ pub trait StateRead<E: Element> {
     }
     /// Low-level version of `get(id)`: returns a reference to the
     /// reference-counted wrapped container of the element.
-    fn get_rc(&self, id: EltId) -> Result<&Rc<E>, ElementOp>;
+    fn get_rc(&self, id: EltId) -> Result<&Arc<E>, ElementOp>;
 }
 
 /// Trait abstracting over write operations on the state of a partition or
@@ -70,12 +72,12 @@ pub trait StateWrite<E: Element>: StateRead<E> {
     /// partition (when called on a single partition) or a loaded partition
     /// (when called on a repository).
     fn insert(&mut self, id: EltId, elt: E) -> Result<EltId, ElementOp> {
-        self.insert_rc(id, Rc::new(elt))
+        self.insert_rc(id, Arc::new(elt))
     }
     
     /// Low-level version of `insert(elt)`: takes a reference-counted wrapper
     /// of an element.
-    fn insert_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<EltId, ElementOp>;
+    fn insert_rc(&mut self, id: EltId, elt: Arc<E>) -> Result<EltId, ElementOp>;
     
     /// Find a free identifier, and insert the element.
     /// 
@@ -83,12 +85,12 @@ pub trait StateWrite<E: Element>: StateRead<E> {
     /// to find the correct partition. When called directly on a partition, it
     /// assumes classification is correct.
     fn insert_new(&mut self, elt: E) -> Result<EltId, ElementOp> {
-        self.insert_new_rc(Rc::new(elt))
+        self.insert_new_rc(Arc::new(elt))
     }
     
     /// Low-level version of `insert_new(elt)`: takes a reference-counted
     /// wrapper of an element.
-    fn insert_new_rc(&mut self, elt: Rc<E>) -> Result<EltId, ElementOp>;
+    fn insert_new_rc(&mut self, elt: Arc<E>) -> Result<EltId, ElementOp>;
     
     /// Remove an existing element, insert a replacement in the same place
     /// and return the removed element.
@@ -96,38 +98,47 @@ pub trait StateWrite<E: Element>: StateRead<E> {
     /// Atomic: makes no changes if there is any error, such as no old element
     /// is found.
     /// 
-    /// Note that the returned `Rc<E>` cannot be unwrapped automatically since
+    /// Note that the returned `Arc<E>` cannot be unwrapped automatically since
     /// we do not know that we have the only reference.
-    fn replace(&mut self, id: EltId, elt: E) -> Result<Rc<E>, ElementOp> {
-        self.replace_rc(id, Rc::new(elt))
+    fn replace(&mut self, id: EltId, elt: E) -> Result<Arc<E>, ElementOp> {
+        self.replace_rc(id, Arc::new(elt))
     }
     
-    /// Low-level version of `replace(id, elt)` which takes an Rc-wrapped
+    /// Low-level version of `replace(id, elt)` which takes an Arc-wrapped
     /// element.
-    fn replace_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<Rc<E>, ElementOp>;
+    fn replace_rc(&mut self, id: EltId, elt: Arc<E>) -> Result<Arc<E>, ElementOp>;
     
     /// Remove an element, returning the element removed or failing.
     /// 
-    /// Note that the returned `Rc<E>` cannot be unwrapped automatically since
+    /// Note that the returned `Arc<E>` cannot be unwrapped automatically since
     /// we do not know that we have the only reference.
-    fn remove(&mut self, id: EltId) -> Result<Rc<E>, ElementOp>;
+    fn remove(&mut self, id: EltId) -> Result<Arc<E>, ElementOp>;
 }
 
 /// A 'state' is the set of elements in a partition at some point in time.
 /// Partitions have multiple states (the latest and each historical state which
 /// has been loaded, possibly also unmerged branches).
 /// 
-/// This holds one state. It is fairly cheap to clone one of these; the map of
-/// elements must be cloned but elements hold their data in a
-/// reference-counted way.
+/// This holds one state. It is cheap to clone one of these: elements are held
+/// in a persistent map, which clones share until modified (only the modified
+/// paths are then copied). States derived from one another thus use memory
+/// proportional to the changes between them, not to the number of elements.
 /// 
 /// Essentially this holds a map of elements indexed by their identifiers,
 /// partition-metadata and commit-metadata.
-#[derive(PartialEq, Debug)]
+/// 
+/// A state read from a snapshot with an element index may hold elements
+/// which are not yet in memory (see `Control::lazy_elements`). These are read
+/// and verified when first accessed via `get` or `get_rc` (failures are
+/// reported as `ElementOp::LoadFailed`) or when iterated over via
+/// `elts_iter` (which panics on failure; use `load_all` first to catch
+/// errors). Operations of this library on states report such failures as
+/// errors.
+#[derive(Debug)]
 pub struct PartState<E: Element> {
     parents: Vec<Sum>,
     statesum: Sum,
-    elts: HashMap<EltId, Rc<E>>,
+    elts: PMap<Slot<E>>,
     meta: CommitMeta,
 }
 
@@ -140,16 +151,125 @@ pub struct PartState<E: Element> {
 /// metadata differently, and requiring explicit type conversion ensures that
 /// commit creation happens correctly.
 /// 
-/// Note: there is a possibility that the internal representation be adjusted
-/// to a copy of the parent state plus a list of changes, however, it remains
-/// to be seen what advantages and disadvantages this would have. See issue
-/// #0021.
-#[derive(PartialEq, Debug)]
+/// The map of elements shares structure with the parent state; each
+/// modification copies only a short path through this map. Modifications are
+/// also recorded as they happen, so that a commit can be made without
+/// comparing all elements with the parent state (see `changes`).
+#[derive(Debug)]
 pub struct MutPartState<E: Element> {
     parent: Sum,
     elt_sum: Sum,
-    elts: HashMap<EltId, Rc<E>>,
+    elts: PMap<Slot<E>>,
     meta: CommitMetaPartial,
+    // For each element modified, the parent's version (if any)
+    edits: HashMap<EltId, Option<Slot<E>>>,
+}
+
+// An element held by a state, with its element sum: either in memory or to be
+// read on first use. Clones of a not-yet-loaded element share the loaded value.
+enum Slot<E: Element> {
+    Loaded(Arc<E>, Sum),
+    Lazy(Arc<LazyElt<E>>),
+}
+struct LazyElt<E: Element> {
+    elt: OnceLock<Arc<E>>,
+    source: Arc<EltSource>,
+    pos: u64,
+    len: usize,
+    sum: Sum,
+}
+impl<E: Element> Slot<E> {
+    fn get(&self, id: EltId) -> Result<&Arc<E>, ElementOp> {
+        match *self {
+            Slot::Loaded(ref elt, _) => Ok(elt),
+            Slot::Lazy(ref lazy) => {
+                if let Some(elt) = lazy.elt.get() {
+                    return Ok(elt);
+                }
+                match lazy.load(id) {
+                    Ok(elt) => {
+                        let _ = lazy.elt.set(Arc::new(elt));
+                        Ok(lazy.elt.get().expect("element loaded"))
+                    },
+                    Err(e) => {
+                        warn!("Failed to load element {}: {}", id, e);
+                        Err(ElementOp::LoadFailed)
+                    },
+                }
+            },
+        }
+    }
+    // Get the element sum, without loading the element
+    fn sum(&self) -> &Sum {
+        match *self {
+            Slot::Loaded(_, ref sum) => sum,
+            Slot::Lazy(ref lazy) => &lazy.sum,
+        }
+    }
+    fn is_loaded(&self) -> bool {
+        match *self {
+            Slot::Loaded(..) => true,
+            Slot::Lazy(ref lazy) => lazy.elt.get().is_some(),
+        }
+    }
+    // Compare, loading elements only if necessary
+    fn equals(&self, other: &Slot<E>) -> bool {
+        match (self, other) {
+            (&Slot::Loaded(ref a, _), &Slot::Loaded(ref b, _)) => a == b,
+            (&Slot::Lazy(ref a), &Slot::Lazy(ref b)) if Arc::ptr_eq(a, b) => true,
+            _ => self.sum() == other.sum(),
+        }
+    }
+}
+impl<E: Element> LazyElt<E> {
+    fn load(&self, id: EltId) -> Result<E> {
+        trace!("Loading element {} ({} bytes at {})", id, self.len, self.pos);
+        E::from_vec_sum(self.read(id)?, self.sum.clone())
+    }
+    // Read the element's data, checking its sum
+    fn read(&self, id: EltId) -> Result<Vec<u8>> {
+        let mut data = vec![0; self.len];
+        self.source.read_at(self.pos, &mut data)?;
+        if Sum::elt_sum(id, &data) != self.sum {
+            return ReadError::err("element checksum mismatch", self.pos as usize, (0, self.len));
+        }
+        Ok(data)
+    }
+}
+impl<E: Element> Clone for Slot<E> {
+    fn clone(&self) -> Slot<E> {
+        match *self {
+            Slot::Loaded(ref elt, ref sum) => Slot::Loaded(elt.clone(), sum.clone()),
+            Slot::Lazy(ref lazy) => Slot::Lazy(lazy.clone()),
+        }
+    }
+}
+impl<E: Element> fmt::Debug for Slot<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Slot::Loaded(ref elt, _) => elt.fmt(f),
+            Slot::Lazy(ref lazy) => match lazy.elt.get() {
+                Some(elt) => elt.fmt(f),
+                None => write!(f, "<not loaded: {}>", lazy.sum),
+            },
+        }
+    }
+}
+
+fn elts_eq<E: Element>(a: &PMap<Slot<E>>, b: &PMap<Slot<E>>) -> bool {
+    a.eq_by(b, |_, slot, slot2| slot.equals(slot2))
+}
+impl<E: Element> PartialEq for PartState<E> {
+    fn eq(&self, other: &PartState<E>) -> bool {
+        self.parents == other.parents && self.statesum == other.statesum &&
+            self.meta == other.meta && elts_eq(&self.elts, &other.elts)
+    }
+}
+impl<E: Element> PartialEq for MutPartState<E> {
+    fn eq(&self, other: &MutPartState<E>) -> bool {
+        self.parent == other.parent && self.elt_sum == other.elt_sum &&
+            self.meta == other.meta && elts_eq(&self.elts, &other.elts)
+    }
 }
 
 // Constructors
@@ -166,19 +286,47 @@ impl<E: Element> PartState<E> {
         PartState {
             parents: vec![],
             statesum: metasum /* no elts, so statesum = metasum */,
-            elts: HashMap::new(),
+            elts: PMap::new(),
             meta: meta,
         }
     }
     
-    /// Create a `PartState`, specifying most things explicitly.
+    /// Create a `PartState`, specifying most things explicitly. Each element
+    /// is given with its element sum (as returned by `Element::sum`).
     /// 
     /// This is for internal use; don't use externally unless you're really
     /// sure of what you're doing.
     pub fn new_explicit(parents: Vec<Sum>,
-            elts: HashMap<EltId, Rc<E>>,
+            elts: HashMap<EltId, (Arc<E>, Sum)>,
             meta: CommitMeta, elt_sum: Sum) -> PartState<E> {
         let metasum = Sum::state_meta_sum(&parents, &meta);
+        PartState {
+            parents: parents,
+            statesum: &metasum ^ &elt_sum,
+            elts: elts.into_iter().map(|(id, (elt, sum))| (id, Slot::Loaded(elt, sum))).collect(),
+            meta: meta
+        }
+    }
+    
+    /// Create a `PartState` whose elements are read from `source` when first
+    /// used. `index` lists for each element its identifier, position and
+    /// length of its data within `source` and its element sum.
+    /// 
+    /// This is for internal use; don't use externally unless you're really
+    /// sure of what you're doing.
+    pub fn new_lazy(parents: Vec<Sum>, index: Vec<(EltId, u64, usize, Sum)>,
+            source: Arc<EltSource>, meta: CommitMeta, elt_sum: Sum) -> PartState<E>
+    {
+        let metasum = Sum::state_meta_sum(&parents, &meta);
+        let elts = index.into_iter().map(|(id, pos, len, sum)| {
+            (id, Slot::Lazy(Arc::new(LazyElt {
+                elt: OnceLock::new(),
+                source: source.clone(),
+                pos: pos,
+                len: len,
+                sum: sum,
+            })))
+        }).collect();
         PartState {
             parents: parents,
             statesum: &metasum ^ &elt_sum,
@@ -252,17 +400,71 @@ impl<E: Element> PartState<E> {
     /// Get the commit meta-data associated with this state
     pub fn meta(&self) -> &CommitMeta { &self.meta }
     
-    /// Iterate over all elements
+    /// Iterate over all elements. Elements not yet loaded are loaded; this
+    /// panics if loading fails (see `load_all`).
     pub fn elts_iter(&self) -> EltIter<E> {
         EltIter { iter: self.elts.iter() }
     }
     
+    /// Iterate over element identifiers and element sums (see `get_sum`),
+    /// without loading elements.
+    pub fn elt_sums_iter(&self) -> EltSumIter<E> {
+        EltSumIter { iter: self.elts.iter() }
+    }
+    
+    /// Load all elements not yet in memory (see `Control::lazy_elements`),
+    /// failing on the first element which cannot be loaded.
+    pub fn load_all(&self) -> Result<(), ElementOp> {
+        for (id, slot) in &self.elts {
+            slot.get(id)?;
+        }
+        Ok(())
+    }
+    /// Count the elements currently in memory. This equals `num_avail()`
+    /// unless elements are loaded on demand.
+    pub fn num_loaded(&self) -> usize {
+        self.elts.values().filter(|slot| slot.is_loaded()).count()
+    }
+    /// Get the element sum of an element (as `Element::sum` would return).
+    /// 
+    /// States store the sum of each element, so this neither loads nor
+    /// serialises the element.
+    pub fn get_sum(&self, id: EltId) -> Result<&Sum, ElementOp> {
+        self.elts.get(id).map(|slot| slot.sum()).ok_or(ElementOp::EltNotFound)
+    }
+    /// Get the serialised data of an element (as `Element::write_buf` would
+    /// write), replacing the contents of `buf`.
+    /// 
+    /// Elements read on demand (see `Control::lazy_elements`) are copied from
+    /// their source, checking their sum, without being loaded.
+    pub fn elt_data(&self, id: EltId, buf: &mut Vec<u8>) -> Result<()> {
+        match self.elts.get(id) {
+            Some(&Slot::Loaded(ref elt, _)) => {
+                buf.clear();
+                elt.write_buf(&mut &mut *buf)
+            },
+            Some(&Slot::Lazy(ref lazy)) => {
+                *buf = lazy.read(id)?;
+                Ok(())
+            },
+            None => Err(Box::new(ElementOp::EltNotFound)),
+        }
+    }
+    /// Get the length of an element's serialised data (see `elt_data`) if
+    /// known without serialising the element, i.e. if it is read on demand.
+    pub fn elt_data_len(&self, id: EltId) -> Option<usize> {
+        match self.elts.get(id) {
+            Some(&Slot::Lazy(ref lazy)) => Some(lazy.len),
+            _ => None,
+        }
+    }
+    
     /// As `gen_id()`, but ensure the generated id is free in both self and
     /// another state.
     pub fn gen_id_binary(&self, s2: &PartState<E>) -> Result<EltId, ElementOp> {
         let mut id = EltId::random();;
         for _ in 0..10000 {
-            if !self.elts.contains_key(&id) && !s2.elts.contains_key(&id)
+            if !self.elts.contains_key(id) && !s2.elts.contains_key(id)
             {
                 return Ok(id)
             }
@@ -278,23 +480,23 @@ impl<E: Element> PartState<E> {
     /// This "clone" will not compare equal to the current one since the
     /// parents are different.
     /// 
-    /// Elements are considered Copy-On-Write so cloning the
-    /// state is not particularly expensive.
+    /// The map of elements is shared until modified, so cloning the state is
+    /// cheap.
     pub fn clone_mut(&self) -> MutPartState<E> {
         MutPartState {
             parent: self.statesum.clone(),
             elt_sum: self.statesum() ^ &self.metasum(),
             elts: self.elts.clone(),
             meta: CommitMeta::new_partial(self.statesum.clone(), self.meta.clone()),
+            edits: HashMap::new(),
         }
     }
     
     /// Clone the state, creating an exact copy. The new state will have the
     /// same parents as the current one.
     /// 
-    /// Elements are considered Copy-On-Write so cloning the
-    /// state is not particularly expensive (though the hash-map of elements
-    /// and a few other bits must still be copied).
+    /// The map of elements is shared, so cloning the state is cheap (only
+    /// metadata is copied).
     pub fn clone_exact(&self) -> Self {
         PartState {
             parents: self.parents.clone(),
@@ -312,10 +514,92 @@ impl<E: Element> MutPartState<E> {
     /// partition statesum is this XORed with the metadata sum.
     pub fn elt_sum(&self) -> &Sum { &self.elt_sum }
     
-    /// Iterate over all elements
+    /// Iterate over all elements. As with `PartState::elts_iter`, this
+    /// panics if an element cannot be loaded.
     pub fn elts_iter(&self) -> EltIter<E> {
         EltIter { iter: self.elts.iter() }
     }
+    /// Get the element sum of an element (see `PartState::get_sum`).
+    pub fn get_sum(&self, id: EltId) -> Result<&Sum, ElementOp> {
+        self.elts.get(id).map(|slot| slot.sum()).ok_or(ElementOp::EltNotFound)
+    }
+    
+    /// Remove an element without loading it (unlike `remove`, which must
+    /// return the element).
+    pub fn discard(&mut self, id: EltId) -> Result<(), ElementOp> {
+        self.record_edit(id);
+        match self.elts.remove(id) {
+            None => Err(ElementOp::EltNotFound),
+            Some(removed) => {
+                self.elt_sum.permute(removed.sum());
+                Ok(())
+            }
+        }
+    }
+    /// Replace an element, without loading the old version (unlike
+    /// `replace_rc`, which must return it).
+    pub fn set_rc(&mut self, id: EltId, elt: Arc<E>) -> Result<(), ElementOp> {
+        let sum = elt.sum(id);
+        self.set_rc_sum(id, elt, sum)
+    }
+    /// As `set_rc`, but with the element sum given (this must equal
+    /// `elt.sum(id)`). This avoids serialising the element.
+    pub fn set_rc_sum(&mut self, id: EltId, elt: Arc<E>, sum: Sum) -> Result<(), ElementOp> {
+        if !self.elts.contains_key(id) {
+            return Err(ElementOp::EltNotFound);
+        }
+        self.record_edit(id);
+        self.elt_sum.permute(&sum);
+        let old = self.elts.insert(id, Slot::Loaded(elt, sum)).expect("element present");
+        self.elt_sum.permute(old.sum());
+        Ok(())
+    }
+    /// As `insert_rc`, but with the element sum given (this must equal
+    /// `elt.sum(id)`). This avoids serialising the element.
+    pub fn insert_rc_sum(&mut self, id: EltId, elt: Arc<E>, sum: Sum) -> Result<EltId, ElementOp> {
+        if self.elts.contains_key(id) { return Err(ElementOp::IdClash); }
+        self.record_edit(id);
+        self.elt_sum.permute(&sum);
+        self.elts.insert(id, Slot::Loaded(elt, sum));
+        Ok(id)
+    }
+    
+    /// Get the changes made since this state was created from its parent
+    /// (via `PartState::clone_mut`).
+    /// 
+    /// This uses the record of modifications, hence its cost is proportional
+    /// to the number of elements modified, not to the size of the state.
+    /// Elements replaced by an equal element or inserted then removed are not
+    /// included.
+    pub fn changes(&self) -> HashMap<EltId, EltChange<E>> {
+        let mut changes = HashMap::new();
+        for (id, old) in &self.edits {
+            let id = *id;
+            let change = match (old.as_ref(), self.elts.get(id)) {
+                (None, None) => continue,
+                (Some(_), None) => EltChange::deletion(),
+                (Some(old), Some(new)) if old.equals(new) => continue,
+                (old, Some(&Slot::Loaded(ref elt, ref sum))) => if old.is_none() {
+                    EltChange::insertion(elt.clone(), sum.clone())
+                } else {
+                    EltChange::replacement(elt.clone(), sum.clone())
+                },
+                // Elements are only held lazily as read, never as modified:
+                (_, Some(&Slot::Lazy(_))) => unreachable!("modified element not in memory"),
+            };
+            changes.insert(id, change);
+        }
+        changes
+    }
+    
+    // Record the parent's version of an element, before its first
+    // modification
+    fn record_edit(&mut self, id: EltId) {
+        if !self.edits.contains_key(&id) {
+            let old = self.elts.get(id).cloned();
+            self.edits.insert(id, old);
+        }
+    }
     
     /// Get access to (partial) metadata
     pub fn meta(&self) -> &CommitMetaPartial { &self.meta }
@@ -336,7 +620,7 @@ impl<E: Element> MutPartState<E> {
     /// assuming random distribution of ids.
     pub fn free_id_near(&mut self, mut id: EltId) -> Result<EltId, ElementOp> {
         for _ in 0..10000 {
-            if !self.elts.contains_key(&id) {
+            if !self.elts.contains_key(id) {
                 return Ok(id);
             }
             id = id.next_elt();
@@ -353,10 +637,10 @@ impl<E: Element> StateRead<E> for PartState<E> {
         self.elts.len()
     }
     fn is_avail(&self, id: EltId) -> bool {
-        self.elts.contains_key(&id)
+        self.elts.contains_key(id)
     }
-    fn get_rc(&self, id: EltId) -> Result<&Rc<E>, ElementOp> {
-        self.elts.get(&id).ok_or(ElementOp::EltNotFound)
+    fn get_rc(&self, id: EltId) -> Result<&Arc<E>, ElementOp> {
+        self.elts.get(id).ok_or(ElementOp::EltNotFound).and_then(|slot| slot.get(id))
     }
 }
 impl<E: Element> StateRead<E> for MutPartState<E> {
@@ -367,59 +651,72 @@ impl<E: Element> StateRead<E> for MutPartState<E> {
         self.elts.len()
     }
     fn is_avail(&self, id: EltId) -> bool {
-        self.elts.contains_key(&id)
+        self.elts.contains_key(id)
     }
-    fn get_rc(&self, id: EltId) -> Result<&Rc<E>, ElementOp> {
-        self.elts.get(&id).ok_or(ElementOp::EltNotFound)
+    fn get_rc(&self, id: EltId) -> Result<&Arc<E>, ElementOp> {
+        self.elts.get(id).ok_or(ElementOp::EltNotFound).and_then(|slot| slot.get(id))
     }
 }
 impl<E: Element> StateWrite<E> for MutPartState<E> {
-    fn insert_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<EltId, ElementOp> {
-        if self.elts.contains_key(&id) { return Err(ElementOp::IdClash); }
-        self.elt_sum.permute(&elt.sum(id));
-        self.elts.insert(id, elt);
-        Ok(id)
+    fn insert_rc(&mut self, id: EltId, elt: Arc<E>) -> Result<EltId, ElementOp> {
+        if self.elts.contains_key(id) { return Err(ElementOp::IdClash); }
+        let sum = elt.sum(id);
+        self.insert_rc_sum(id, elt, sum)
     }
     
-    fn insert_new_rc(&mut self, elt: Rc<E>) -> Result<EltId, ElementOp> {
+    fn insert_new_rc(&mut self, elt: Arc<E>) -> Result<EltId, ElementOp> {
         let id = self.free_id()?;
         self.insert_rc(id, elt)
     }
     
-    fn replace_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<Rc<E>, ElementOp> {
-        match self.elts.entry(id) {
-            hs::Entry::Occupied(ref mut entry) => Ok(entry.insert(elt)),
-            hs::Entry::Vacant(_) => Err(ElementOp::EltNotFound),
-        }
+    fn replace_rc(&mut self, id: EltId, elt: Arc<E>) -> Result<Arc<E>, ElementOp> {
+        let old = self.get_rc(id)?.clone();
+        self.set_rc(id, elt)?;
+        Ok(old)
     }
     
-    fn remove(&mut self, id: EltId) -> Result<Rc<E>, ElementOp> {
-        match self.elts.remove(&id) {
-            None => Err(ElementOp::EltNotFound),
-            Some(removed) => {
-                self.elt_sum.permute(&removed.sum(id));
-                Ok(removed)
-            }
-        }
+    fn remove(&mut self, id: EltId) -> Result<Arc<E>, ElementOp> {
+        let removed = self.get_rc(id)?.clone();
+        self.discard(id)?;
+        Ok(removed)
     }
 }
 
 /// Wrapper around underlying iterator structure
-pub struct EltIter<'a, E: 'a> {
-    iter: hs::Iter<'a, EltId, Rc<E>>
+pub struct EltIter<'a, E: Element+'a> {
+    iter: pmap::Iter<'a, Slot<E>>
 }
-impl<'a, E> Clone for EltIter<'a, E> {
+impl<'a, E: Element> Clone for EltIter<'a, E> {
     fn clone(&self) -> EltIter<'a, E> {
         EltIter { iter: self.iter.clone() }
     }
 }
-impl<'a, E> Iterator for EltIter<'a, E> {
-    type Item = (EltId, &'a Rc<E>);
-    fn next(&mut self) -> Option<(EltId, &'a Rc<E>)> {
-        self.iter.next().map(|(k,v)| (*k, v))
+impl<'a, E: Element> Iterator for EltIter<'a, E> {
+    type Item = (EltId, &'a Arc<E>);
+    fn next(&mut self) -> Option<(EltId, &'a Arc<E>)> {
+        self.iter.next().map(|(k,v)| match v.get(k) {
+            Ok(elt) => (k, elt),
+            Err(e) => panic!("element {}: {}", k, e),
+        })
+    }
+}
+impl<'a, E: Element> ExactSizeIterator for EltIter<'a, E> {
+    fn len(&self) -> usize {
+        self.iter.len()
     }
 }
-impl<'a, E> ExactSizeIterator for EltIter<'a, E> {
+
+/// Iterator over element identifiers and sums (see `PartState::elt_sums_iter`)
+pub struct EltSumIter<'a, E: Element+'a> {
+    iter: pmap::Iter<'a, Slot<E>>
+}
+impl<'a, E: Element> Iterator for EltSumIter<'a, E> {
+    type Item = (EltId, &'a Sum);
+    fn next(&mut self) -> Option<(EltId, &'a Sum)> {
+        self.iter.next().map(|(k,v)| (k, v.sum()))
+    }
+}
+impl<'a, E: Element> ExactSizeIterator for EltSumIter<'a, E> {
     fn len(&self) -> usize {
         self.iter.len()
     }
@@ -432,3 +729,8 @@ impl<E: Element> KeyComparator<PartState<E>, Sum> for PartStateSumComparator {
         value.statesum()
     }
 }
+impl<E: Element> KeyComparator<Arc<PartState<E>>, Sum> for PartStateSumComparator {
+    fn extract_key(value: &Arc<PartState<E>>) -> &Sum {
+        value.statesum()
+    }
+}
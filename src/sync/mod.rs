@@ -0,0 +1,659 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Synchronisation of a partition between two replicas over a byte stream.
+//!
+//! One side serves requests (see `serve`); the other is a client, which may
+//! `clone` the server's partition, `fetch` commits from the server or `push`
+//! commits to it, or `reconcile` element content with the server's without
+//! using history. Any stream implementing `Read + Write` may be used; the
+//! `tcp` module provides a server and client over TCP.
+//!
+//! ### Protocol
+//!
+//! Messages start with an 8-byte tag; numbers are big-endian, strings are
+//! UTF-8 prefixed by a 32-bit length and lists of state sums are prefixed by a
+//! 32-bit count. Each connection carries a single request:
+//!
+//! 1.  The client sends `PIPSYNC\0`, the protocol version, a request tag
+//!     (`CLONE\0\0\0`, `FETCH\0\0\0`, `PUSH\0\0\0\0` or `RECONCIL`) and
+//!     the repo name (empty when cloning, and followed by the 32-bit number
+//!     of commits of history wanted). The server answers with `PIPSYNC\0`,
+//!     its protocol version and repo name, then `OK\0\0\0\0\0\0` or
+//!     `ERROR\0\0\0` and a message.
+//! 2.  When cloning, the server sends `SNAPSHOT`, a 64-bit length and a
+//!     snapshot file of the state this number of commits before its tip (or
+//!     as far back as its history goes), then the commits leading from this
+//!     state to the tip as in step 4; the client replies likewise. Both
+//!     files record the shallow boundary (see `Partition::shallow_boundary`).
+//! 3.  Otherwise the client sends `TIPS\0\0\0\0` and its tips, and the server
+//!     replies likewise. The side sending commits (the server on fetch, the
+//!     client on push) walks back from its tips through the commit DAG,
+//!     asking which states the receiver knows in batches (`QUERY\0\0\0` with
+//!     a list of sums, answered by `KNOWN\0\0\0` and one byte per sum) and
+//!     not walking further back from states known by the receiver.
+//! 4.  The sender sends `COMMITS\0`, the number of commits and, if this is
+//!     not zero, these commits as a commit log file (header included; see
+//!     `rw::commitlog`), parents before children (or, if it cannot get
+//!     these, `ERROR\0\0\0` and a message). The receiver adds these,
+//!     writes them to its own logs and replies with `OK\0\0\0\0\0\0` and the
+//!     number of commits added, or `ERROR\0\0\0` and a message.
+//!
+//! When reconciling, steps 3 and 4 are replaced: the client compares ranges
+//! of element identifiers (`RANGES\0\0` with a list of ranges, answered by
+//! `FPRINTS\0` with the XOR of element sums and number of elements of each),
+//! splitting those which differ until small enough to list (`LIST\0\0\0\0`,
+//! answered by `ITEMS\0\0\0` with element identifiers and sums). It then
+//! gets the server's differing elements (`GET\0\0\0\0\0`, answered by
+//! `ELTS\0\0\0\0`) and sends the changes the server should make
+//! (`PUT\0\0\0\0\0`), which the server answers like commits.
+
+pub mod tcp;
+mod reconcile;
+
+pub use self::reconcile::{reconcile, Reconciled};
+
+use std::cmp::min;
+use std::collections::{HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::u32;
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use commit::Commit;
+use control::Control;
+use elt::Element;
+use error::{Result, Error, SyncError, TipError, OtherError};
+use part::Partition;
+use rw::commitlog::{CommitReceiver, read_log, start_log, write_commit};
+use rw::header::{FileType, FileHeader, read_head, write_head};
+use rw::snapshot::{read_snapshot, write_snapshot};
+use shared::SharedPartition;
+use sum::{Sum, SUM_BYTES};
+
+
+/// Version of the protocol. Peers must use the same version.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+const HELLO: &'static [u8; 8] = b"PIPSYNC\x00";
+const CLONE: &'static [u8; 8] = b"CLONE\x00\x00\x00";
+const FETCH: &'static [u8; 8] = b"FETCH\x00\x00\x00";
+const PUSH: &'static [u8; 8] = b"PUSH\x00\x00\x00\x00";
+const RECONCILE: &'static [u8; 8] = b"RECONCIL";
+const OK: &'static [u8; 8] = b"OK\x00\x00\x00\x00\x00\x00";
+const ERROR: &'static [u8; 8] = b"ERROR\x00\x00\x00";
+const SNAPSHOT: &'static [u8; 8] = b"SNAPSHOT";
+const TIPS: &'static [u8; 8] = b"TIPS\x00\x00\x00\x00";
+const QUERY: &'static [u8; 8] = b"QUERY\x00\x00\x00";
+const KNOWN: &'static [u8; 8] = b"KNOWN\x00\x00\x00";
+const COMMITS: &'static [u8; 8] = b"COMMITS\x00";
+
+// Number of states asked about per query
+const QUERY_BATCH: usize = 64;
+// Limits on lengths read, to fail early on nonsense input
+const MAX_STRING: usize = 1 << 16;
+const MAX_SUMS: usize = 1 << 20;
+
+/// A request made by a client
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// Copy the server's tip and recent history (see `clone`)
+    Clone,
+    /// Get commits from the server (see `fetch`)
+    Fetch,
+    /// Send commits to the server (see `push`)
+    Push,
+    /// Exchange differing elements with the server (see `reconcile`)
+    Reconcile,
+}
+
+/// Summary of a request handled by `serve`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Served {
+    /// The request
+    pub request: Request,
+    /// Number of commits sent (clone, fetch) or added (push), or elements
+    /// changed (reconcile)
+    pub commits: usize,
+}
+
+
+/// Access to the partition served by `serve`.
+///
+/// `serve` uses the partition in short steps, never while waiting on the
+/// peer, calling `write` only to add commits or change elements. This is
+/// implemented for `Partition` and for `&SharedPartition`, where each step
+/// takes the writer (see `SharedPartition::writer`); other users of a shared
+/// partition are thus only blocked briefly, even by slow clients.
+pub trait PartAccess {
+    /// The partition's `Control` type
+    type Control: Control;
+    /// Call `f` with read access to the partition.
+    fn read<T, F: FnOnce(&Partition<Self::Control>) -> T>(&self, f: F) -> T;
+    /// Call `f` with write access to the partition.
+    fn write<T, F: FnOnce(&mut Partition<Self::Control>) -> T>(&mut self, f: F) -> T;
+}
+impl<C: Control> PartAccess for Partition<C> {
+    type Control = C;
+    fn read<T, F: FnOnce(&Partition<C>) -> T>(&self, f: F) -> T {
+        f(self)
+    }
+    fn write<T, F: FnOnce(&mut Partition<C>) -> T>(&mut self, f: F) -> T {
+        f(self)
+    }
+}
+impl<'a, C: Control> PartAccess for &'a SharedPartition<C> {
+    type Control = C;
+    fn read<T, F: FnOnce(&Partition<C>) -> T>(&self, f: F) -> T {
+        f(&self.writer())
+    }
+    fn write<T, F: FnOnce(&mut Partition<C>) -> T>(&mut self, f: F) -> T {
+        f(&mut self.writer())
+    }
+}
+
+
+/// Handle one request from a client connected via `stream`.
+///
+/// Fetching and cloning only read from `part`; on push, commits received are
+/// added and written (see `Partition::import_commits` and `write_fast`). The
+/// partition must be loaded; cloning also requires a single tip.
+///
+/// Errors which the client should know about are reported to it before
+/// failing.
+pub fn serve<P: PartAccess, S: Read + Write>(part: &mut P, stream: &mut S) -> Result<Served> {
+    expect_tag(stream, HELLO)?;
+    let version = read_u32(stream)?;
+    let tag = read_tag(stream)?;
+    let name = read_string(stream)?;
+    let depth = if tag == *CLONE { read_u32(stream)? as usize } else { 0 };
+
+    let part_name = part.read(|p| p.name().to_string());
+    stream.write_all(HELLO)?;
+    stream.write_u32::<BigEndian>(PROTOCOL_VERSION)?;
+    write_string(stream, &part_name)?;
+
+    let request = match &tag {
+        t if t == CLONE => Request::Clone,
+        t if t == FETCH => Request::Fetch,
+        t if t == PUSH => Request::Push,
+        t if t == RECONCILE => Request::Reconcile,
+        _ => return reply_error(stream, Box::new(SyncError::Protocol("unknown request"))),
+    };
+    debug!("Partition {}: serving {:?} request", part_name, request);
+    if version != PROTOCOL_VERSION {
+        return reply_error(stream, Box::new(SyncError::Protocol("protocol version mismatch")));
+    }
+    if request != Request::Clone && name != part_name {
+        return reply_error(stream, Box::new(SyncError::WrongRepo));
+    }
+    let ready = part.read(|p| -> Result<()> {
+        if !p.is_loaded() {
+            return Err(Box::new(TipError::NotReady));
+        }
+        if request == Request::Reconcile {
+            p.tip()?;
+        }
+        Ok(())
+    });
+    if let Err(e) = ready {
+        return reply_error(stream, e);
+    }
+
+    let commits = match request {
+        Request::Clone => {
+            let (data, commits) = match part.read(|p| clone_data(p, depth)) {
+                Ok(result) => result,
+                Err(e) => return reply_error(stream, e),
+            };
+            stream.write_all(OK)?;
+            stream.write_all(SNAPSHOT)?;
+            stream.write_u64::<BigEndian>(data.len() as u64)?;
+            stream.write_all(&data)?;
+            write_commits(&part_name, stream, &commits)?
+        },
+        Request::Fetch => {
+            stream.write_all(OK)?;
+            stream.flush()?;
+            let tips = part.read(|p| sorted_tips(p));
+            let peer_tips = exchange_tips(&tips, stream, false)?;
+            send_commits(part, stream, &peer_tips)?
+        },
+        Request::Push => {
+            stream.write_all(OK)?;
+            stream.flush()?;
+            let tips = part.read(|p| sorted_tips(p));
+            exchange_tips(&tips, stream, false)?;
+            receive_commits(part, stream)?
+        },
+        Request::Reconcile => {
+            stream.write_all(OK)?;
+            stream.flush()?;
+            reconcile::serve_reconcile(part, stream)?
+        },
+    };
+    Ok(Served { request: request, commits: commits })
+}
+
+/// Clone a partition from a server, connected via `stream`.
+///
+/// The state `depth` commits before the server's tip (following first
+/// parents) is written as the first snapshot via `control`'s `RepoIO` (which
+/// should be empty), then the partition is opened and the commits leading to
+/// the tip are added and written. History prior to this state is not copied;
+/// where it has parents, it is recorded in the shallow boundary (see
+/// `Partition::shallow_boundary`), as are merge commits whose other parents
+/// were not copied.
+pub fn clone<C: Control, S: Read + Write>(mut control: C, stream: &mut S, depth: usize) ->
+        Result<Partition<C>>
+{
+    send_request(stream, CLONE, "")?;
+    stream.write_u32::<BigEndian>(min(depth, u32::MAX as usize) as u32)?;
+    stream.flush()?;
+    read_reply(stream, "")?;
+    expect_tag(stream, SNAPSHOT)?;
+    let len = read_u64(stream)?;
+    let mut data = Vec::new();
+    stream.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return SyncError::protocol("snapshot truncated");
+    }
+
+    // Verify before writing:
+    {
+        let mut r = &data[..];
+        let header = read_head(&mut r)?;
+        match header.ftype {
+            FileType::Snapshot(ver) => {
+                let state = read_snapshot::<C::Element>(&mut r, ver)?;
+                debug!("Cloned partition {}: {}", header.name, state.statesum());
+            },
+            _ => return SyncError::protocol("expected a snapshot"),
+        }
+    }
+    match control.io_mut().new_ss(0)? {
+        Some(mut writer) => {
+            writer.write_all(&data)?;
+            writer.flush()?;
+        },
+        None => return OtherError::err("cannot clone: a snapshot already exists"),
+    }
+    let mut part = Partition::open(control, true)?;
+    let n = receive_commits(&mut part, stream)?;
+    debug!("Partition {}: cloned with {} commits", part.name(), n);
+    Ok(part)
+}
+
+/// Fetch commits from a server, connected via `stream`.
+///
+/// Commits not known locally are added and written (see
+/// `Partition::import_commits` and `write_fast`). Returns the tips which are
+/// new (usually with more than one tip; call `merge` to resolve).
+///
+/// Requires that the partition is loaded, and fails with `SyncError::NoHistory`
+/// where the server's history does not connect to the local history.
+pub fn fetch<C: Control, S: Read + Write>(part: &mut Partition<C>, stream: &mut S) ->
+        Result<Vec<Sum>>
+{
+    if !part.is_loaded() {
+        return Err(Box::new(TipError::NotReady));
+    }
+    let tips: HashSet<Sum> = part.tips_iter().cloned().collect();
+    handshake(stream, FETCH, part.name())?;
+    exchange_tips(&sorted_tips(part), stream, true)?;
+    let n = receive_commits(part, stream)?;
+    debug!("Partition {}: fetched {} commits", part.name(), n);
+    let mut new_tips: Vec<Sum> = part.tips_iter().filter(|tip| !tips.contains(tip))
+            .cloned().collect();
+    new_tips.sort();
+    Ok(new_tips)
+}
+
+/// Push commits to a server, connected via `stream`.
+///
+/// All commits leading to local tips which the server does not know are
+/// sent. Fails with `SyncError::NoHistory` if some of these are not available
+/// (e.g. if history is not loaded; see `Partition::load_all`). Returns the
+/// number of commits the server added.
+pub fn push<C: Control, S: Read + Write>(part: &Partition<C>, stream: &mut S) -> Result<usize> {
+    if !part.is_loaded() {
+        return Err(Box::new(TipError::NotReady));
+    }
+    handshake(stream, PUSH, part.name())?;
+    let peer_tips = exchange_tips(&sorted_tips(part), stream, true)?;
+    let n = send_commits(part, stream, &peer_tips)?;
+    debug!("Partition {}: pushed {} commits", part.name(), n);
+    Ok(n)
+}
+
+
+// Send the request, check the server's reply
+fn handshake<S: Read + Write>(stream: &mut S, request: &[u8; 8], name: &str) -> Result<()> {
+    send_request(stream, request, name)?;
+    stream.flush()?;
+    read_reply(stream, name)
+}
+fn send_request<S: Write>(stream: &mut S, request: &[u8; 8], name: &str) -> Result<()> {
+    stream.write_all(HELLO)?;
+    stream.write_u32::<BigEndian>(PROTOCOL_VERSION)?;
+    stream.write_all(request)?;
+    write_string(stream, name)
+}
+fn read_reply<S: Read>(stream: &mut S, name: &str) -> Result<()> {
+    expect_tag(stream, HELLO)?;
+    if read_u32(stream)? != PROTOCOL_VERSION {
+        return SyncError::protocol("protocol version mismatch");
+    }
+    let peer_name = read_string(stream)?;
+    if !name.is_empty() && peer_name != name {
+        return Err(Box::new(SyncError::WrongRepo));
+    }
+    expect_tag(stream, OK)
+}
+
+fn sorted_tips<C: Control>(part: &Partition<C>) -> Vec<Sum> {
+    let mut tips: Vec<Sum> = part.tips_iter().cloned().collect();
+    tips.sort();
+    tips
+}
+
+// Send tips and receive the peer's tips (the client sends first)
+fn exchange_tips<S: Read + Write>(tips: &[Sum], stream: &mut S, client: bool) ->
+        Result<Vec<Sum>>
+{
+    let send = |stream: &mut S| -> Result<()> {
+        stream.write_all(TIPS)?;
+        write_sums(stream, tips)?;
+        stream.flush()?;
+        Ok(())
+    };
+    if client {
+        send(stream)?;
+    }
+    expect_tag(stream, TIPS)?;
+    let peer_tips = read_sums(stream)?;
+    if !client {
+        send(stream)?;
+    }
+    Ok(peer_tips)
+}
+
+// Find which commits the peer lacks and send them. Returns the number of
+// commits the peer added.
+fn send_commits<P: PartAccess, S: Read + Write>(part: &P, stream: &mut S,
+        peer_tips: &[Sum]) -> Result<usize>
+{
+    // The peer knows its tips; we need not ask about these or their ancestors:
+    let peer_known: HashSet<&Sum> = peer_tips.iter().collect();
+    let mut visited = HashSet::new();
+    let mut frontier = VecDeque::new();
+    let (name, tips) = part.read(|p| (p.name().to_string(), sorted_tips(p)));
+    for tip in tips {
+        visited.insert(tip.clone());
+        frontier.push_back(tip);
+    }
+
+    // Walk back through history, in batches, until reaching states the peer knows:
+    let mut missing = vec![];
+    while !frontier.is_empty() {
+        let mut batch = vec![];
+        while batch.len() < QUERY_BATCH {
+            match frontier.pop_front() {
+                Some(key) => if !peer_known.contains(&key) { batch.push(key) },
+                None => break,
+            }
+        }
+        if batch.is_empty() {
+            continue;
+        }
+        stream.write_all(QUERY)?;
+        write_sums(stream, &batch)?;
+        stream.flush()?;
+        expect_tag(stream, KNOWN)?;
+        let mut known = vec![0; batch.len()];
+        stream.read_exact(&mut known)?;
+        part.read(|p| for (key, known) in batch.into_iter().zip(known) {
+            if known != 0 {
+                continue;
+            }
+            if let Some(parents) = p.parents_of(&key) {
+                for parent in parents {
+                    if p.is_known(parent) && visited.insert(parent.clone()) {
+                        frontier.push_back(parent.clone());
+                    }
+                }
+            }
+            missing.push(key);
+        });
+    }
+
+    // Parents were found after their children:
+    let result = part.read(|p| -> Result<Vec<_>> {
+        let mut commits = Vec::with_capacity(missing.len());
+        for key in missing.iter().rev() {
+            commits.push(p.commit(key)?.ok_or(SyncError::NoHistory)?);
+        }
+        Ok(commits)
+    });
+    match result {
+        Ok(commits) => write_commits(&name, stream, &commits),
+        Err(e) => reply_error(stream, e),
+    }
+}
+
+// Send commits (parents first), then read the number the peer added
+fn write_commits<E: Element, S: Read + Write>(name: &str, stream: &mut S,
+        commits: &[Commit<E>]) -> Result<usize>
+{
+    stream.write_all(COMMITS)?;
+    stream.write_u64::<BigEndian>(commits.len() as u64)?;
+    if !commits.is_empty() {
+        let header = FileHeader {
+            ftype: FileType::CommitLog(0),
+            name: name.to_string(),
+            user: vec![],
+            shallow: vec![],
+        };
+        write_head(&header, stream)?;
+        start_log(stream)?;
+        for commit in commits {
+            write_commit(commit, stream)?;
+        }
+    }
+    stream.flush()?;
+    trace!("Partition {}: sent {} commits", name, commits.len());
+
+    expect_tag(stream, OK)?;
+    Ok(read_u64(stream)? as usize)
+}
+
+// Make a snapshot file of the state up to `depth` commits before the tip,
+// and get the commits leading from this to the tip
+fn clone_data<C: Control>(part: &Partition<C>, depth: usize) ->
+        Result<(Vec<u8>, Vec<Commit<C::Element>>)>
+{
+    // States from the tip back along first parents:
+    let mut chain = vec![part.tip_key()?.clone()];
+    while chain.len() <= depth {
+        let parent = match part.parents_of(&chain[chain.len() - 1]).and_then(|p| p.first()) {
+            Some(parent) if part.is_known(parent) => parent.clone(),
+            _ => break,
+        };
+        chain.push(parent);
+    }
+    let base = part.state(&chain[chain.len() - 1]).ok_or(SyncError::NoHistory)?;
+    let mut commits = Vec::with_capacity(chain.len() - 1);
+    for key in chain[0..chain.len() - 1].iter().rev() {
+        commits.push(part.commit(key)?.ok_or(SyncError::NoHistory)?);
+    }
+
+    // The boundary: copied states with parents which are not copied
+    let copied: HashSet<&Sum> = chain.iter().collect();
+    let mut shallow: Vec<Sum> = chain.iter().filter(|key| {
+        part.parents_of(key).map_or(false, |parents| parents.iter().any(|p| !copied.contains(p)))
+    }).cloned().collect();
+    shallow.sort();
+
+    let header = FileHeader {
+        ftype: FileType::Snapshot(0),
+        name: part.name().to_string(),
+        user: vec![],
+        shallow: shallow,
+    };
+    let mut data = Vec::new();
+    write_head(&header, &mut data)?;
+    write_snapshot(&base, &mut data)?;
+    Ok((data, commits))
+}
+
+// Answer queries until commits are received, then add these and reply.
+// Returns the number of commits added, or fails with the sender's error.
+fn receive_commits<P: PartAccess, S: Read + Write>(part: &mut P, stream: &mut S) ->
+        Result<usize>
+{
+    let name = part.read(|p| p.name().to_string());
+    loop {
+        let tag = read_tag(stream)?;
+        if tag == *QUERY {
+            let keys = read_sums(stream)?;
+            let known: Vec<u8> = part.read(|p| {
+                keys.iter().map(|key| p.is_known(key) as u8).collect()
+            });
+            stream.write_all(KNOWN)?;
+            stream.write_all(&known)?;
+            stream.flush()?;
+        } else if tag == *COMMITS {
+            let n = read_u64(stream)? as usize;
+            let commits = if n > 0 {
+                read_commits(&name, stream, n)?
+            } else {
+                vec![]
+            };
+            let result = part.write(|p| {
+                p.import_commits(commits).map_err(|e| Box::new(e) as Error)
+                    .and_then(|n| { p.write_fast()?; Ok(n) })
+            });
+            return match result {
+                Ok(n) => {
+                    stream.write_all(OK)?;
+                    stream.write_u64::<BigEndian>(n as u64)?;
+                    stream.flush()?;
+                    Ok(n)
+                },
+                Err(e) => reply_error(stream, e),
+            };
+        } else if tag == *ERROR {
+            return Err(Box::new(SyncError::Remote(read_string(stream)?)));
+        } else {
+            return SyncError::protocol("unexpected message");
+        }
+    }
+}
+
+// Receives a given number of commits, then stops reading
+struct CommitCounter<E: Element> {
+    commits: Vec<Commit<E>>,
+    n: usize,
+}
+impl<E: Element> CommitReceiver<E> for CommitCounter<E> {
+    fn receive(&mut self, commit: Commit<E>) -> bool {
+        self.commits.push(commit);
+        self.commits.len() < self.n
+    }
+}
+
+// Read `n > 0` commits, sent as a commit log
+fn read_commits<E: Element, S: Read>(name: &str, stream: &mut S, n: usize) ->
+        Result<Vec<Commit<E>>>
+{
+    let header = read_head(stream)?;
+    if header.name != name {
+        return Err(Box::new(SyncError::WrongRepo));
+    }
+    let ver = match header.ftype {
+        FileType::CommitLog(ver) => ver,
+        _ => return SyncError::protocol("expected a commit log"),
+    };
+    let mut receiver = CommitCounter { commits: Vec::with_capacity(n), n: n };
+    read_log(stream, &mut receiver, ver)?;
+    if receiver.commits.len() != n {
+        return SyncError::protocol("commit stream truncated");
+    }
+    Ok(receiver.commits)
+}
+
+// Report an error to the peer (as far as possible), then fail with it
+fn reply_error<T, S: Write>(stream: &mut S, e: Error) -> Result<T> {
+    let result = stream.write_all(ERROR).map_err(|e| Box::new(e) as Error)
+        .and_then(|_| write_string(stream, &e.to_string()))
+        .and_then(|_| stream.flush().map_err(|e| Box::new(e) as Error));
+    if let Err(e2) = result {
+        warn!("Unable to report error to peer: {}", e2);
+    }
+    Err(e)
+}
+
+fn read_tag<S: Read>(stream: &mut S) -> Result<[u8; 8]> {
+    let mut tag = [0u8; 8];
+    stream.read_exact(&mut tag)?;
+    Ok(tag)
+}
+// Read a tag, failing if it is not `expected` (or with the peer's error)
+fn expect_tag<S: Read>(stream: &mut S, expected: &[u8; 8]) -> Result<()> {
+    let tag = read_tag(stream)?;
+    if tag == *expected {
+        Ok(())
+    } else if tag == *ERROR {
+        Err(Box::new(SyncError::Remote(read_string(stream)?)))
+    } else {
+        SyncError::protocol("unexpected message")
+    }
+}
+fn read_u32<S: Read>(stream: &mut S) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(BigEndian::read_u32(&buf))
+}
+fn read_u64<S: Read>(stream: &mut S) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(BigEndian::read_u64(&buf))
+}
+fn write_string<S: Write>(stream: &mut S, s: &str) -> Result<()> {
+    stream.write_u32::<BigEndian>(s.len() as u32)?;
+    stream.write_all(s.as_bytes())?;
+    Ok(())
+}
+fn read_string<S: Read>(stream: &mut S) -> Result<String> {
+    let len = read_u32(stream)? as usize;
+    if len > MAX_STRING {
+        return SyncError::protocol("string too long");
+    }
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+fn write_sums<S: Write>(stream: &mut S, sums: &[Sum]) -> Result<()> {
+    stream.write_u32::<BigEndian>(sums.len() as u32)?;
+    for sum in sums {
+        sum.write_to(stream)?;
+    }
+    Ok(())
+}
+fn read_sum<S: Read>(stream: &mut S) -> Result<Sum> {
+    let mut buf = [0u8; SUM_BYTES];
+    stream.read_exact(&mut buf)?;
+    Ok(Sum::load(&buf))
+}
+fn read_sums<S: Read>(stream: &mut S) -> Result<Vec<Sum>> {
+    let n = read_u32(stream)? as usize;
+    if n > MAX_SUMS {
+        return SyncError::protocol("too many sums");
+    }
+    let mut sums = Vec::with_capacity(n);
+    for _ in 0..n {
+        sums.push(read_sum(stream)?);
+    }
+    Ok(sums)
+}
@@ -0,0 +1,493 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Range-based reconciliation of element content (see `reconcile`).
+//!
+//! The state sum is the XOR of element sums (and a metadata sum); the XOR of
+//! the element sums of elements whose identifiers fall in some range thus
+//! summarises that part of the state, and can be compared cheaply with the
+//! same range of another replica's state. Differing ranges are split and
+//! compared again until they are small, whereupon element identifiers and
+//! sums are listed, and finally the differing elements are transferred.
+
+use std::collections::{HashMap, BTreeSet};
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::u64;
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use control::Control;
+use elt::{Element, EltId};
+use error::{Result, ElementOp, SyncError, TipError, MergeError};
+use merge::{EltMerge, TwoWaySolver};
+use part::Partition;
+use state::{PartState, StateRead, StateWrite};
+use sum::Sum;
+use super::{PartAccess, RECONCILE, OK, MAX_SUMS, handshake, expect_tag, read_tag, reply_error, read_u32,
+        read_u64, read_sum};
+
+const RANGES: &'static [u8; 8] = b"RANGES\x00\x00";
+const FPRINTS: &'static [u8; 8] = b"FPRINTS\x00";
+const LIST: &'static [u8; 8] = b"LIST\x00\x00\x00\x00";
+const ITEMS: &'static [u8; 8] = b"ITEMS\x00\x00\x00";
+const GET: &'static [u8; 8] = b"GET\x00\x00\x00\x00\x00";
+const ELTS: &'static [u8; 8] = b"ELTS\x00\x00\x00\x00";
+const PUT: &'static [u8; 8] = b"PUT\x00\x00\x00\x00\x00";
+
+// Number of sub-ranges a differing range is split into
+const SPLIT: u64 = 16;
+// Ranges holding at most this many elements (on either side) are listed
+const LEAF_SIZE: usize = 16;
+// Limit on element length read, to fail early on nonsense input
+const MAX_ELT_LEN: u64 = 1 << 30;
+
+/// Summary of a reconciliation (see `reconcile`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reconciled {
+    /// Number of identifier ranges compared
+    pub ranges: usize,
+    /// Number of elements inserted, replaced or removed locally
+    pub local: usize,
+    /// Number of elements inserted, replaced or removed by the server
+    pub remote: usize,
+}
+
+/// Reconcile element content with that of a server, connected via `stream`,
+/// without using history (the replicas need have no common history).
+///
+/// The tips of both partitions are compared by ranges of element
+/// identifiers, and elements which differ are exchanged. An element present
+/// on only one side is copied to the other (since there is no history, the
+/// absence of an element cannot be distinguished from its removal). Where
+/// both sides hold different versions of an element, `solver` chooses (it
+/// is called with the local element as `a`, the server's as `b` and no
+/// common ancestor); `EltMerge::Rename` is not supported and, like
+/// `EltMerge::Fail`, causes reconciliation to fail without changes.
+///
+/// Changes are made by a new commit on each side's tip, which is written
+/// (see `write_fast`); afterwards the tips of both sides hold the same
+/// elements. Requires that both partitions have a single tip.
+pub fn reconcile<C: Control, S: Read + Write, V: TwoWaySolver<C::Element>>(
+        part: &mut Partition<C>, stream: &mut S, solver: &V) -> Result<Reconciled>
+{
+    let index = RangeIndex::new(part.tip()?);
+    handshake(stream, RECONCILE, part.name())?;
+
+    // Compare fingerprints, splitting differing ranges until small:
+    let mut num_ranges = 0;
+    let mut ranges = vec![(0, u64::MAX)];
+    let mut leaves = vec![];
+    while !ranges.is_empty() {
+        num_ranges += ranges.len();
+        stream.write_all(RANGES)?;
+        write_ranges(stream, &ranges)?;
+        stream.flush()?;
+        expect_tag(stream, FPRINTS)?;
+        let n = read_u32(stream)? as usize;
+        if n != ranges.len() {
+            return SyncError::protocol("wrong number of fingerprints");
+        }
+        let mut next = vec![];
+        for (lo, hi) in ranges {
+            let remote = (read_sum(stream)?, read_u64(stream)? as usize);
+            let local = index.fingerprint(lo, hi);
+            if local == remote {
+                continue;
+            }
+            if local.1 <= LEAF_SIZE || remote.1 <= LEAF_SIZE || lo == hi {
+                leaves.push((lo, hi));
+            } else {
+                next.extend(split(lo, hi));
+            }
+        }
+        ranges = next;
+    }
+    trace!("Partition {}: compared {} ranges; {} differ", part.name(), num_ranges, leaves.len());
+
+    // List differing ranges to find differing elements:
+    let mut remote_sums = HashMap::new();
+    if !leaves.is_empty() {
+        stream.write_all(LIST)?;
+        write_ranges(stream, &leaves)?;
+        stream.flush()?;
+        expect_tag(stream, ITEMS)?;
+        for _ in 0..leaves.len() {
+            let n = read_u32(stream)? as usize;
+            if n > MAX_SUMS {
+                return SyncError::protocol("too many items");
+            }
+            for _ in 0..n {
+                let id = EltId::from(read_u64(stream)?);
+                remote_sums.insert(id, read_sum(stream)?);
+            }
+        }
+    }
+    let mut differ = BTreeSet::new();
+    for &(lo, hi) in &leaves {
+        for &(id, ref sum) in index.items(lo, hi) {
+            if remote_sums.get(&EltId::from(id)) != Some(sum) {
+                differ.insert(EltId::from(id));
+            }
+        }
+    }
+    let mut wanted = vec![];
+    for (id, sum) in &remote_sums {
+        if index.get(*id) != Some(sum) {
+            differ.insert(*id);
+            wanted.push(*id);
+        }
+    }
+    wanted.sort();
+
+    // Get the server's versions of these:
+    let mut remote_elts = HashMap::new();
+    if !wanted.is_empty() {
+        stream.write_all(GET)?;
+        write_ids(stream, &wanted)?;
+        stream.flush()?;
+        expect_tag(stream, ELTS)?;
+        for id in wanted {
+            let elt = read_elt::<C::Element, _>(stream, id, &remote_sums[&id])?;
+            remote_elts.insert(id, Arc::new(elt));
+        }
+    }
+
+    // Decide the result for each differing element:
+    let mut local_changes = vec![];
+    let mut remote_changes = vec![];
+    {
+        let tip = part.tip()?;
+        for id in differ {
+            let local = match tip.get_rc(id) {
+                Ok(elt) => Some(elt),
+                Err(ElementOp::EltNotFound) => None,
+                Err(e) => return reply_error(stream, Box::new(e)),
+            };
+            let remote = remote_elts.get(&id);
+            match (local, remote) {
+                (Some(elt), None) => remote_changes.push((id, Some(elt.clone()))),
+                (None, Some(elt)) => local_changes.push((id, Some(elt.clone()))),
+                (Some(a), Some(b)) => match solver.solve(Some(a), Some(b), None) {
+                    EltMerge::A => remote_changes.push((id, Some(a.clone()))),
+                    EltMerge::B => local_changes.push((id, Some(b.clone()))),
+                    EltMerge::Value(elt) => {
+                        local_changes.push((id, Some(elt.clone())));
+                        remote_changes.push((id, Some(elt)));
+                    },
+                    EltMerge::Delete => {
+                        local_changes.push((id, None));
+                        remote_changes.push((id, None));
+                    },
+                    EltMerge::Rename | EltMerge::Fail => {
+                        return reply_error(stream, Box::new(MergeError::NotSolved));
+                    },
+                },
+                (None, None) => {},
+            }
+        }
+    }
+
+    // Send changes to the server, then apply locally:
+    stream.write_all(PUT)?;
+    write_changes(stream, &remote_changes)?;
+    stream.flush()?;
+    expect_tag(stream, OK)?;
+    let remote = read_u64(stream)? as usize;
+    let local = apply_changes(part, local_changes)?;
+    debug!("Partition {}: reconciled {} ranges; changed {} elements locally and {} remotely",
+            part.name(), num_ranges, local, remote);
+    Ok(Reconciled { ranges: num_ranges, local: local, remote: remote })
+}
+
+// Answer a client's queries about the tip until it sends changes, then apply
+// these (failing if the tip has since changed). Returns the number of
+// elements changed.
+pub fn serve_reconcile<P: PartAccess, S: Read + Write>(part: &mut P, stream: &mut S) ->
+        Result<usize>
+{
+    let tip = part.read(|p| -> Result<_> {
+        Ok(p.state(p.tip_key()?).ok_or(TipError::NotReady)?)
+    })?;
+    let index = RangeIndex::new(&tip);
+    loop {
+        let tag = read_tag(stream)?;
+        if tag == *RANGES {
+            let ranges = read_ranges(stream)?;
+            stream.write_all(FPRINTS)?;
+            stream.write_u32::<BigEndian>(ranges.len() as u32)?;
+            for (lo, hi) in ranges {
+                let (sum, n) = index.fingerprint(lo, hi);
+                sum.write_to(stream)?;
+                stream.write_u64::<BigEndian>(n as u64)?;
+            }
+            stream.flush()?;
+        } else if tag == *LIST {
+            let ranges = read_ranges(stream)?;
+            stream.write_all(ITEMS)?;
+            for (lo, hi) in ranges {
+                let items = index.items(lo, hi);
+                stream.write_u32::<BigEndian>(items.len() as u32)?;
+                for &(id, ref sum) in items {
+                    stream.write_u64::<BigEndian>(id)?;
+                    sum.write_to(stream)?;
+                }
+            }
+            stream.flush()?;
+        } else if tag == *GET {
+            let ids = read_ids(stream)?;
+            let mut elts = Vec::with_capacity(ids.len());
+            for id in ids {
+                match tip.get_rc(id) {
+                    Ok(elt) => elts.push(elt.clone()),
+                    Err(e) => return reply_error(stream, Box::new(e)),
+                }
+            }
+            stream.write_all(ELTS)?;
+            let mut buf = Vec::new();
+            for elt in elts {
+                write_elt(stream, &*elt, &mut buf)?;
+            }
+            stream.flush()?;
+        } else if tag == *PUT {
+            let changes = read_changes(stream)?;
+            let result = part.write(|p| -> Result<usize> {
+                if !changes.is_empty() && p.tip_key()? != tip.statesum() {
+                    return Err(Box::new(SyncError::TipChanged));
+                }
+                apply_changes(p, changes)
+            });
+            return match result {
+                Ok(n) => {
+                    stream.write_all(OK)?;
+                    stream.write_u64::<BigEndian>(n as u64)?;
+                    stream.flush()?;
+                    Ok(n)
+                },
+                Err(e) => reply_error(stream, e),
+            };
+        } else {
+            return SyncError::protocol("unexpected message");
+        }
+    }
+}
+
+// Apply changes via a new commit on the tip, and write. Returns the number
+// of elements changed.
+fn apply_changes<C: Control>(part: &mut Partition<C>,
+        changes: Vec<(EltId, Option<Arc<C::Element>>)>) -> Result<usize>
+{
+    if changes.is_empty() {
+        return Ok(0);
+    }
+    let n = changes.len();
+    let mut state = part.tip()?.clone_mut();
+    for (id, change) in changes {
+        match (change, state.is_avail(id)) {
+            (Some(elt), true) => { state.replace_rc(id, elt)?; },
+            (Some(elt), false) => { state.insert_rc(id, elt)?; },
+            (None, true) => { state.remove(id)?; },
+            (None, false) => {},
+        }
+    }
+    part.push_state(state)?;
+    part.write_fast()?;
+    Ok(n)
+}
+
+
+// Element sums of a state in order of identifier, with cumulative XORs
+// allowing the XOR over any range to be found in O(log n) time
+struct RangeIndex {
+    items: Vec<(u64, Sum)>,
+    // cumulative[i] is the XOR of the sums of items[0..i]
+    cumulative: Vec<Sum>,
+}
+impl RangeIndex {
+    fn new<E: Element>(state: &PartState<E>) -> RangeIndex {
+        let mut items: Vec<(u64, Sum)> = state.elt_sums_iter()
+                .map(|(id, sum)| (id.into(), sum.clone())).collect();
+        items.sort_by_key(|item| item.0);
+        let mut cumulative = Vec::with_capacity(items.len() + 1);
+        let mut sum = Sum::zero();
+        cumulative.push(sum.clone());
+        for item in &items {
+            sum.permute(&item.1);
+            cumulative.push(sum.clone());
+        }
+        RangeIndex { items: items, cumulative: cumulative }
+    }
+    // Indices of items in the range `lo..=hi`
+    fn bounds(&self, lo: u64, hi: u64) -> (usize, usize) {
+        let i = match self.items.binary_search_by_key(&lo, |item| item.0) {
+            Ok(i) | Err(i) => i,
+        };
+        let j = match self.items.binary_search_by_key(&hi, |item| item.0) {
+            Ok(j) => j + 1,
+            Err(j) => j,
+        };
+        (i, j.max(i))
+    }
+    // XOR of element sums and number of elements in a range
+    fn fingerprint(&self, lo: u64, hi: u64) -> (Sum, usize) {
+        let (i, j) = self.bounds(lo, hi);
+        (&self.cumulative[i] ^ &self.cumulative[j], j - i)
+    }
+    fn items(&self, lo: u64, hi: u64) -> &[(u64, Sum)] {
+        let (i, j) = self.bounds(lo, hi);
+        &self.items[i..j]
+    }
+    fn get(&self, id: EltId) -> Option<&Sum> {
+        let id: u64 = id.into();
+        self.items.binary_search_by_key(&id, |item| item.0).ok().map(|i| &self.items[i].1)
+    }
+}
+
+// Split the range `lo..=hi` (where `lo < hi`) into up to `SPLIT` parts
+fn split(lo: u64, hi: u64) -> Vec<(u64, u64)> {
+    let step = (hi - lo) / SPLIT + 1;
+    let mut parts = Vec::with_capacity(SPLIT as usize);
+    let mut start = lo;
+    loop {
+        let end = start.saturating_add(step - 1).min(hi);
+        parts.push((start, end));
+        if end == hi {
+            return parts;
+        }
+        start = end + 1;
+    }
+}
+
+fn write_ranges<S: Write>(stream: &mut S, ranges: &[(u64, u64)]) -> Result<()> {
+    stream.write_u32::<BigEndian>(ranges.len() as u32)?;
+    for &(lo, hi) in ranges {
+        stream.write_u64::<BigEndian>(lo)?;
+        stream.write_u64::<BigEndian>(hi)?;
+    }
+    Ok(())
+}
+fn read_ranges<S: Read>(stream: &mut S) -> Result<Vec<(u64, u64)>> {
+    let n = read_u32(stream)? as usize;
+    if n > MAX_SUMS {
+        return SyncError::protocol("too many ranges");
+    }
+    let mut ranges = Vec::with_capacity(n);
+    for _ in 0..n {
+        let (lo, hi) = (read_u64(stream)?, read_u64(stream)?);
+        if lo > hi {
+            return SyncError::protocol("invalid range");
+        }
+        ranges.push((lo, hi));
+    }
+    Ok(ranges)
+}
+fn write_ids<S: Write>(stream: &mut S, ids: &[EltId]) -> Result<()> {
+    stream.write_u32::<BigEndian>(ids.len() as u32)?;
+    for &id in ids {
+        stream.write_u64::<BigEndian>(id.into())?;
+    }
+    Ok(())
+}
+fn read_ids<S: Read>(stream: &mut S) -> Result<Vec<EltId>> {
+    let n = read_u32(stream)? as usize;
+    if n > MAX_SUMS {
+        return SyncError::protocol("too many identifiers");
+    }
+    let mut ids = Vec::with_capacity(n);
+    for _ in 0..n {
+        ids.push(EltId::from(read_u64(stream)?));
+    }
+    Ok(ids)
+}
+// Write an element, leaving its serialised data in `buf`
+fn write_elt<E: Element, S: Write>(stream: &mut S, elt: &E, buf: &mut Vec<u8>) -> Result<()> {
+    buf.clear();
+    elt.write_buf(buf)?;
+    stream.write_u64::<BigEndian>(buf.len() as u64)?;
+    stream.write_all(buf)?;
+    Ok(())
+}
+// Read an element, checking its sum
+fn read_elt<E: Element, S: Read>(stream: &mut S, id: EltId, sum: &Sum) -> Result<E> {
+    let len = read_u64(stream)?;
+    if len > MAX_ELT_LEN {
+        return SyncError::protocol("element too long");
+    }
+    let mut data = vec![0; len as usize];
+    stream.read_exact(&mut data)?;
+    if Sum::elt_sum(id, &data) != *sum {
+        return SyncError::protocol("element checksum mismatch");
+    }
+    E::from_vec_sum(data, sum.clone())
+}
+// Changes are sent as identifier, then a flag: 0 to remove, or 1 followed
+// by the element and its sum.
+fn write_changes<E: Element, S: Write>(stream: &mut S,
+        changes: &[(EltId, Option<Arc<E>>)]) -> Result<()>
+{
+    stream.write_u32::<BigEndian>(changes.len() as u32)?;
+    let mut buf = Vec::new();
+    for &(id, ref change) in changes {
+        stream.write_u64::<BigEndian>(id.into())?;
+        match *change {
+            Some(ref elt) => {
+                stream.write_u8(1)?;
+                write_elt(stream, &**elt, &mut buf)?;
+                Sum::elt_sum(id, &buf).write_to(stream)?;
+            },
+            None => stream.write_u8(0)?,
+        }
+    }
+    Ok(())
+}
+fn read_changes<E: Element, S: Read>(stream: &mut S) -> Result<Vec<(EltId, Option<Arc<E>>)>> {
+    let n = read_u32(stream)? as usize;
+    if n > MAX_SUMS {
+        return SyncError::protocol("too many changes");
+    }
+    let mut changes = Vec::with_capacity(n);
+    for _ in 0..n {
+        let id = EltId::from(read_u64(stream)?);
+        let mut flag = [0u8];
+        stream.read_exact(&mut flag)?;
+        let change = match flag[0] {
+            0 => None,
+            1 => {
+                let len = read_u64(stream)?;
+                if len > MAX_ELT_LEN {
+                    return SyncError::protocol("element too long");
+                }
+                let mut data = vec![0; len as usize];
+                stream.read_exact(&mut data)?;
+                let sum = read_sum(stream)?;
+                if Sum::elt_sum(id, &data) != sum {
+                    return SyncError::protocol("element checksum mismatch");
+                }
+                Some(Arc::new(E::from_vec_sum(data, sum)?))
+            },
+            _ => return SyncError::protocol("invalid change"),
+        };
+        changes.push((id, change));
+    }
+    Ok(changes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::split;
+    use std::u64;
+
+    #[test]
+    fn split_ranges() {
+        assert_eq!(split(0, 31), (0..16).map(|i| (2 * i, 2 * i + 1)).collect::<Vec<_>>());
+        assert_eq!(split(5, 7), vec![(5, 5), (6, 6), (7, 7)]);
+        let parts = split(0, u64::MAX);
+        assert_eq!(parts.len(), 16);
+        assert_eq!(parts[15].1, u64::MAX);
+        for w in parts.windows(2) {
+            assert_eq!(w[0].1 + 1, w[1].0);
+        }
+    }
+}
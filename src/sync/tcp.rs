@@ -0,0 +1,394 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Synchronisation over TCP (see parent module for the protocol).
+
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use control::Control;
+use error::Result;
+use merge::TwoWaySolver;
+use part::Partition;
+use shared::SharedPartition;
+use sum::Sum;
+use sync::{self, Served, Reconciled};
+
+
+// Default for `TcpServer::set_timeout`
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+/// Serves requests on a partition to clients connecting via TCP.
+///
+/// Connections are handled one at a time. The partition is only locked for
+/// short steps of each request (see `sync::PartAccess`), and reads and
+/// writes on a connection time out (see `set_timeout`), thus slow clients do
+/// not block other users of the partition. Modifications made by push
+/// requests are published to readers of the `SharedPartition`.
+pub struct TcpServer<C: Control> {
+    listener: TcpListener,
+    part: Arc<SharedPartition<C>>,
+    timeout: Option<Duration>,
+}
+
+impl<C: Control> TcpServer<C> {
+    /// Listen on the given address (use port 0 to have one assigned; see
+    /// `local_addr`).
+    pub fn bind<A: ToSocketAddrs>(addr: A, part: Arc<SharedPartition<C>>) -> Result<TcpServer<C>> {
+        Ok(TcpServer {
+            listener: TcpListener::bind(addr)?,
+            part: part,
+            timeout: Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS)),
+        })
+    }
+
+    /// Set the timeout applied to each read and write on a connection,
+    /// after which the request fails (`None` to wait indefinitely). The
+    /// default is one minute. A zero duration is an error when serving.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Get the address listened on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept a single connection and serve its request.
+    pub fn serve_one(&self) -> Result<Served> {
+        let (stream, addr) = self.listener.accept()?;
+        debug!("Sync connection from {}", addr);
+        self.serve_stream(stream)
+    }
+
+    /// Serve connections until accepting fails. Failed requests are logged
+    /// and otherwise ignored.
+    pub fn run(&self) -> Result<()> {
+        loop {
+            let (stream, addr) = self.listener.accept()?;
+            match self.serve_stream(stream) {
+                Ok(served) => info!("Sync: served {:?} to {}", served, addr),
+                Err(e) => warn!("Sync: request from {} failed: {}", addr, e),
+            }
+        }
+    }
+
+    fn serve_stream(&self, stream: TcpStream) -> Result<Served> {
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+        let mut stream = BufStream::new(stream)?;
+        sync::serve(&mut &*self.part, &mut stream)
+    }
+}
+
+/// Clone a partition from a server at the given address, with `depth`
+/// commits of history (see `sync::clone`).
+pub fn clone<C: Control, A: ToSocketAddrs>(control: C, addr: A, depth: usize) ->
+        Result<Partition<C>>
+{
+    let mut stream = BufStream::connect(addr)?;
+    sync::clone(control, &mut stream, depth)
+}
+
+/// Fetch commits from a server at the given address (see `sync::fetch`).
+pub fn fetch<C: Control, A: ToSocketAddrs>(part: &mut Partition<C>, addr: A) -> Result<Vec<Sum>> {
+    let mut stream = BufStream::connect(addr)?;
+    sync::fetch(part, &mut stream)
+}
+
+/// Push commits to a server at the given address (see `sync::push`).
+pub fn push<C: Control, A: ToSocketAddrs>(part: &Partition<C>, addr: A) -> Result<usize> {
+    let mut stream = BufStream::connect(addr)?;
+    sync::push(part, &mut stream)
+}
+
+/// Reconcile element content with a server at the given address (see
+/// `sync::reconcile`).
+pub fn reconcile<C: Control, A: ToSocketAddrs, V: TwoWaySolver<C::Element>>(
+        part: &mut Partition<C>, addr: A, solver: &V) -> Result<Reconciled>
+{
+    let mut stream = BufStream::connect(addr)?;
+    sync::reconcile(part, &mut stream, solver)
+}
+
+
+// A TCP stream with buffered reads and writes
+struct BufStream {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+impl BufStream {
+    fn new(stream: TcpStream) -> Result<BufStream> {
+        Ok(BufStream {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+    fn connect<A: ToSocketAddrs>(addr: A) -> Result<BufStream> {
+        BufStream::new(TcpStream::connect(addr)?)
+    }
+}
+impl Read for BufStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+impl Write for BufStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use control::DefaultControl;
+    use elt::EltId;
+    use error::{SyncError, MergeError};
+    use io::RepoIO;
+    use io::mem::MemRepoIO;
+    use merge::{AncestorSolver2W, TwoWaySolveUseB};
+    use part::Partition;
+    use rw::header::{FileHeader, FileType, write_head};
+    use rw::snapshot::write_snapshot;
+    use shared::SharedPartition;
+    use state::{StateRead, StateWrite};
+    use super::*;
+
+    #[test]
+    fn clone_push_fetch() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "sync test").unwrap();
+        {
+            let mut state = part.tip().unwrap().clone_mut();
+            state.insert(EltId::from(1), "one".to_string()).unwrap();
+            state.insert(EltId::from(2), "two".to_string()).unwrap();
+            part.push_state(state).unwrap();
+        }
+        part.write_full().unwrap();
+        let shared = Arc::new(SharedPartition::new(part));
+        let server = TcpServer::bind("127.0.0.1:0", shared.clone()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            for _ in 0..4 {
+                let _ = server.serve_one();
+            }
+        });
+
+        // Clone, then modify both replicas:
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut local = clone(control, addr, 0).unwrap();
+        assert_eq!(local.name(), "sync test");
+        assert_eq!(local.tip_key().unwrap(), shared.snapshot().unwrap().statesum());
+        {
+            let mut state = local.tip().unwrap().clone_mut();
+            state.insert(EltId::from(3), "three".to_string()).unwrap();
+            local.push_state(state).unwrap();
+        }
+        {
+            let mut part = shared.writer();
+            let mut state = part.tip().unwrap().clone_mut();
+            state.replace(EltId::from(1), "uno".to_string()).unwrap();
+            part.push_state(state).unwrap();
+        }
+
+        // Fetch the remote change, merge and push the result:
+        let new_tips = fetch(&mut local, addr).unwrap();
+        assert_eq!(new_tips.len(), 1);
+        assert_eq!(local.tips_len(), 2);
+        local.merge(&AncestorSolver2W::new(), true).unwrap();
+        let merged = local.tip_key().unwrap().clone();
+        assert_eq!(push(&local, addr).unwrap(), 2);
+        assert_eq!(push(&local, addr).unwrap(), 0);
+
+        let snapshot = shared.snapshot().unwrap();
+        assert_eq!(*snapshot.statesum(), merged);
+        assert_eq!(snapshot.get(EltId::from(1)).unwrap(), "uno");
+        assert_eq!(snapshot.get(EltId::from(3)).unwrap(), "three");
+        handle.join().unwrap();
+
+        // A replica of another repository is refused:
+        let server = TcpServer::bind("127.0.0.1:0", shared.clone()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || server.serve_one().is_err());
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut other = Partition::create(control, "other").unwrap();
+        let e = fetch(&mut other, addr).unwrap_err();
+        assert_eq!(e.downcast_ref::<SyncError>(), Some(&SyncError::WrongRepo));
+        assert!(handle.join().unwrap());
+    }
+    
+    #[test]
+    fn fetch_unrelated() {
+        // The server's history starts from a snapshot the client does not know:
+        let io = MemRepoIO::new();
+        {
+            let mut part = Partition::create(DefaultControl::<String, _>::new(io.clone()),
+                    "unrelated").unwrap();
+            let mut state = part.tip().unwrap().clone_mut();
+            state.insert(EltId::from(1), "server".to_string()).unwrap();
+            part.push_state(state).unwrap();
+            part.write_snapshot().unwrap();
+        }
+        let part = Partition::open(DefaultControl::<String, _>::new(io), true).unwrap();
+        let shared = Arc::new(SharedPartition::new(part));
+        let server = TcpServer::bind("127.0.0.1:0", shared.clone()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || server.serve_one().unwrap_err().to_string());
+        
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut local = Partition::create(control, "unrelated").unwrap();
+        let tip = local.tip_key().unwrap().clone();
+        // The client is told why rather than seeing the connection close:
+        let e = fetch(&mut local, addr).unwrap_err();
+        let msg = SyncError::NoHistory.to_string();
+        assert_eq!(e.downcast_ref::<SyncError>(), Some(&SyncError::Remote(msg.clone())));
+        assert_eq!(handle.join().unwrap(), msg);
+        assert_eq!(local.tips_iter().collect::<Vec<_>>(), vec![&tip]);
+    }
+    
+    #[test]
+    fn slow_client() {
+        use std::time::Duration;
+        use sync::{FETCH, send_request, read_reply};
+        
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let part = Partition::create(control, "slow").unwrap();
+        let shared = Arc::new(SharedPartition::new(part));
+        let mut server = TcpServer::bind("127.0.0.1:0", shared.clone()).unwrap();
+        server.set_timeout(Some(Duration::from_millis(200)));
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || server.serve_one().is_err());
+        
+        // A client stalling mid-request does not hold the partition:
+        let mut stream = BufStream::connect(addr).unwrap();
+        send_request(&mut stream, FETCH, "slow").unwrap();
+        stream.flush().unwrap();
+        read_reply(&mut stream, "slow").unwrap();
+        {
+            let mut part = shared.writer();
+            let mut state = part.tip().unwrap().clone_mut();
+            state.insert(EltId::from(1), "one".to_string()).unwrap();
+            part.push_state(state).unwrap();
+        }
+        // ... and the server gives up on it:
+        assert!(handle.join().unwrap());
+        assert_eq!(shared.snapshot().unwrap().num_avail(), 1);
+    }
+    
+    #[test]
+    fn shallow_clone() {
+        let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+        let mut part = Partition::create(control, "shallow").unwrap();
+        let mut keys = vec![part.tip_key().unwrap().clone()];
+        for i in 1..4 {
+            let mut state = part.tip().unwrap().clone_mut();
+            state.insert(EltId::from(i), format!("element {}", i)).unwrap();
+            part.push_state(state).unwrap();
+            keys.push(part.tip_key().unwrap().clone());
+        }
+        let shared = Arc::new(SharedPartition::new(part));
+        let server = TcpServer::bind("127.0.0.1:0", shared.clone()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || server.serve_one().unwrap());
+        
+        // Copy the last two commits only:
+        let io = MemRepoIO::new();
+        let local = clone(DefaultControl::<String, _>::new(io.clone()), addr, 2).unwrap();
+        assert_eq!(handle.join().unwrap().commits, 2);
+        assert_eq!(local.tip_key().unwrap(), &keys[3]);
+        assert!(local.is_known(&keys[1]) && !local.is_known(&keys[0]));
+        assert_eq!(local.shallow_boundary(), vec![keys[1].clone()]);
+        
+        // Branch from before the boundary on the server, and add this
+        // branch to the clone as a snapshot:
+        let branch = {
+            let mut part = shared.writer();
+            let mut state = part.state(&keys[0]).unwrap().clone_mut();
+            state.insert(EltId::from(9), "branch".to_string()).unwrap();
+            part.push_state(state).unwrap();
+            let key = part.tips_iter().find(|key| **key != keys[3]).unwrap().clone();
+            part.state(&key).unwrap()
+        };
+        {
+            let mut io = io.clone();
+            let mut writer = io.new_ss(1).unwrap().unwrap();
+            let header = FileHeader {
+                ftype: FileType::Snapshot(0),
+                name: "shallow".to_string(),
+                user: vec![],
+                shallow: vec![],
+            };
+            write_head(&header, &mut writer).unwrap();
+            write_snapshot(&branch, &mut writer).unwrap();
+        }
+        
+        // The boundary is persisted; merging cannot reach the common ancestor:
+        let mut local = Partition::open(DefaultControl::<String, _>::new(io), true).unwrap();
+        local.load_all().unwrap();
+        assert_eq!(local.tips_len(), 2);
+        assert_eq!(local.shallow_boundary(), vec![keys[1].clone()]);
+        assert_eq!(local.merge_two(branch.statesum(), &keys[3]).err(),
+                Some(MergeError::HistoryTruncated));
+        let e = local.merge(&AncestorSolver2W::new(), true).unwrap_err();
+        assert_eq!(e.downcast_ref::<MergeError>(), Some(&MergeError::HistoryTruncated));
+    }
+    
+    #[test]
+    fn reconcile_without_history() {
+        // Two partitions with the same name but separate histories:
+        let make = |elts: &[(u64, &str)]| {
+            let control = DefaultControl::<String, _>::new(MemRepoIO::new());
+            let mut part = Partition::create(control, "reconcile").unwrap();
+            let mut state = part.tip().unwrap().clone_mut();
+            for &(id, elt) in elts {
+                state.insert(EltId::from(id), elt.to_string()).unwrap();
+            }
+            part.push_state(state).unwrap();
+            part
+        };
+        // Spread identifiers over the whole range:
+        let id = |i: u64| i.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        let common: Vec<String> = (0..300).map(|i| format!("element {}", i)).collect();
+        let mut elts1: Vec<(u64, &str)> = (0..300).map(|i| (id(i), &common[i as usize][..]))
+                .collect();
+        let mut elts2 = elts1.clone();
+        elts1.extend_from_slice(&[(id(1000), "server only"), (id(1001), "server only")]);
+        elts2.push((id(1002), "client only"));
+        elts1[7].1 = "server version";
+        elts2[7].1 = "client version";
+        let server_part = make(&elts1);
+        let mut local = make(&elts2);
+        assert!(server_part.tip_key().unwrap() != local.tip_key().unwrap());
+        
+        let shared = Arc::new(SharedPartition::new(server_part));
+        let server = TcpServer::bind("127.0.0.1:0", shared.clone()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || server.serve_one().unwrap());
+        
+        let result = reconcile(&mut local, addr, &TwoWaySolveUseB::new()).unwrap();
+        assert_eq!((result.local, result.remote), (3, 1));
+        // Not everything was compared:
+        assert!(result.ranges < 300);
+        assert_eq!(handle.join().unwrap().commits, 1);
+        
+        let snapshot = shared.snapshot().unwrap();
+        let tip = local.tip().unwrap();
+        assert_eq!(tip.num_avail(), 303);
+        assert_eq!(snapshot.num_avail(), 303);
+        for (id, elt) in tip.elts_iter() {
+            assert_eq!(snapshot.get(id).unwrap(), &**elt);
+        }
+        assert_eq!(tip.get(EltId::from(id(7))).unwrap(), "server version");
+        assert_eq!(tip.get(EltId::from(id(1002))).unwrap(), "client only");
+    }
+}
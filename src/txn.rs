@@ -0,0 +1,132 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Optimistic transactions: edits against some state, checked for conflicts
+//! with intervening changes when committed (see `Partition::commit_txn`).
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use commit::CommitMetaPartial;
+use elt::{Element, EltId};
+use error::ElementOp;
+use state::{MutPartState, StateRead, StateWrite};
+use sum::Sum;
+
+
+/// A set of modifications to a partition's state, along with a record of which
+/// elements were read and which were written.
+///
+/// Create with `Partition::begin()`, then read and modify via the `StateRead`
+/// and `StateWrite` traits, and finally commit with `Partition::commit_txn()`.
+///
+/// Reads are recorded via `is_avail`, `get` and `get_rc`. Reading something
+/// else (e.g. via `state()`) is not recorded; use `mark_read` to record such
+/// reads. `any_avail` and `num_avail` depend on all elements, hence after
+/// calling either of these any change to the partition is a conflict.
+#[derive(Debug)]
+pub struct Transaction<E: Element> {
+    state: MutPartState<E>,
+    read: RefCell<HashSet<EltId>>,
+    read_all: Cell<bool>,
+    written: HashSet<EltId>,
+}
+
+impl<E: Element> Transaction<E> {
+    /// Start a transaction modifying the given state.
+    ///
+    /// Usually one would use `Partition::begin()` instead.
+    pub fn new(state: MutPartState<E>) -> Transaction<E> {
+        Transaction {
+            state: state,
+            read: RefCell::new(HashSet::new()),
+            read_all: Cell::new(false),
+            written: HashSet::new(),
+        }
+    }
+
+    /// Get the sum of the state the transaction started from
+    pub fn base(&self) -> &Sum {
+        self.state.parent()
+    }
+
+    /// Access the modified state directly. Reads made this way are not
+    /// recorded.
+    pub fn state(&self) -> &MutPartState<E> {
+        &self.state
+    }
+
+    /// Get write access to (partial) commit metadata
+    pub fn meta_mut(&mut self) -> &mut CommitMetaPartial {
+        self.state.meta_mut()
+    }
+
+    /// Record that an element was read (or that its absence was relied on).
+    pub fn mark_read(&self, id: EltId) {
+        self.read.borrow_mut().insert(id);
+    }
+
+    /// True if any element was written
+    pub fn is_modified(&self) -> bool {
+        !self.written.is_empty()
+    }
+
+    /// True if the given element was recorded as read
+    pub fn was_read(&self, id: EltId) -> bool {
+        self.read_all.get() || self.read.borrow().contains(&id)
+    }
+
+    /// True if the given element was inserted, replaced or removed
+    pub fn was_written(&self, id: EltId) -> bool {
+        self.written.contains(&id)
+    }
+
+    /// Unwrap into `(state, read, read_all, written)`.
+    pub fn into_parts(self) -> (MutPartState<E>, HashSet<EltId>, bool, HashSet<EltId>) {
+        (self.state, self.read.into_inner(), self.read_all.get(), self.written)
+    }
+}
+
+impl<E: Element> StateRead<E> for Transaction<E> {
+    fn any_avail(&self) -> bool {
+        self.read_all.set(true);
+        self.state.any_avail()
+    }
+    fn num_avail(&self) -> usize {
+        self.read_all.set(true);
+        self.state.num_avail()
+    }
+    fn is_avail(&self, id: EltId) -> bool {
+        self.mark_read(id);
+        self.state.is_avail(id)
+    }
+    fn get_rc(&self, id: EltId) -> Result<&Arc<E>, ElementOp> {
+        self.mark_read(id);
+        self.state.get_rc(id)
+    }
+}
+
+impl<E: Element> StateWrite<E> for Transaction<E> {
+    fn insert_rc(&mut self, id: EltId, elt: Arc<E>) -> Result<EltId, ElementOp> {
+        let id = self.state.insert_rc(id, elt)?;
+        self.written.insert(id);
+        Ok(id)
+    }
+    fn insert_new_rc(&mut self, elt: Arc<E>) -> Result<EltId, ElementOp> {
+        let id = self.state.insert_new_rc(elt)?;
+        self.written.insert(id);
+        Ok(id)
+    }
+    fn replace_rc(&mut self, id: EltId, elt: Arc<E>) -> Result<Arc<E>, ElementOp> {
+        let old = self.state.replace_rc(id, elt)?;
+        self.written.insert(id);
+        Ok(old)
+    }
+    fn remove(&mut self, id: EltId) -> Result<Arc<E>, ElementOp> {
+        let old = self.state.remove(id)?;
+        self.written.insert(id);
+        Ok(old)
+    }
+}
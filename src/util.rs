@@ -6,6 +6,7 @@
 
 use std::cmp;
 use std::fmt::{self, Write};
+use std::io::{self, Read};
 
 /// "trim" applied to generic arrays: while the last byte is pat, remove it.
 ///  
@@ -83,3 +84,30 @@ impl <'a> fmt::Display for HexFormatter<'a> {
         Ok(())
     }
 }
+
+/// A reader which counts the number of bytes read through it.
+pub struct CountingReader<R> {
+    inner: R,
+    count: usize,
+}
+impl<R: Read> CountingReader<R> {
+    /// Create, wrapping a reader
+    pub fn new(r: R) -> CountingReader<R> {
+        CountingReader { inner: r, count: 0 }
+    }
+    /// Get the number of bytes read so far
+    pub fn count(&self) -> usize {
+        self.count
+    }
+    /// Consume self and return the inner reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len;
+        Ok(len)
+    }
+}